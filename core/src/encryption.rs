@@ -0,0 +1,1289 @@
+//! End-to-end encryption of what's sent to each recipient, in the style of Signal.
+//!
+//! The first time something is sent to a recipient, a key exchange carrying this identity's key
+//! and a fresh ephemeral key is sent instead, and the message waits until the recipient answers
+//! with theirs. Both sides then derive the same secret from three Diffie-Hellman agreements
+//! between those keys and use it to start a double ratchet: every payload is sealed with a key of
+//! its own, and each time the conversation changes direction a new ratchet key pair is agreed, so
+//! keys taken from a device reveal neither earlier payloads nor, once the ratchet has turned,
+//! later ones. Relays only ever see key exchanges and ciphertext.
+//!
+//! Whatever is sent, encrypted or not, can also be signed with an Ed25519 key derived from the
+//! same secret as the sender's address. Nothing ties the key to the address a recipient can check,
+//! so the recipient trusts the key a sender announces in their key exchange, or else the first one
+//! they sign with. Anything signed with another key, or unsigned once the sender has been seen
+//! signing, is taken as forged, and the other key is flagged as a key change. The safety number
+//! covers both parties' identity and signing keys, and verifying it or dismissing the change
+//! trusts the new key instead.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::str::FromStr;
+
+use comm::address::Address;
+use comm::client::messages::TextMessage;
+use chacha20poly1305::aead::{self, Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use ed25519_dalek::{self, Signer};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::{OsRng, Rng};
+use sha2::{Digest, Sha256, Sha512};
+use x25519_dalek::{x25519, X25519_BASEPOINT_BYTES};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use payload::{Encrypted, Header, KeyExchange, Payload, Signed};
+
+/// Most message keys kept for payloads that haven't arrived yet, per session.
+const MAX_SKIP: u32 = 1000;
+
+/// Times a key exchange is sent without an answer before what's waiting for it is given up on.
+const MAX_KEY_EXCHANGES: u32 = 3;
+
+/// Rounds of hashing behind each half of a safety number, to make finding a key with a matching
+/// one expensive.
+const FINGERPRINT_ITERATIONS: usize = 5200;
+
+/// Whether what's sent to a recipient is end-to-end encrypted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EncryptionState {
+    /// There's no session. One is set up when something is first sent.
+    Unencrypted,
+    /// Waiting for the recipient to answer the key exchange. Messages wait with it.
+    Pending,
+    Encrypted
+}
+
+/// Whether the user has checked the recipient's keys against the recipient's own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verification {
+    /// Not checked yet, or there's no key to check until a session is set up.
+    Unverified,
+    Verified,
+    /// The recipient's identity or signing key isn't the one first seen for them. Stays until the
+    /// user verifies the new key or dismisses the warning.
+    KeyChanged
+}
+
+/// Whether a received message is known to come from the address it claims to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Authenticity {
+    /// Signed with the sender's key.
+    Authentic,
+    /// Not signed, by a sender not seen signing before, so there's no telling who sent it.
+    Unsigned,
+    /// Signed badly or with a key other than the sender's, or unsigned although the sender has
+    /// signed before.
+    Forged
+}
+
+/// Digits and a grid derived from both parties' identity and signing keys. Both parties see the
+/// same ones, so comparing them in person or over a call shows that nobody is in the middle.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SafetyNumber {
+    digits: String,
+    grid: Vec<bool>
+}
+
+impl SafetyNumber {
+    /// Cells along each side of the grid.
+    pub const GRID_SIZE: usize = 16;
+
+    fn new(ours: (Address, &Key, Option<Key>), theirs: (Address, &Key, Option<Key>)) -> SafetyNumber {
+        let (ours, theirs) = (fingerprint(ours.0, ours.1, ours.2), fingerprint(theirs.0, theirs.1, theirs.2));
+        let digits = if ours < theirs { ours + &theirs } else { theirs + &ours };
+
+        let bits = Sha256::digest(digits.as_bytes());
+        let grid = (0..Self::GRID_SIZE * Self::GRID_SIZE)
+            .map(|index| bits[index / 8] & (0x80 >> (index % 8)) != 0)
+            .collect();
+
+        SafetyNumber { digits: digits, grid: grid }
+    }
+
+    /// All sixty digits.
+    pub fn digits(&self) -> &str {
+        &self.digits
+    }
+
+    /// The digits in twelve groups of five, for reading out.
+    pub fn groups(&self) -> Vec<&str> {
+        (0..self.digits.len() / 5).map(|group| &self.digits[group * 5..group * 5 + 5]).collect()
+    }
+
+    /// Whether the cell at `row` and `column` of the grid is filled.
+    pub fn is_filled(&self, row: usize, column: usize) -> bool {
+        self.grid[row * Self::GRID_SIZE + column]
+    }
+}
+
+/// Thirty digits standing for one party's identity key, signing key and address.
+fn fingerprint(address: Address, key: &Key, signing_key: Option<Key>) -> String {
+    let mut sha = Sha512::new();
+    sha.update(b"comm-gtk fingerprint\0");
+    sha.update(&key.0);
+    if let Some(signing_key) = signing_key {
+        sha.update(&signing_key.0);
+    }
+    sha.update(address.to_str().as_bytes());
+    let mut hash = sha.finalize();
+    for _ in 0..FINGERPRINT_ITERATIONS {
+        let mut sha = Sha512::new();
+        sha.update(&hash);
+        sha.update(&key.0);
+        hash = sha.finalize();
+    }
+
+    hash.chunks(5).take(6).map(|chunk| {
+        let number = chunk.iter().fold(0u64, |number, byte| number << 8 | u64::from(*byte));
+        format!("{:05}", number % 100_000)
+    }).collect()
+}
+
+/// A Curve25519 or Ed25519 public key, stored hex encoded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Key([u8; 32]);
+
+impl Key {
+    pub fn to_hex(&self) -> String {
+        to_hex(&self.0)
+    }
+
+    pub fn from_hex(hex: &str) -> Option<Key> {
+        match from_hex(hex) {
+            Some(ref bytes) if bytes.len() == 32 => {
+                let mut key = [0u8; 32];
+                key.copy_from_slice(bytes);
+                Some(Key(key))
+            }
+            _ => None
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl Serialize for Key {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_hex())
+    }
+}
+
+impl<'de> Deserialize<'de> for Key {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Key, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        Key::from_hex(&hex).ok_or_else(|| de::Error::custom("expected 32 hex encoded bytes"))
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct KeyPair {
+    secret: Key,
+    public: Key
+}
+
+impl KeyPair {
+    pub fn generate() -> KeyPair {
+        let mut secret = [0u8; 32];
+        OsRng::new().expect("No source of randomness").fill_bytes(&mut secret);
+        Self::from_secret(secret)
+    }
+
+    /// The identity key pair of the profile whose secret is `secret`, so that it's the same
+    /// wherever the profile is used.
+    pub fn for_secret(secret: &str) -> KeyPair {
+        let mut hash = Sha256::new();
+        hash.update(b"comm-gtk identity key\0");
+        hash.update(secret.as_bytes());
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&hash.finalize());
+        Self::from_secret(key)
+    }
+
+    fn from_secret(mut secret: [u8; 32]) -> KeyPair {
+        secret[0] &= 248;
+        secret[31] &= 127;
+        secret[31] |= 64;
+        KeyPair {
+            public: Key(x25519(secret, X25519_BASEPOINT_BYTES)),
+            secret: Key(secret)
+        }
+    }
+
+    pub fn public(&self) -> Key {
+        self.public
+    }
+
+    fn agree(&self, public: &Key) -> [u8; 32] {
+        x25519(self.secret.0, public.0)
+    }
+}
+
+/// The Ed25519 key pair the profile with some secret signs what it sends with.
+#[derive(Clone)]
+pub struct SigningKey {
+    key: ed25519_dalek::SigningKey
+}
+
+impl SigningKey {
+    pub fn for_secret(secret: &str) -> SigningKey {
+        let mut hash = Sha256::new();
+        hash.update(b"comm-gtk signing key\0");
+        hash.update(secret.as_bytes());
+        let mut seed = [0u8; 32];
+        seed.copy_from_slice(&hash.finalize());
+        SigningKey { key: ed25519_dalek::SigningKey::from_bytes(&seed) }
+    }
+
+    pub fn public(&self) -> Key {
+        Key(self.key.verifying_key().to_bytes())
+    }
+
+    fn sign(&self, message: &[u8]) -> String {
+        to_hex(&self.key.sign(message).to_bytes())
+    }
+}
+
+/// Whether `signature` is one `signing_key` made of `message`. Only canonical signatures are
+/// accepted, so that a signed message can't be changed into another that's also signed.
+fn verify(signing_key: &Key, message: &[u8], signature: &[u8]) -> bool {
+    let signing_key = match ed25519_dalek::VerifyingKey::from_bytes(&signing_key.0) {
+        Ok(signing_key) => signing_key,
+        Err(_) => return false
+    };
+    match ed25519_dalek::Signature::from_slice(signature) {
+        Ok(signature) => signing_key.verify_strict(message, &signature).is_ok(),
+        Err(_) => false
+    }
+}
+
+/// What a signature covers: the id and text of a message, and who it's from and to.
+fn signed_content(id: Address, sender: Address, recipient: Address, text: &str) -> Vec<u8> {
+    let mut content = b"comm-gtk signature\0".to_vec();
+    content.extend_from_slice(id.to_str().as_bytes());
+    content.push(0);
+    content.extend_from_slice(sender.to_str().as_bytes());
+    content.push(0);
+    content.extend_from_slice(recipient.to_str().as_bytes());
+    content.push(0);
+    content.extend_from_slice(text.as_bytes());
+    content
+}
+
+/// The secret both sides of a key exchange arrive at, from agreements between each side's identity
+/// key and the other's ephemeral key, and between the two ephemeral keys.
+fn shared_secret(initiator: bool, identity: &KeyPair, ephemeral: &KeyPair,
+                 their_identity: &Key, their_ephemeral: &Key) -> Key {
+    let (first, second) = if initiator {
+        (identity.agree(their_ephemeral), ephemeral.agree(their_identity))
+    } else {
+        (ephemeral.agree(their_identity), identity.agree(their_ephemeral))
+    };
+    let mut input = Vec::with_capacity(96);
+    input.extend_from_slice(&first);
+    input.extend_from_slice(&second);
+    input.extend_from_slice(&ephemeral.agree(their_ephemeral));
+
+    let mut secret = [0u8; 32];
+    hkdf(&[0u8; 32], &input, b"comm-gtk key exchange", &mut secret);
+    Key(secret)
+}
+
+/// A key exchange carrying `identity`, `ephemeral` and the key we sign with, or answering one
+/// when `response` is set.
+fn key_exchange(self_address: Address, identity: &KeyPair, signing_key: Option<&SigningKey>,
+                ephemeral: &Key, response: bool) -> TextMessage {
+    let exchange = KeyExchange {
+        identity_key: identity.public().to_hex(),
+        ephemeral_key: ephemeral.to_hex(),
+        response: response,
+        signing_key: signing_key.map(|signing_key| signing_key.public().to_hex())
+    };
+    TextMessage::new(self_address, Payload::KeyExchange(exchange).encode())
+}
+
+fn hkdf(salt: &[u8], input: &[u8], info: &[u8], output: &mut [u8]) {
+    Hkdf::<Sha256>::new(Some(salt), input).expand(info, output).expect("HKDF output is too long");
+}
+
+fn hmac(key: &Key, input: &[u8]) -> Key {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&key.0).expect("HMAC takes keys of any length");
+    mac.update(input);
+    let mut output = [0u8; 32];
+    output.copy_from_slice(&mac.finalize().into_bytes());
+    Key(output)
+}
+
+/// Turns the root chain with a new Diffie-Hellman output: the next root key and a chain key.
+fn kdf_root(root_key: &Key, agreement: [u8; 32]) -> (Key, Key) {
+    let mut output = [0u8; 64];
+    hkdf(&root_key.0, &agreement, b"comm-gtk ratchet", &mut output);
+    let (mut root, mut chain) = ([0u8; 32], [0u8; 32]);
+    root.copy_from_slice(&output[..32]);
+    chain.copy_from_slice(&output[32..]);
+    (Key(root), Key(chain))
+}
+
+/// Turns a sending or receiving chain: the next chain key and the key for one payload.
+fn kdf_chain(chain_key: &Key) -> (Key, Key) {
+    (hmac(chain_key, &[2]), hmac(chain_key, &[1]))
+}
+
+/// The cipher and nonce for one payload, both derived from its message key, which is never used
+/// for another.
+fn cipher(message_key: &Key) -> (ChaCha20Poly1305, [u8; 12]) {
+    let mut output = [0u8; 44];
+    hkdf(&[0u8; 32], &message_key.0, b"comm-gtk message", &mut output);
+    let mut nonce = [0u8; 12];
+    nonce.copy_from_slice(&output[32..]);
+    (ChaCha20Poly1305::new_from_slice(&output[..32]).expect("ChaCha20 keys are 32 bytes"), nonce)
+}
+
+/// The ciphertext of `plaintext` followed by its tag.
+fn seal(message_key: &Key, plaintext: &[u8], associated_data: &[u8]) -> Vec<u8> {
+    let (cipher, nonce) = cipher(message_key);
+    let payload = aead::Payload { msg: plaintext, aad: associated_data };
+    cipher.encrypt(Nonce::from_slice(&nonce), payload).expect("Payload is too long to encrypt")
+}
+
+fn open(message_key: &Key, sealed: &[u8], associated_data: &[u8]) -> Option<Vec<u8>> {
+    let (cipher, nonce) = cipher(message_key);
+    let payload = aead::Payload { msg: sealed, aad: associated_data };
+    cipher.decrypt(Nonce::from_slice(&nonce), payload).ok()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        write!(hex, "{:02x}", byte).unwrap();
+    }
+    hex
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len() / 2)
+        .map(|index| hex.get(index * 2..index * 2 + 2).and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+        .collect()
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct SkippedKey {
+    ratchet_key: Key,
+    number: u32,
+    message_key: Key
+}
+
+/// One side of a double ratchet.
+#[derive(Clone, Serialize, Deserialize)]
+struct Ratchet {
+    /// Identity keys of the initiator and the responder, bound into every payload.
+    identities: (Key, Key),
+    root_key: Key,
+    ratchet_pair: KeyPair,
+    their_ratchet_key: Option<Key>,
+    sending_chain: Option<Key>,
+    receiving_chain: Option<Key>,
+    sent: u32,
+    received: u32,
+    previous: u32,
+    skipped: Vec<SkippedKey>
+}
+
+impl Ratchet {
+    /// The initiator's side, once the responder has answered with `their_ratchet_key`.
+    fn initiator(shared: Key, their_ratchet_key: Key, identities: (Key, Key)) -> Ratchet {
+        let ratchet_pair = KeyPair::generate();
+        let (root_key, sending_chain) = kdf_root(&shared, ratchet_pair.agree(&their_ratchet_key));
+        Ratchet {
+            identities: identities,
+            root_key: root_key,
+            ratchet_pair: ratchet_pair,
+            their_ratchet_key: Some(their_ratchet_key),
+            sending_chain: Some(sending_chain),
+            receiving_chain: None,
+            sent: 0,
+            received: 0,
+            previous: 0,
+            skipped: vec![]
+        }
+    }
+
+    /// The responder's side, whose first ratchet key is the ephemeral key it answered with. It
+    /// can't send until the initiator's first payload arrives.
+    fn responder(shared: Key, ratchet_pair: KeyPair, identities: (Key, Key)) -> Ratchet {
+        Ratchet {
+            identities: identities,
+            root_key: shared,
+            ratchet_pair: ratchet_pair,
+            their_ratchet_key: None,
+            sending_chain: None,
+            receiving_chain: None,
+            sent: 0,
+            received: 0,
+            previous: 0,
+            skipped: vec![]
+        }
+    }
+
+    fn can_send(&self) -> bool {
+        self.sending_chain.is_some()
+    }
+
+    fn associated_data(&self, header: &Header) -> Vec<u8> {
+        let mut data = Vec::with_capacity(128);
+        data.extend_from_slice(&(self.identities.0).0);
+        data.extend_from_slice(&(self.identities.1).0);
+        let header = format!("{}:{}:{}", header.ratchet_key, header.previous, header.number);
+        data.extend_from_slice(header.as_bytes());
+        data
+    }
+
+    fn encrypt(&mut self, plaintext: &[u8]) -> Option<Encrypted> {
+        let (chain_key, message_key) = match self.sending_chain {
+            Some(ref chain_key) => kdf_chain(chain_key),
+            None => return None
+        };
+        self.sending_chain = Some(chain_key);
+        let header = Header {
+            ratchet_key: self.ratchet_pair.public().to_hex(),
+            previous: self.previous,
+            number: self.sent
+        };
+        self.sent += 1;
+        let ciphertext = seal(&message_key, plaintext, &self.associated_data(&header));
+        Some(Encrypted { header: header, ciphertext: to_hex(&ciphertext) })
+    }
+
+    /// Opens `encrypted`, leaving the ratchet as it was if it can't be.
+    fn decrypt(&mut self, encrypted: &Encrypted) -> Option<Vec<u8>> {
+        let mut next = self.clone();
+        let plaintext = next.try_decrypt(encrypted);
+        if plaintext.is_some() {
+            *self = next;
+        }
+        plaintext
+    }
+
+    fn try_decrypt(&mut self, encrypted: &Encrypted) -> Option<Vec<u8>> {
+        let header = &encrypted.header;
+        let ratchet_key = Key::from_hex(&header.ratchet_key)?;
+        let ciphertext = from_hex(&encrypted.ciphertext)?;
+        let associated_data = self.associated_data(header);
+
+        let skipped = self.skipped.iter()
+            .position(|skipped| skipped.ratchet_key == ratchet_key && skipped.number == header.number);
+        if let Some(index) = skipped {
+            let skipped = self.skipped.remove(index);
+            return open(&skipped.message_key, &ciphertext, &associated_data);
+        }
+
+        if self.their_ratchet_key != Some(ratchet_key) {
+            self.skip_until(header.previous)?;
+            self.turn(ratchet_key);
+        }
+        self.skip_until(header.number)?;
+        let (chain_key, message_key) = kdf_chain(self.receiving_chain.as_ref()?);
+        self.receiving_chain = Some(chain_key);
+        self.received += 1;
+        open(&message_key, &ciphertext, &associated_data)
+    }
+
+    /// Keeps the keys of payloads on the receiving chain that were sent before `number` but
+    /// haven't arrived, for when they do.
+    fn skip_until(&mut self, number: u32) -> Option<()> {
+        let (their_ratchet_key, mut chain_key) = match (self.their_ratchet_key, self.receiving_chain) {
+            (Some(ratchet_key), Some(chain_key)) => (ratchet_key, chain_key),
+            _ => return Some(())
+        };
+        if number > self.received + MAX_SKIP {
+            return None;
+        }
+        while self.received < number {
+            let (next, message_key) = kdf_chain(&chain_key);
+            self.skipped.push(SkippedKey {
+                ratchet_key: their_ratchet_key,
+                number: self.received,
+                message_key: message_key
+            });
+            chain_key = next;
+            self.received += 1;
+        }
+        self.receiving_chain = Some(chain_key);
+
+        let excess = self.skipped.len().saturating_sub(MAX_SKIP as usize);
+        self.skipped.drain(..excess);
+        Some(())
+    }
+
+    /// Moves to the sender's new ratchet key, and to a new one of our own for replying.
+    fn turn(&mut self, their_ratchet_key: Key) {
+        self.previous = self.sent;
+        self.sent = 0;
+        self.received = 0;
+        self.their_ratchet_key = Some(their_ratchet_key);
+
+        let agreement = self.ratchet_pair.agree(&their_ratchet_key);
+        let (root_key, receiving_chain) = kdf_root(&self.root_key, agreement);
+        self.ratchet_pair = KeyPair::generate();
+        let agreement = self.ratchet_pair.agree(&their_ratchet_key);
+        let (root_key, sending_chain) = kdf_root(&root_key, agreement);
+        self.root_key = root_key;
+        self.receiving_chain = Some(receiving_chain);
+        self.sending_chain = Some(sending_chain);
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+enum SessionState {
+    /// We've sent a key exchange with this ephemeral key, `attempts` times, and are waiting for the
+    /// answer.
+    Initiated {
+        ephemeral: KeyPair,
+        #[serde(default)]
+        attempts: u32
+    },
+    Established(Ratchet)
+}
+
+/// A message waiting for its session, with the id it will be sent with.
+#[derive(Clone, Serialize, Deserialize)]
+struct Queued {
+    id: String,
+    text: String
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Session {
+    state: SessionState,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    outbox: Vec<Queued>,
+    /// The initiator's ephemeral key and ours, when this session answered a key exchange, so that
+    /// the same answer can be sent again if the initiator didn't get it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    answered: Option<(Key, Key)>
+}
+
+/// The identity key a recipient first set up a session with, the signing key they announced with
+/// it, and whether they have been verified.
+#[derive(Clone, Serialize, Deserialize)]
+struct Contact {
+    identity_key: Key,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signing_key: Option<Key>,
+    #[serde(default)]
+    verified: bool,
+    #[serde(default)]
+    key_changed: bool
+}
+
+/// The encrypted sessions of one identity, keyed by recipient address, and the signing keys of
+/// whoever it has heard from.
+#[derive(Default, Serialize, Deserialize)]
+pub struct Sessions {
+    #[serde(skip)]
+    owner: Option<(Address, KeyPair)>,
+    #[serde(skip)]
+    signing_key: Option<SigningKey>,
+    sessions: BTreeMap<String, Session>,
+    #[serde(default)]
+    contacts: BTreeMap<String, Contact>,
+    #[serde(default)]
+    signing_keys: BTreeMap<String, Key>,
+    /// The last key each sender signed with that isn't theirs, until it's verified or dismissed.
+    #[serde(default)]
+    changed_signing_keys: BTreeMap<String, Key>
+}
+
+impl Sessions {
+    /// Sets the identity sessions are held as, once its secret is known.
+    pub fn set_identity(&mut self, self_address: Address, identity: KeyPair) {
+        self.owner = Some((self_address, identity));
+    }
+
+    /// Sets the key what's sent is signed with.
+    pub fn set_signing_key(&mut self, signing_key: SigningKey) {
+        self.signing_key = Some(signing_key);
+    }
+
+    /// Replaces the sessions with ones that were stored.
+    pub fn restore(&mut self, stored: Sessions) {
+        self.sessions = stored.sessions;
+        self.contacts = stored.contacts;
+        self.signing_keys = stored.signing_keys;
+        self.changed_signing_keys = stored.changed_signing_keys;
+    }
+
+    /// Signs `message` for `recipient`, keeping its id. It's returned as it is until the signing
+    /// key is known.
+    pub fn sign(&self, recipient: Address, message: TextMessage) -> TextMessage {
+        let signing_key = match self.signing_key {
+            Some(ref signing_key) => signing_key,
+            None => return message
+        };
+        let content = signed_content(message.id, message.sender, recipient, &message.text);
+        let signed = Signed {
+            signing_key: signing_key.public().to_hex(),
+            signature: signing_key.sign(&content),
+            text: message.text
+        };
+        TextMessage { id: message.id, sender: message.sender, text: Payload::Signed(signed).encode() }
+    }
+
+    /// Checks who a received message is from. Returns it as it was before it was signed, and
+    /// whether it's from its sender. A sender's key is the one they announced in their key
+    /// exchange, or else the first they sign with. Another key is flagged as a key change.
+    pub fn authenticate(&mut self, message: TextMessage) -> (TextMessage, Authenticity) {
+        let sender = message.sender.to_str();
+        let signed = match Payload::decode(&message.text) {
+            Payload::Signed(signed) => signed,
+            _ if self.signing_keys.contains_key(&sender) => {
+                warn!("Message {} from {} isn't signed", message.id.to_str(), sender);
+                return (message, Authenticity::Forged);
+            }
+            _ => return (message, Authenticity::Unsigned)
+        };
+        let unsigned = TextMessage { id: message.id, sender: message.sender, text: signed.text };
+
+        let self_address = match self.owner {
+            Some((self_address, _)) => self_address,
+            None => return (unsigned, Authenticity::Unsigned)
+        };
+        let signing_key = match (Key::from_hex(&signed.signing_key), from_hex(&signed.signature)) {
+            (Some(signing_key), Some(ref signature)) if signature.len() == 64 => {
+                let content = signed_content(unsigned.id, unsigned.sender, self_address, &unsigned.text);
+                if verify(&signing_key, &content, signature) {
+                    Some(signing_key)
+                } else {
+                    None
+                }
+            }
+            _ => None
+        };
+        let signing_key = match signing_key {
+            Some(signing_key) => signing_key,
+            None => {
+                warn!("Message {} from {} has a bad signature", unsigned.id.to_str(), sender);
+                return (unsigned, Authenticity::Forged);
+            }
+        };
+
+        let announced = self.contacts.get(&sender).and_then(|contact| contact.signing_key);
+        let pinned = *self.signing_keys.entry(sender.clone()).or_insert(announced.unwrap_or(signing_key));
+        if announced.unwrap_or(pinned) != signing_key {
+            warn!("Message {} from {} is signed with another key", unsigned.id.to_str(), sender);
+            self.changed_signing_keys.insert(sender.clone(), signing_key);
+            if let Some(contact) = self.contacts.get_mut(&sender) {
+                contact.verified = false;
+            }
+            return (unsigned, Authenticity::Forged);
+        }
+        (unsigned, Authenticity::Authentic)
+    }
+
+    pub fn verification(&self, recipient: Address) -> Verification {
+        let key = recipient.to_str();
+        match self.contacts.get(&key) {
+            _ if self.changed_signing_keys.contains_key(&key) => Verification::KeyChanged,
+            Some(contact) if contact.key_changed => Verification::KeyChanged,
+            Some(contact) if contact.verified => Verification::Verified,
+            _ => Verification::Unverified
+        }
+    }
+
+    /// The safety number for the recipient's keys and ours, once a session has been set up with
+    /// them. After a key change it's the one for their new signing key.
+    pub fn safety_number(&self, recipient: Address) -> Option<SafetyNumber> {
+        let key = recipient.to_str();
+        match (self.owner.as_ref(), self.contacts.get(&key)) {
+            (Some(&(self_address, ref identity)), Some(contact)) => {
+                let ours = (identity.public(), self.signing_key.as_ref().map(SigningKey::public));
+                let their_signing_key = self.changed_signing_keys.get(&key).cloned()
+                    .or(contact.signing_key)
+                    .or_else(|| self.signing_keys.get(&key).cloned());
+                Some(SafetyNumber::new((self_address, &ours.0, ours.1),
+                                       (recipient, &contact.identity_key, their_signing_key)))
+            }
+            _ => None
+        }
+    }
+
+    /// Marks the recipient's current keys as checked, or not. Verifying them also clears a key
+    /// change warning, trusting the new signing key from then on.
+    pub fn set_verified(&mut self, recipient: Address, verified: bool) {
+        if verified {
+            self.dismiss_key_change(recipient);
+        }
+        if let Some(contact) = self.contacts.get_mut(&recipient.to_str()) {
+            contact.verified = verified;
+        }
+    }
+
+    /// Clears the warning that the recipient's keys have changed, leaving them unverified. Their
+    /// new signing key is trusted from then on.
+    pub fn dismiss_key_change(&mut self, recipient: Address) {
+        let key = recipient.to_str();
+        let changed_signing_key = self.changed_signing_keys.remove(&key);
+        if let Some(signing_key) = changed_signing_key {
+            self.signing_keys.insert(key.clone(), signing_key);
+        }
+        if let Some(contact) = self.contacts.get_mut(&key) {
+            contact.key_changed = false;
+            if changed_signing_key.is_some() {
+                contact.signing_key = changed_signing_key;
+            }
+        }
+    }
+
+    /// Remembers the identity and signing keys a session was set up with, flagging them when
+    /// they aren't the ones seen before.
+    fn record_identity_key(&mut self, recipient: &str, identity_key: Key, signing_key: Option<Key>) {
+        let pinned = self.signing_keys.get(recipient).cloned();
+        let contact = self.contacts.entry(String::from(recipient)).or_insert_with(|| Contact {
+            identity_key: identity_key,
+            signing_key: pinned,
+            verified: false,
+            key_changed: false
+        });
+        let signing_key_changed = match (contact.signing_key, signing_key) {
+            (Some(known), Some(signing_key)) => known != signing_key,
+            _ => false
+        };
+        if contact.identity_key != identity_key || signing_key_changed {
+            warn!("The keys of {} have changed", recipient);
+            contact.identity_key = identity_key;
+            contact.verified = false;
+            contact.key_changed = true;
+        }
+        contact.signing_key = signing_key.or(contact.signing_key);
+    }
+
+    pub fn state(&self, recipient: Address) -> EncryptionState {
+        match self.sessions.get(&recipient.to_str()).map(|session| &session.state) {
+            Some(&SessionState::Established(_)) => EncryptionState::Encrypted,
+            Some(&SessionState::Initiated { .. }) => EncryptionState::Pending,
+            None => EncryptionState::Unencrypted
+        }
+    }
+
+    /// The text messages to send so that `message` reaches `recipient`: the message encrypted,
+    /// or a key exchange when the session isn't ready. The key exchange is sent again with each
+    /// message that has to wait, in case it or its answer was lost, and the message goes out, with
+    /// its own id, once the session is ready.
+    pub fn seal(&mut self, recipient: Address, message: TextMessage) -> Vec<TextMessage> {
+        let (self_address, identity) = match self.owner.clone() {
+            Some(owner) => owner,
+            None => {
+                warn!("Can't encrypt for {} before the identity is known", recipient.to_str());
+                return vec![];
+            }
+        };
+        let key = recipient.to_str();
+        let queued = Queued { id: message.id.to_str(), text: message.text };
+
+        if !self.sessions.contains_key(&key) {
+            let ephemeral = KeyPair::generate();
+            let exchange = key_exchange(self_address, &identity, self.signing_key.as_ref(),
+                                        &ephemeral.public(), false);
+            self.sessions.insert(key, Session {
+                state: SessionState::Initiated { ephemeral: ephemeral, attempts: 1 },
+                outbox: vec![queued],
+                answered: None
+            });
+            return vec![exchange];
+        }
+
+        let resend = {
+            let session = self.sessions.get_mut(&key).unwrap();
+            session.outbox.push(queued);
+            match session.state {
+                SessionState::Initiated { ref ephemeral, ref mut attempts } => {
+                    *attempts += 1;
+                    Some(ephemeral.public())
+                }
+                SessionState::Established(_) => None
+            }
+        };
+        match resend {
+            Some(ephemeral) => {
+                vec![key_exchange(self_address, &identity, self.signing_key.as_ref(), &ephemeral, false)]
+            }
+            None => self.flush(&key)
+        }
+    }
+
+    /// Gives up on the session with `recipient` once its key exchange has been sent
+    /// `MAX_KEY_EXCHANGES` times without an answer, as happens when the recipient doesn't encrypt.
+    /// Returns the ids of the messages that were waiting for it, which won't be sent.
+    pub fn expire(&mut self, recipient: Address) -> Vec<Address> {
+        let unanswered = match self.sessions.get(&recipient.to_str()).map(|session| &session.state) {
+            Some(&SessionState::Initiated { attempts, .. }) => attempts >= MAX_KEY_EXCHANGES,
+            _ => false
+        };
+        if !unanswered {
+            return vec![];
+        }
+        warn!("{} never answered the key exchange", recipient.to_str());
+        self.abandon(recipient).into_iter().map(|message| message.id).collect()
+    }
+
+    /// Stops waiting for `recipient` to answer the key exchange. Returns what was waiting for the
+    /// session, with the ids it was given, to be sent unencrypted or not at all.
+    pub fn abandon(&mut self, recipient: Address) -> Vec<TextMessage> {
+        let key = recipient.to_str();
+        let self_address = match (self.owner.as_ref(), self.sessions.get(&key)) {
+            (Some(&(self_address, _)), Some(&Session { state: SessionState::Initiated { .. }, .. })) => {
+                self_address
+            }
+            _ => return vec![]
+        };
+        let session = self.sessions.remove(&key).unwrap();
+        session.outbox.into_iter().filter_map(|queued| {
+            Address::from_str(&queued.id).ok().map(|id| TextMessage {
+                id: id,
+                sender: self_address,
+                text: queued.text
+            })
+        }).collect()
+    }
+
+    /// Opens a received text message. Returns it as it was sent, if it carried a payload, and
+    /// anything to send in answer.
+    pub fn open(&mut self, message: TextMessage) -> (Option<TextMessage>, Vec<TextMessage>) {
+        match Payload::decode(&message.text) {
+            Payload::KeyExchange(exchange) => (None, self.receive_key_exchange(message.sender, &exchange)),
+            Payload::Encrypted(encrypted) => {
+                let key = message.sender.to_str();
+                let plaintext = match self.sessions.get_mut(&key).map(|session| &mut session.state) {
+                    Some(&mut SessionState::Established(ref mut ratchet)) => ratchet.decrypt(&encrypted),
+                    _ => None
+                };
+                match plaintext.and_then(|plaintext| String::from_utf8(plaintext).ok()) {
+                    Some(text) => {
+                        let opened = TextMessage { id: message.id, sender: message.sender, text: text };
+                        (Some(opened), self.flush(&key))
+                    }
+                    None => {
+                        warn!("Couldn't decrypt message {} from {}", message.id.to_str(), key);
+                        (Some(message), vec![])
+                    }
+                }
+            }
+            _ => (Some(message), vec![])
+        }
+    }
+
+    fn receive_key_exchange(&mut self, sender: Address, exchange: &KeyExchange) -> Vec<TextMessage> {
+        let (self_address, identity) = match self.owner.clone() {
+            Some(owner) => owner,
+            None => return vec![]
+        };
+        let (their_identity, their_ephemeral) = match (Key::from_hex(&exchange.identity_key),
+                                                       Key::from_hex(&exchange.ephemeral_key)) {
+            (Some(identity), Some(ephemeral)) => (identity, ephemeral),
+            _ => {
+                warn!("Ignoring malformed key exchange from {}", sender.to_str());
+                return vec![];
+            }
+        };
+        let their_signing_key = exchange.signing_key.as_ref().and_then(|hex| Key::from_hex(hex));
+        let key = sender.to_str();
+        let initiated = match self.sessions.get(&key).map(|session| &session.state) {
+            Some(&SessionState::Initiated { ref ephemeral, .. }) => Some(ephemeral.clone()),
+            _ => None
+        };
+        let answered = self.sessions.get(&key).and_then(|session| session.answered);
+
+        if exchange.response {
+            let ephemeral = match initiated {
+                Some(ephemeral) => ephemeral,
+                None => {
+                    debug!("Ignoring unexpected key exchange answer from {}", key);
+                    return vec![];
+                }
+            };
+            let shared = shared_secret(true, &identity, &ephemeral, &their_identity, &their_ephemeral);
+            let ratchet = Ratchet::initiator(shared, their_ephemeral, (identity.public(), their_identity));
+            self.sessions.get_mut(&key).unwrap().state = SessionState::Established(ratchet);
+            self.record_identity_key(&key, their_identity, their_signing_key);
+            return self.flush(&key);
+        }
+
+        // When both sides start a session at once, the one with the lower address carries on
+        // with its own and the other answers it.
+        if initiated.is_some() && self_address.to_str() < key {
+            return vec![];
+        }
+        // The initiator sent the key exchange again, so our answer may not have reached them
+        if let Some((initiator_ephemeral, ours)) = answered {
+            if initiator_ephemeral == their_ephemeral {
+                return vec![key_exchange(self_address, &identity, self.signing_key.as_ref(), &ours, true)];
+            }
+        }
+
+        let ephemeral = KeyPair::generate();
+        let shared = shared_secret(false, &identity, &ephemeral, &their_identity, &their_ephemeral);
+        let ratchet = Ratchet::responder(shared, ephemeral.clone(), (their_identity, identity.public()));
+        let outbox = self.sessions.remove(&key).map(|session| session.outbox).unwrap_or_else(|| vec![]);
+        self.record_identity_key(&key, their_identity, their_signing_key);
+        self.sessions.insert(key, Session {
+            state: SessionState::Established(ratchet),
+            outbox: outbox,
+            answered: Some((their_ephemeral, ephemeral.public()))
+        });
+
+        vec![key_exchange(self_address, &identity, self.signing_key.as_ref(), &ephemeral.public(), true)]
+    }
+
+    /// Encrypts and returns whatever is waiting for the session with `key`, as far as it can
+    /// send yet.
+    fn flush(&mut self, key: &str) -> Vec<TextMessage> {
+        let self_address = match self.owner {
+            Some((self_address, _)) => self_address,
+            None => return vec![]
+        };
+        let session = match self.sessions.get_mut(key) {
+            Some(session) => session,
+            None => return vec![]
+        };
+        let ratchet = match session.state {
+            SessionState::Established(ref mut ratchet) if ratchet.can_send() => ratchet,
+            _ => return vec![]
+        };
+
+        session.outbox.drain(..).filter_map(|queued| {
+            let id = match Address::from_str(&queued.id) {
+                Ok(id) => id,
+                Err(_) => return None
+            };
+            ratchet.encrypt(queued.text.as_bytes()).map(|encrypted| TextMessage {
+                id: id,
+                sender: self_address,
+                text: Payload::Encrypted(encrypted).encode()
+            })
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sessions(secret: &str) -> (Address, Sessions) {
+        let address = Address::for_content(secret);
+        let mut sessions = Sessions::default();
+        sessions.set_identity(address, KeyPair::for_secret(secret));
+        sessions.set_signing_key(SigningKey::for_secret(secret));
+        (address, sessions)
+    }
+
+    /// Opens each of `messages` and collects what they carried and what was sent in answer.
+    fn deliver(to: &mut Sessions, messages: Vec<TextMessage>) -> (Vec<String>, Vec<TextMessage>) {
+        let mut texts = vec![];
+        let mut answers = vec![];
+        for message in messages {
+            let (opened, mut sent) = to.open(message);
+            texts.extend(opened.map(|message| message.text));
+            answers.append(&mut sent);
+        }
+        (texts, answers)
+    }
+
+    fn text(from: Address, text: &str) -> TextMessage {
+        TextMessage::new(from, String::from(text))
+    }
+
+    #[test]
+    fn keys_survive_hex_encoding() {
+        let key = KeyPair::generate().public();
+        assert_eq!(Key::from_hex(&key.to_hex()), Some(key));
+        assert_eq!(Key::from_hex("not hex"), None);
+    }
+
+    #[test]
+    fn identity_keys_follow_from_the_secret() {
+        assert_eq!(KeyPair::for_secret("alice").public(), KeyPair::for_secret("alice").public());
+        assert!(KeyPair::for_secret("alice").public() != KeyPair::for_secret("bob").public());
+    }
+
+    #[test]
+    fn signatures_bind_messages_to_their_id_sender_and_recipient() {
+        let (alice_address, alice) = sessions("alice");
+        let (bob_address, mut bob) = sessions("bob");
+        let (carol_address, mut carol) = sessions("carol");
+
+        let message = text(alice_address, "hi bob");
+        let signed = alice.sign(bob_address, message.clone());
+        assert_eq!(signed.id, message.id);
+        let (opened, authenticity) = bob.authenticate(signed.clone());
+        assert_eq!((opened.text.as_str(), authenticity), ("hi bob", Authenticity::Authentic));
+
+        // Passed on to someone else, changed on the way, or sent again as another message
+        assert_eq!(carol.authenticate(signed.clone()).1, Authenticity::Forged);
+        let mut tampered = signed.clone();
+        tampered.text = tampered.text.replace("hi bob", "bye bob");
+        assert_eq!(bob.authenticate(tampered).1, Authenticity::Forged);
+        let mut replayed = signed.clone();
+        replayed.id = Address::for_content("another message");
+        assert_eq!(bob.authenticate(replayed).1, Authenticity::Forged);
+
+        // Someone else signing in alice's name, or not signing once she has
+        let (_, mallory) = sessions("mallory");
+        let forged = mallory.sign(bob_address, text(alice_address, "send money"));
+        assert_eq!(bob.authenticate(forged).1, Authenticity::Forged);
+        assert_eq!(bob.authenticate(text(alice_address, "me again")).1, Authenticity::Forged);
+        assert_eq!(bob.authenticate(text(carol_address, "hello")).1, Authenticity::Unsigned);
+        assert_eq!(bob.authenticate(signed).1, Authenticity::Authentic);
+    }
+
+    #[test]
+    fn messages_wait_for_the_key_exchange() {
+        let (alice_address, mut alice) = sessions("alice");
+        let (bob_address, mut bob) = sessions("bob");
+
+        let message = text(alice_address, "hello bob");
+        let exchange = alice.seal(bob_address, message.clone());
+        assert_eq!(alice.state(bob_address), EncryptionState::Pending);
+        // The key exchange goes out again, in case it was lost
+        let again = alice.seal(bob_address, text(alice_address, "still there?"));
+        assert_eq!(again.len(), 1);
+        assert_eq!(again[0].text, exchange[0].text);
+
+        let (texts, answer) = deliver(&mut bob, exchange);
+        assert!(texts.is_empty());
+        assert_eq!(bob.state(alice_address), EncryptionState::Encrypted);
+
+        let (texts, encrypted) = deliver(&mut alice, answer);
+        assert!(texts.is_empty());
+        assert_eq!(alice.state(bob_address), EncryptionState::Encrypted);
+        assert_eq!(encrypted.len(), 2);
+        assert_eq!(encrypted[0].id, message.id);
+        assert!(!encrypted[0].text.contains("hello bob"));
+
+        let (texts, _) = deliver(&mut bob, encrypted);
+        assert_eq!(texts, vec!["hello bob", "still there?"]);
+    }
+
+    #[test]
+    fn lost_answers_are_sent_again() {
+        let (alice_address, mut alice) = sessions("alice");
+        let (bob_address, mut bob) = sessions("bob");
+
+        let exchange = alice.seal(bob_address, text(alice_address, "hi"));
+        let (_, lost) = deliver(&mut bob, exchange);
+        let again = alice.seal(bob_address, text(alice_address, "hello?"));
+        let (_, answer) = deliver(&mut bob, again);
+        assert_eq!(answer.len(), 1);
+        assert_eq!(answer[0].text, lost[0].text);
+
+        let (_, sent) = deliver(&mut alice, answer);
+        assert_eq!(deliver(&mut bob, sent).0, vec!["hi", "hello?"]);
+    }
+
+    #[test]
+    fn unanswered_key_exchanges_are_given_up_on() {
+        let (alice_address, mut alice) = sessions("alice");
+        let bob_address = Address::for_content("bob");
+
+        let messages: Vec<TextMessage> = ["one", "two", "three"].iter()
+            .map(|message| text(alice_address, message))
+            .collect();
+        for (index, message) in messages.iter().enumerate() {
+            assert!(alice.expire(bob_address).is_empty(), "Gave up after {} key exchanges", index);
+            alice.seal(bob_address, message.clone());
+        }
+        let failed = alice.expire(bob_address);
+        assert_eq!(failed.len(), 3);
+        assert!(failed.iter().zip(messages.iter()).all(|(&id, message)| id == message.id));
+        assert_eq!(alice.state(bob_address), EncryptionState::Unencrypted);
+
+        // Or what's waiting is sent without encryption
+        let waiting = text(alice_address, "four");
+        alice.seal(bob_address, waiting.clone());
+        let unencrypted = alice.abandon(bob_address);
+        assert_eq!(unencrypted.len(), 1);
+        assert!(unencrypted[0].id == waiting.id);
+        assert_eq!(unencrypted[0].text, "four");
+        assert_eq!(alice.state(bob_address), EncryptionState::Unencrypted);
+    }
+
+    #[test]
+    fn both_sides_can_send_once_the_initiator_has() {
+        let (alice_address, mut alice) = sessions("alice");
+        let (bob_address, mut bob) = sessions("bob");
+
+        let exchange = alice.seal(bob_address, text(alice_address, "hi"));
+        let (_, answer) = deliver(&mut bob, exchange);
+        assert!(bob.seal(alice_address, text(bob_address, "early")).is_empty());
+
+        let (_, first) = deliver(&mut alice, answer);
+        let (texts, flushed) = deliver(&mut bob, first);
+        assert_eq!(texts, vec!["hi"]);
+        let (texts, _) = deliver(&mut alice, flushed);
+        assert_eq!(texts, vec!["early"]);
+
+        for round in 0..3 {
+            let from_alice = alice.seal(bob_address, text(alice_address, &format!("ping {}", round)));
+            assert_eq!(deliver(&mut bob, from_alice).0, vec![format!("ping {}", round)]);
+            let from_bob = bob.seal(alice_address, text(bob_address, &format!("pong {}", round)));
+            assert_eq!(deliver(&mut alice, from_bob).0, vec![format!("pong {}", round)]);
+        }
+    }
+
+    #[test]
+    fn messages_can_arrive_out_of_order() {
+        let (alice_address, mut alice) = sessions("alice");
+        let (bob_address, mut bob) = sessions("bob");
+
+        let exchange = alice.seal(bob_address, text(alice_address, "one"));
+        let (_, answer) = deliver(&mut bob, exchange);
+        let (_, one) = deliver(&mut alice, answer);
+        let two = alice.seal(bob_address, text(alice_address, "two"));
+        let three = alice.seal(bob_address, text(alice_address, "three"));
+
+        assert_eq!(deliver(&mut bob, three).0, vec!["three"]);
+        assert_eq!(deliver(&mut bob, one).0, vec!["one"]);
+        assert_eq!(deliver(&mut bob, two).0, vec!["two"]);
+    }
+
+    #[test]
+    fn tampered_and_replayed_messages_are_not_opened() {
+        let (alice_address, mut alice) = sessions("alice");
+        let (bob_address, mut bob) = sessions("bob");
+
+        let exchange = alice.seal(bob_address, text(alice_address, "hi"));
+        let (_, answer) = deliver(&mut bob, exchange);
+        let (_, sent) = deliver(&mut alice, answer);
+        let mut tampered = sent[0].clone();
+        tampered.text = tampered.text.replace("\"number\":0", "\"number\":1");
+
+        // What can't be opened is passed on as it arrived
+        assert_eq!(deliver(&mut bob, vec![tampered.clone()]).0, vec![tampered.text]);
+        assert_eq!(deliver(&mut bob, sent.clone()).0, vec!["hi"]);
+        assert_eq!(deliver(&mut bob, sent.clone()).0, vec![sent[0].text.clone()]);
+    }
+
+    #[test]
+    fn simultaneous_key_exchanges_settle_on_one_session() {
+        let (alice_address, mut alice) = sessions("alice");
+        let (bob_address, mut bob) = sessions("bob");
+
+        let from_alice = alice.seal(bob_address, text(alice_address, "hi bob"));
+        let from_bob = bob.seal(alice_address, text(bob_address, "hi alice"));
+        let (_, alice_answers) = deliver(&mut alice, from_bob);
+        let (_, bob_answers) = deliver(&mut bob, from_alice);
+
+        // Exactly one of them answered, and the conversation carries on from there
+        let mut texts = vec![];
+        let (mut to_alice, mut to_bob) = (bob_answers, alice_answers);
+        assert_eq!(to_alice.len() + to_bob.len(), 1);
+        while !to_alice.is_empty() || !to_bob.is_empty() {
+            let (mut opened, answers) = deliver(&mut alice, to_alice);
+            texts.append(&mut opened);
+            let (mut opened, next) = deliver(&mut bob, to_bob);
+            texts.append(&mut opened);
+            to_alice = next;
+            to_bob = answers;
+        }
+        texts.sort();
+        assert_eq!(texts, vec!["hi alice", "hi bob"]);
+    }
+
+    /// Sets up a session from `from` to `to` and returns the first message sent over it.
+    fn establish(from: (Address, &mut Sessions), to: (Address, &mut Sessions)) -> Vec<TextMessage> {
+        let exchange = from.1.seal(to.0, text(from.0, "hi"));
+        let (_, answer) = deliver(to.1, exchange);
+        deliver(from.1, answer).1
+    }
+
+    #[test]
+    fn both_sides_see_the_same_safety_number() {
+        let (alice_address, mut alice) = sessions("alice");
+        let (bob_address, mut bob) = sessions("bob");
+        assert_eq!(alice.safety_number(bob_address), None);
+
+        establish((alice_address, &mut alice), (bob_address, &mut bob));
+        let number = alice.safety_number(bob_address).unwrap();
+        assert_eq!(Some(number.clone()), bob.safety_number(alice_address));
+        assert_eq!(number.digits().len(), 60);
+        assert!(number.digits().chars().all(|c| c.is_ascii_digit()));
+        assert_eq!(number.groups().len(), 12);
+
+        let (carol_address, mut carol) = sessions("carol");
+        establish((alice_address, &mut alice), (carol_address, &mut carol));
+        assert!(alice.safety_number(carol_address) != Some(number));
+    }
+
+    #[test]
+    fn changed_identity_keys_are_flagged_until_verified() {
+        let (alice_address, mut alice) = sessions("alice");
+        let (bob_address, mut bob) = sessions("bob");
+        establish((alice_address, &mut alice), (bob_address, &mut bob));
+        assert_eq!(alice.verification(bob_address), Verification::Unverified);
+        alice.set_verified(bob_address, true);
+        assert_eq!(alice.verification(bob_address), Verification::Verified);
+        let verified = alice.safety_number(bob_address);
+
+        // Someone else sets up a session in bob's name
+        let mut mallory = Sessions::default();
+        mallory.set_identity(bob_address, KeyPair::for_secret("mallory"));
+        establish((bob_address, &mut mallory), (alice_address, &mut alice));
+        assert_eq!(alice.verification(bob_address), Verification::KeyChanged);
+        assert!(alice.safety_number(bob_address) != verified);
+
+        alice.dismiss_key_change(bob_address);
+        assert_eq!(alice.verification(bob_address), Verification::Unverified);
+        alice.set_verified(bob_address, true);
+        assert_eq!(alice.verification(bob_address), Verification::Verified);
+    }
+
+    #[test]
+    fn changed_signing_keys_are_flagged_until_verified_or_dismissed() {
+        let (alice_address, mut alice) = sessions("alice");
+        let (bob_address, mut bob) = sessions("bob");
+        establish((alice_address, &mut alice), (bob_address, &mut bob));
+        let number = bob.safety_number(alice_address);
+        let from_alice = alice.sign(bob_address, text(alice_address, "hi"));
+        assert_eq!(bob.authenticate(from_alice.clone()).1, Authenticity::Authentic);
+
+        // Alice's signing key is the one she announced, so another is a change to check
+        let (_, mallory) = sessions("mallory");
+        let from_mallory = mallory.sign(bob_address, text(alice_address, "send money"));
+        assert_eq!(bob.authenticate(from_mallory.clone()).1, Authenticity::Forged);
+        assert_eq!(bob.verification(alice_address), Verification::KeyChanged);
+        assert!(bob.safety_number(alice_address) != number);
+
+        // Dismissing the change trusts the new key instead, as does verifying it
+        bob.dismiss_key_change(alice_address);
+        assert_eq!(bob.verification(alice_address), Verification::Unverified);
+        assert_eq!(bob.authenticate(from_mallory).1, Authenticity::Authentic);
+        assert_eq!(bob.authenticate(from_alice.clone()).1, Authenticity::Forged);
+        assert_eq!(bob.verification(alice_address), Verification::KeyChanged);
+        assert_eq!(bob.safety_number(alice_address), number);
+        bob.set_verified(alice_address, true);
+        assert_eq!(bob.verification(alice_address), Verification::Verified);
+        assert_eq!(bob.authenticate(from_alice).1, Authenticity::Authentic);
+    }
+
+    #[test]
+    fn sessions_survive_being_stored() {
+        let (alice_address, mut alice) = sessions("alice");
+        let (bob_address, mut bob) = sessions("bob");
+
+        let exchange = alice.seal(bob_address, text(alice_address, "hi"));
+        let (_, answer) = deliver(&mut bob, exchange);
+        let (_, sent) = deliver(&mut alice, answer);
+        bob.authenticate(alice.sign(bob_address, text(alice_address, "signed")));
+
+        let stored: Sessions = ::serde_yaml::from_str(&::serde_yaml::to_string(&bob).unwrap()).unwrap();
+        let (_, mut restored) = sessions("bob");
+        restored.restore(stored);
+        assert_eq!(deliver(&mut restored, sent).0, vec!["hi"]);
+        assert_eq!(restored.safety_number(alice_address), bob.safety_number(alice_address));
+        assert_eq!(restored.authenticate(text(alice_address, "unsigned")).1, Authenticity::Forged);
+    }
+}
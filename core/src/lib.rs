@@ -0,0 +1,29 @@
+//! The model layer of comm-gtk: configuration and profiles, connections to the comm network,
+//! conversations and their messages, and the observer traits a frontend implements to follow
+//! them. Nothing here depends on GTK.
+
+#[macro_use] extern crate log;
+#[macro_use] extern crate serde_derive;
+extern crate chacha20poly1305;
+extern crate comm;
+extern crate ed25519_dalek;
+extern crate hkdf;
+extern crate hmac;
+extern crate rand;
+extern crate serde;
+extern crate serde_json;
+extern crate serde_yaml;
+extern crate sha2;
+extern crate x25519_dalek;
+
+pub mod encryption;
+pub mod limits;
+pub mod models;
+pub mod payload;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
+pub use models::{Authenticity, CommTransport, Configuration, Connection, ConnectionObserver,
+    ConnectionState, Conversation, ConversationList, ConversationListObserver, ConversationObserver,
+    EncryptionState, EventHandler, EventObserver, Limits, Message, MessageObserver, MessageStore,
+    Observable, Profile, SafetyNumber, Subscription, Transport, Update, Verification};
@@ -0,0 +1,149 @@
+//! Limits on what peers can send an identity. Anyone can send messages to an address, so a hostile
+//! peer could otherwise flood a conversation with as many messages as the network will carry.
+//!
+//! Each profile can set its own limits in `comm.yml`, under `limits`. Everything a stranger sends
+//! counts towards the rate limits before it's opened, but only what a contact sends that would be
+//! shown counts for them. What's over the limits is collapsed into a placeholder saying how many
+//! were hidden. Bodies over the size limit are discarded unread.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use comm::address::Address;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Limits {
+    /// How many messages one sender may send in each period before the rest are hidden.
+    pub messages_per_sender: usize,
+    /// How many messages senders without an accepted conversation may send together in each
+    /// period, so that strangers can't crowd out contacts.
+    pub messages_in_total: usize,
+    /// Length of the period the rate limits are counted over, in seconds.
+    pub period_secs: u64,
+    /// Size, in bytes, of the largest message body that's read at all.
+    pub max_message_bytes: usize
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            messages_per_sender: 30,
+            messages_in_total: 120,
+            period_secs: 60,
+            max_message_bytes: 64 * 1024
+        }
+    }
+}
+
+impl Limits {
+    pub fn is_default(&self) -> bool {
+        *self == Limits::default()
+    }
+}
+
+/// Counts the messages admitted over the last period, oldest first, and whether each was from a
+/// stranger.
+pub struct RateLimiter {
+    limits: Limits,
+    admitted: VecDeque<(Address, bool, Instant)>
+}
+
+impl RateLimiter {
+    pub fn new(limits: Limits) -> RateLimiter {
+        RateLimiter {
+            limits: limits,
+            admitted: VecDeque::new()
+        }
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Whether a message from `sender` received at `now` is within the limits, counting it if it
+    /// is. Only a `stranger`, a sender without an accepted conversation, is held to the total
+    /// limit. Messages that aren't admitted don't count against later ones, so a flood only keeps
+    /// being hidden while it lasts.
+    pub fn admit(&mut self, sender: Address, stranger: bool, now: Instant) -> bool {
+        let period = Duration::from_secs(self.limits.period_secs);
+        while self.admitted.front().map_or(false, |&(_, _, at)| now.duration_since(at) >= period) {
+            self.admitted.pop_front();
+        }
+
+        let from_sender = self.admitted.iter().filter(|&&(address, _, _)| address == sender).count();
+        let from_strangers = self.admitted.iter().filter(|&&(_, stranger, _)| stranger).count();
+        if from_sender >= self.limits.messages_per_sender ||
+            stranger && from_strangers >= self.limits.messages_in_total {
+            return false;
+        }
+        self.admitted.push_back((sender, stranger, now));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use comm::address::Address;
+    use super::{Limits, RateLimiter};
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(Limits {
+            messages_per_sender: 2,
+            messages_in_total: 3,
+            period_secs: 60,
+            max_message_bytes: 1024
+        })
+    }
+
+    #[test]
+    fn senders_are_limited_on_their_own_and_together() {
+        let mut limiter = limiter();
+        let (bob, carol, dave) = (Address::for_content("bob"), Address::for_content("carol"),
+                                  Address::for_content("dave"));
+        let now = Instant::now();
+
+        assert!(limiter.admit(bob, true, now));
+        assert!(limiter.admit(bob, true, now));
+        assert!(!limiter.admit(bob, true, now));
+        assert!(limiter.admit(carol, true, now));
+        assert!(!limiter.admit(dave, true, now));
+    }
+
+    #[test]
+    fn contacts_are_only_limited_on_their_own() {
+        let mut limiter = limiter();
+        let (bob, carol, dave) = (Address::for_content("bob"), Address::for_content("carol"),
+                                  Address::for_content("dave"));
+        let now = Instant::now();
+
+        assert!(limiter.admit(bob, false, now));
+        assert!(limiter.admit(bob, false, now));
+        assert!(!limiter.admit(bob, false, now));
+        assert!(limiter.admit(carol, true, now));
+        assert!(limiter.admit(carol, true, now));
+        assert!(limiter.admit(dave, true, now));
+        assert!(!limiter.admit(Address::for_content("erin"), true, now));
+        assert!(limiter.admit(Address::for_content("frank"), false, now));
+    }
+
+    #[test]
+    fn limits_reset_once_the_period_has_passed() {
+        let mut limiter = limiter();
+        let bob = Address::for_content("bob");
+        let start = Instant::now();
+
+        assert!(limiter.admit(bob, true, start));
+        assert!(limiter.admit(bob, true, start + Duration::from_secs(30)));
+        assert!(!limiter.admit(bob, true, start + Duration::from_secs(59)));
+        assert!(limiter.admit(bob, true, start + Duration::from_secs(60)));
+        assert!(!limiter.admit(bob, true, start + Duration::from_secs(61)));
+        assert!(limiter.admit(bob, true, start + Duration::from_secs(90)));
+    }
+}
@@ -0,0 +1,4186 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path;
+use std::rc::{Rc, Weak};
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use std::fs;
+use std::io;
+use serde_yaml;
+use std::io::{Error, ErrorKind};
+use rand::{OsRng, Rng};
+
+use comm::address::Address;
+use comm;
+pub use encryption::{Authenticity, EncryptionState, SafetyNumber, Verification};
+use encryption::{KeyPair, Sessions, SigningKey};
+pub use limits::Limits;
+use limits::RateLimiter;
+pub use payload::{Deletion, Edit, GroupMessage, Invite, Leave, Payload, Quote, Reaction, ReadReceipt,
+    Reply, Typing};
+
+/// Observers of a model, held weakly so that registering doesn't keep an observer alive. Observers
+/// that have been dropped are pruned the next time the set is notified.
+pub struct ObserverSet<O: ?Sized> {
+    next_id: usize,
+    observers: RefCell<Vec<(usize, Weak<RefCell<O>>)>>
+}
+
+impl<O: ?Sized> ObserverSet<O> {
+    fn new() -> ObserverSet<O> {
+        ObserverSet {
+            next_id: 0,
+            observers: RefCell::new(vec![])
+        }
+    }
+
+    fn insert(&mut self, observer: &Rc<RefCell<O>>) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.observers.get_mut().push((id, Rc::downgrade(observer)));
+        id
+    }
+
+    /// Queues a call of `function` with each observer, in the order they registered. The model is
+    /// usually borrowed while it notifies, so the calls are made later by `deliver_notifications`.
+    fn notify<F>(&self, function: F) where O: 'static, F: Fn(&Rc<RefCell<O>>) + 'static {
+        self.observers.borrow_mut().retain(|&(_, ref observer)| observer.upgrade().is_some());
+
+        let observers: Vec<Weak<RefCell<O>>> = self.observers.borrow().iter()
+            .map(|&(_, ref observer)| observer.clone())
+            .collect();
+        if observers.is_empty() {
+            return;
+        }
+
+        PENDING_NOTIFICATIONS.with(|pending| {
+            pending.borrow_mut().push_back(Box::new(move || {
+                // Skip observers freed by an earlier notification
+                for observer in observers.iter().filter_map(|observer| observer.upgrade()) {
+                    function(&observer);
+                }
+            }));
+        });
+    }
+
+    fn remove(&mut self, id: &usize) {
+        self.observers.get_mut().retain(|&(observer_id, _)| observer_id != *id);
+    }
+
+    /// Number of registered observers that are still alive.
+    pub fn len(&self) -> usize {
+        self.observers.borrow().iter().filter(|&&(_, ref observer)| observer.upgrade().is_some()).count()
+    }
+}
+
+thread_local!(
+    static PENDING_NOTIFICATIONS: RefCell<VecDeque<Box<FnMut()>>> = RefCell::new(VecDeque::new());
+    static DELIVERING: Cell<bool> = Cell::new(false);
+    static UPDATE_DEPTH: Cell<usize> = Cell::new(0);
+);
+
+/// Calls observers with the notifications models have queued, oldest first. Notifications queued
+/// by observers while this runs are delivered by the same call, after the ones already queued, so
+/// observers are free to read or change any model. Does nothing when called from an observer.
+pub fn deliver_notifications() {
+    if DELIVERING.with(|delivering| delivering.replace(true)) {
+        return;
+    }
+
+    struct Delivering;
+    impl Drop for Delivering {
+        fn drop(&mut self) {
+            DELIVERING.with(|delivering| delivering.set(false));
+        }
+    }
+    let _delivering = Delivering;
+
+    loop {
+        let notification = PENDING_NOTIFICATIONS.with(|pending| pending.borrow_mut().pop_front());
+        match notification {
+            Some(mut notification) => notification(),
+            None => break
+        }
+    }
+}
+
+/// Delivers notifications left behind by a change made through `borrow_mut` rather than `update`,
+/// which would otherwise wait until some unrelated update. Frontends call this whenever their event
+/// loop goes idle. Such a change is a bug, so it's logged.
+pub fn deliver_stray_notifications() {
+    let stray = PENDING_NOTIFICATIONS.with(|pending| pending.borrow().len());
+    if stray > 0 {
+        warn!("{} notifications were queued outside of an update", stray);
+    }
+    deliver_notifications();
+}
+
+/// Changes a shared model, then delivers the notifications queued by the change once the model is
+/// no longer borrowed.
+pub trait Update<T: ?Sized> {
+    fn update<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> R;
+}
+
+impl<T: ?Sized> Update<T> for Rc<RefCell<T>> {
+    fn update<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> R {
+        UPDATE_DEPTH.with(|depth| depth.set(depth.get() + 1));
+        let result = f(&mut self.borrow_mut());
+        let depth = UPDATE_DEPTH.with(|depth| {
+            depth.set(depth.get() - 1);
+            depth.get()
+        });
+
+        // Nested updates leave delivery to the outermost one, which may still hold a borrow
+        if depth == 0 {
+            deliver_notifications();
+        }
+        result
+    }
+}
+
+pub trait Observable {
+    type Observer: ?Sized;
+
+    fn observers(&mut self) -> &mut ObserverSet<Self::Observer>;
+
+    fn register_observer(&mut self, observer: Rc<RefCell<Self::Observer>>) -> usize {
+        self.observers().insert(&observer)
+    }
+
+    fn deregister_observer(&mut self, id: &usize) {
+        self.observers().remove(id);
+    }
+}
+
+/// Keeps an observer registered with a model until it's dropped. Controllers hold on to these so
+/// that they stop being notified as soon as they're freed.
+pub struct Subscription {
+    deregister: Option<Box<FnMut()>>
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if let Some(mut deregister) = self.deregister.take() {
+            deregister();
+        }
+    }
+}
+
+/// Registers `observer` with `observable` for as long as the returned subscription lives.
+pub fn subscribe<M>(observable: &Rc<RefCell<M>>, observer: Rc<RefCell<M::Observer>>) -> Subscription
+    where M: Observable + 'static {
+    let id = observable.borrow_mut().register_observer(observer);
+    let observable = Rc::downgrade(observable);
+    Subscription {
+        deregister: Some(Box::new(move || {
+            if let Some(observable) = observable.upgrade() {
+                // If the model is busy notifying, the dead observer is pruned later instead
+                if let Ok(mut observable) = observable.try_borrow_mut() {
+                    observable.deregister_observer(&id);
+                }
+            }
+        }))
+    }
+}
+
+pub trait ConversationListObserver {
+    fn conversation_was_added(&mut self, Rc<RefCell<Conversation>>);
+    fn conversation_was_removed(&mut self, Rc<RefCell<Conversation>>);
+    fn conversation_was_selected(&mut self, Rc<RefCell<Conversation>>);
+    /// Called when the user accepts a message request, which moves it to the top of the list.
+    fn request_was_accepted(&mut self, Rc<RefCell<Conversation>>);
+}
+
+pub trait ConversationObserver {
+    fn identity_was_changed(&self, String);
+    fn recipient_was_changed(&self, Option<Address>);
+    fn pending_message_was_changed(&self, String);
+    /// Called with the message the next one sent will reply to, if any.
+    fn reply_was_changed(&self, Option<Quote>);
+    /// Called when the recipient starts or stops typing. They're also taken to have stopped once
+    /// `TYPING_TIMEOUT_SECS` pass without hearing from them, but that isn't notified.
+    fn recipient_typing_was_changed(&self, bool);
+    /// Called when the session with the recipient changes, or the recipient does.
+    fn encryption_was_changed(&self, EncryptionState);
+    /// Called when the recipient's identity key is verified or changes, or the recipient does.
+    fn verification_was_changed(&self, Verification);
+    /// Called with the other members of a group when someone joins or leaves it.
+    fn members_were_changed(&self, Vec<Address>);
+    fn did_receive_message(&mut self, Rc<RefCell<Message>>);
+    fn did_send_message(&mut self, Rc<RefCell<Message>>);
+}
+
+/// Broad categories of client events, used to route each event to the models that handle it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    /// The client starting or shutting down.
+    Connection,
+    /// Messages and acknowledgements.
+    Message
+}
+
+impl EventKind {
+    pub fn of(event: &comm::client::Event) -> EventKind {
+        match *event {
+            comm::client::Event::Started |
+            comm::client::Event::Shutdown => EventKind::Connection,
+            comm::client::Event::ReceivedTextMessage(_) |
+            comm::client::Event::ReceivedMessageAcknowledgement(_) |
+            comm::client::Event::SentTextMessage(_) => EventKind::Message
+        }
+    }
+}
+
+pub trait EventObserver {
+    /// Handles an event emitted by the client of `connection`.
+    fn handle_event(&mut self, connection: &Rc<RefCell<Connection>>, event: comm::client::Event);
+}
+
+pub trait MessageObserver {
+    fn did_receieve_acknowledgement(&self);
+    /// Called with each emoji reacted with and how many times, in the order they were first used.
+    fn reactions_were_changed(&self, Vec<(String, usize)>);
+    /// Called with the new text of a message its sender has edited.
+    fn text_was_edited(&self, String);
+    /// Called when the sender has deleted the message for everyone.
+    fn was_deleted(&self);
+    /// Called when the recipient of a sent message says they've read it.
+    fn did_receive_read_receipt(&self);
+    /// Called when another message is collapsed into a placeholder for hidden messages.
+    fn hidden_count_was_changed(&self, usize);
+    /// Called when a member of a group acknowledges a message sent to it, with how many members
+    /// have and how many it was sent to. Once all have, the message is acknowledged.
+    fn acknowledgements_were_changed(&self, usize, usize);
+    /// Called when a sent message is given up on because encryption couldn't be set up with the
+    /// recipient.
+    fn did_fail(&self);
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Profile {
+    secret: Option<String>,
+    #[serde(default)]
+    routers: Vec<String>,
+    port: Option<u16>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    disable_typing_indicators: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    send_read_receipts: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    disable_encryption: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    disable_signing: bool,
+    #[serde(default, skip_serializing_if = "Limits::is_default")]
+    limits: Limits
+}
+
+impl Profile {
+    pub fn update(&mut self, secret: Option<String>, routers: Vec<String>, port: Option<u16>) {
+        self.secret = secret;
+        self.routers = routers;
+        self.port = port;
+    }
+
+    pub fn secret(&self) -> &Option<String> {
+        &self.secret
+    }
+
+    pub fn routers(&self) -> &Vec<String> {
+        &self.routers
+    }
+
+    pub fn port(&self) -> &Option<u16> {
+        &self.port
+    }
+
+    /// Whether recipients are told when this identity is typing to them.
+    pub fn sends_typing_indicators(&self) -> bool {
+        !self.disable_typing_indicators
+    }
+
+    pub fn set_sends_typing_indicators(&mut self, sends: bool) {
+        self.disable_typing_indicators = !sends;
+    }
+
+    /// Whether senders are told when this identity has read their messages. Off unless the user
+    /// opts in.
+    pub fn sends_read_receipts(&self) -> bool {
+        self.send_read_receipts
+    }
+
+    pub fn set_sends_read_receipts(&mut self, sends: bool) {
+        self.send_read_receipts = sends;
+    }
+
+    /// Whether messages from this identity are end-to-end encrypted. Turning it off lets it talk
+    /// to comm clients that only read plain text.
+    pub fn encrypts_messages(&self) -> bool {
+        !self.disable_encryption
+    }
+
+    pub fn set_encrypts_messages(&mut self, encrypts: bool) {
+        self.disable_encryption = !encrypts;
+    }
+
+    /// Whether messages from this identity are signed, so recipients can tell they're from it.
+    /// Like encryption, it's only understood by clients that read more than plain text.
+    pub fn signs_messages(&self) -> bool {
+        !self.disable_signing
+    }
+
+    pub fn set_signs_messages(&mut self, signs: bool) {
+        self.disable_signing = !signs;
+    }
+
+    /// How much other people may send this identity before their messages are hidden or
+    /// discarded.
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+}
+
+pub const DEFAULT_PROFILE: &str = "default";
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Configuration {
+    #[serde(default)]
+    profiles: BTreeMap<String, Profile>,
+    current_profile: Option<String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    keybindings: HashMap<String, String>,
+
+    // Single-identity settings written by earlier versions. They're moved into the default profile
+    // when loaded.
+    #[serde(default, skip_serializing)]
+    secret: Option<String>,
+    #[serde(default, skip_serializing)]
+    router: Option<String>,
+    #[serde(default, skip_serializing)]
+    port: Option<u16>
+}
+
+impl Configuration {
+    pub fn load_from_config_or_empty(config_file_path: path::PathBuf) -> Configuration {
+        debug!("Loading config from {:?}", config_file_path);
+        let mut configuration = fs::File::open(config_file_path).ok()
+            .and_then(|file| serde_yaml::from_reader(file).ok())
+            .unwrap_or_else(|| Self::empty());
+        configuration.migrate_legacy_profile();
+        configuration
+    }
+
+    pub fn empty() -> Configuration {
+        Configuration {
+            profiles: BTreeMap::new(),
+            current_profile: None,
+            keybindings: HashMap::new(),
+            secret: None,
+            router: None,
+            port: None
+        }
+    }
+
+    fn migrate_legacy_profile(&mut self) {
+        if self.secret.is_none() && self.router.is_none() && self.port.is_none() {
+            return;
+        }
+        let profile = Profile {
+            secret: self.secret.take(),
+            routers: self.router.take().into_iter().collect(),
+            port: self.port.take(),
+            disable_typing_indicators: false,
+            send_read_receipts: false,
+            disable_encryption: false,
+            disable_signing: false,
+            limits: Limits::default()
+        };
+        self.profiles.entry(String::from(DEFAULT_PROFILE)).or_insert(profile);
+    }
+
+    pub fn save(&self, config_file_path: path::PathBuf) -> io::Result<()> {
+        use std::io::prelude::*;
+        debug!("Saving config to {:?}", config_file_path);
+        match serde_yaml::to_string(self) {
+            Ok(yaml) => {
+                if let Some(dir) = config_file_path.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                fs::File::create(config_file_path)
+                    .and_then(|mut file| file.write_all(&yaml.into_bytes()))
+            }
+
+            Err(err) => {
+                warn!("Failed to save configuration");
+                Err(Error::new(ErrorKind::Other, err))
+            }
+        }
+    }
+
+    pub fn profile_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.profiles.keys().cloned().collect();
+        if names.is_empty() {
+            names.push(String::from(DEFAULT_PROFILE));
+        }
+        names
+    }
+
+    /// Name of the profile in use, falling back to the first configured one.
+    pub fn current_profile_name(&self) -> String {
+        self.current_profile.clone()
+            .or_else(|| self.profiles.keys().next().cloned())
+            .unwrap_or_else(|| String::from(DEFAULT_PROFILE))
+    }
+
+    /// Makes `name` the profile opened on startup, creating an empty one if it doesn't exist yet.
+    pub fn select_profile(&mut self, name: &str) {
+        self.add_profile(name);
+        self.current_profile = Some(String::from(name));
+    }
+
+    pub fn add_profile(&mut self, name: &str) {
+        self.profiles.entry(String::from(name)).or_insert_with(Profile::default);
+    }
+
+    pub fn profile(&self, name: &str) -> Profile {
+        self.profiles.get(name).cloned().unwrap_or_default()
+    }
+
+    pub fn profile_mut(&mut self, name: &str) -> &mut Profile {
+        self.profiles.entry(String::from(name)).or_insert_with(Profile::default)
+    }
+
+    pub fn keybindings(&self) -> &HashMap<String, String> {
+        &self.keybindings
+    }
+}
+
+/// Starts comm clients for connections. Tests substitute a fake so that no network is involved.
+pub trait Transport {
+    /// Runs a client for `self_address` listening on `port`, forwarding its events to `events`.
+    /// Returns the channel its tasks are sent on.
+    fn start(&self, self_address: Address, port: u16, routers: Vec<comm::node::Node>,
+             events: mpsc::Sender<comm::client::Event>) -> comm::client::TaskSender;
+}
+
+/// Runs a real comm client on its own network thread.
+pub struct CommTransport {
+    host: &'static str
+}
+
+impl CommTransport {
+    /// Listens on every interface.
+    pub fn new() -> CommTransport {
+        Self::on_host("0.0.0.0")
+    }
+
+    pub fn on_host(host: &'static str) -> CommTransport {
+        CommTransport {
+            host: host
+        }
+    }
+}
+
+impl Transport for CommTransport {
+    fn start(&self, self_address: Address, port: u16, routers: Vec<comm::node::Node>,
+             events: mpsc::Sender<comm::client::Event>) -> comm::client::TaskSender {
+        let network = comm::network::Network::new(self_address, (self.host, port), routers);
+        let mut client = comm::client::Client::new(self_address);
+        client.register_event_listener(events);
+        client.run(network)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    Running,
+    Starting,
+    Stopped,
+    Stopping
+}
+
+pub trait ConnectionObserver {
+    fn connection_started(&mut self, self_address: Address);
+    fn connection_shutdown(&mut self);
+    /// Called with every blocked address when one is blocked or unblocked.
+    fn blocklist_was_changed(&mut self, blocked: Vec<Address>);
+}
+
+pub struct Connection {
+    name: String,
+    transport: Rc<Transport>,
+    event_sender: mpsc::Sender<comm::client::Event>,
+    commands: Option<comm::client::TaskSender>,
+    self_address: Option<comm::address::Address>,
+    state: ConnectionState,
+    sends_typing_indicators: bool,
+    sends_read_receipts: bool,
+    encrypts_messages: bool,
+    signs_messages: bool,
+    sessions: Sessions,
+    sessions_path: Option<path::PathBuf>,
+    blocked: Vec<Address>,
+    limiter: RateLimiter,
+    unsaved: Cell<bool>,
+    observers: ObserverSet<ConnectionObserver>
+}
+
+impl Connection {
+    /// Creates a stopped connection for the identity of the profile called `name`.
+    pub fn new(name: &str) -> (Rc<RefCell<Connection>>, comm::client::Events) {
+        Self::with_transport(name, Rc::new(CommTransport::new()))
+    }
+
+    /// Creates a stopped connection whose client is started by `transport`.
+    pub fn with_transport(name: &str, transport: Rc<Transport>)
+        -> (Rc<RefCell<Connection>>, comm::client::Events) {
+        let (event_sender, events) = mpsc::channel();
+
+        let connection = Rc::new(RefCell::new(Connection {
+            name: String::from(name),
+            transport: transport,
+            event_sender: event_sender,
+            commands: None,
+            self_address: None,
+            state: ConnectionState::Stopped,
+            sends_typing_indicators: true,
+            sends_read_receipts: false,
+            encrypts_messages: true,
+            signs_messages: true,
+            sessions: Sessions::default(),
+            sessions_path: None,
+            blocked: vec![],
+            limiter: RateLimiter::new(Limits::default()),
+            unsaved: Cell::new(false),
+            observers: ObserverSet::new()
+        }));
+
+        (connection, events)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn commands(&self) -> comm::client::TaskSender {
+        self.commands.as_ref().unwrap().clone()
+    }
+
+    pub fn self_address(&self) -> Address {
+        self.self_address.unwrap()
+    }
+
+    /// The identity's own address, once the connection has started and it's known.
+    pub fn started_address(&self) -> Option<Address> {
+        self.self_address
+    }
+
+    pub fn shutdown(&mut self) {
+        if let Some(c) = self.commands.as_ref() {
+            c.send(comm::client::Task::Shutdown).expect("Failed to send Shutdown");
+            self.state = ConnectionState::Stopping;
+        }
+    }
+
+    pub fn start(&mut self, profile: &Profile) -> Result<(), String> {
+        let self_address;
+        match profile.secret().as_ref() {
+            Some(s) => {
+                self_address = comm::address::Address::for_content(s.as_str());
+                self.sessions.set_identity(self_address, KeyPair::for_secret(s));
+                self.sessions.set_signing_key(SigningKey::for_secret(s));
+            }
+
+            None => {
+                return Err(String::from("Invalid secret provided"));
+            }
+        }
+
+        let port;
+        if let &Some(p) = profile.port() {
+            port = p;
+        } else {
+            return Err(String::from("Invalid port provided"));
+        }
+
+
+        let routers: Vec<comm::node::Node> = profile.routers().iter().filter_map(|r| {
+            comm::node::Node::from_socket_addrs(comm::address::Address::null(), r.as_str()).ok()
+        }).collect();
+
+        if routers.is_empty() {
+            // This is still a valid client, but they will not be connected to the network
+            // until someone uses them as a bootstrap node.
+            warn!("No routers were provided");
+        }
+
+        self.self_address = Some(self_address);
+        self.sends_typing_indicators = profile.sends_typing_indicators();
+        self.sends_read_receipts = profile.sends_read_receipts();
+        self.encrypts_messages = profile.encrypts_messages();
+        self.signs_messages = profile.signs_messages();
+        self.limiter.set_limits(profile.limits());
+        self.commands = Some(self.transport.start(self_address, port, routers, self.event_sender.clone()));
+        self.state = ConnectionState::Starting;
+
+        Ok(())
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    pub fn sends_typing_indicators(&self) -> bool {
+        self.sends_typing_indicators
+    }
+
+    /// Changes whether conversations held as this identity tell recipients when the user types.
+    pub fn set_sends_typing_indicators(&mut self, sends: bool) {
+        self.sends_typing_indicators = sends;
+    }
+
+    pub fn sends_read_receipts(&self) -> bool {
+        self.sends_read_receipts
+    }
+
+    /// Changes whether conversations held as this identity send read receipts.
+    pub fn set_sends_read_receipts(&mut self, sends: bool) {
+        self.sends_read_receipts = sends;
+    }
+
+    pub fn encrypts_messages(&self) -> bool {
+        self.encrypts_messages
+    }
+
+    /// Changes whether messages sent as this identity are end-to-end encrypted. Sessions already
+    /// set up are kept, and received messages are decrypted either way. Conversations aren't told;
+    /// change it through `ConversationList::set_encrypts_messages` once they've been loaded.
+    pub fn set_encrypts_messages(&mut self, encrypts: bool) {
+        self.encrypts_messages = encrypts;
+    }
+
+    pub fn signs_messages(&self) -> bool {
+        self.signs_messages
+    }
+
+    /// Changes whether messages sent as this identity are signed. Received messages are checked
+    /// either way.
+    pub fn set_signs_messages(&mut self, signs: bool) {
+        self.signs_messages = signs;
+    }
+
+    /// How what's sent to `recipient` is protected.
+    pub fn encryption(&self, recipient: Address) -> EncryptionState {
+        if self.encrypts_messages {
+            self.sessions.state(recipient)
+        } else {
+            EncryptionState::Unencrypted
+        }
+    }
+
+    /// Schedules delivery of `message` to `recipient`, encrypted if this identity encrypts
+    /// messages. Until the session with the recipient is set up, it waits in the session. Returns
+    /// the ids of messages given up on because the recipient never answered the key exchange.
+    pub fn send(&mut self, recipient: Address, message: comm::client::messages::TextMessage) -> Vec<Address> {
+        if !self.encrypts_messages {
+            self.deliver(recipient, vec![message]);
+            return vec![];
+        }
+        let failed = self.sessions.expire(recipient);
+        let messages = self.sessions.seal(recipient, message);
+        self.save_sessions();
+        self.deliver(recipient, messages);
+        failed
+    }
+
+    /// Schedules delivery of `message` to `recipient` without encrypting it.
+    pub fn send_unencrypted(&mut self, recipient: Address, message: comm::client::messages::TextMessage) {
+        self.deliver(recipient, vec![message]);
+    }
+
+    /// Stops waiting for `recipient` to answer the key exchange and sends whatever was waiting for
+    /// it unencrypted.
+    pub fn send_waiting_unencrypted(&mut self, recipient: Address) {
+        let waiting = self.sessions.abandon(recipient);
+        self.deliver(recipient, waiting);
+    }
+
+    /// Opens a received text message, answering key exchanges and sending whatever was waiting
+    /// for them. Returns the message as it was sent and whether it's from its sender, unless it
+    /// only concerned the session. Forged key exchanges are ignored.
+    pub fn open(&mut self, message: comm::client::messages::TextMessage)
+        -> Option<(comm::client::messages::TextMessage, Authenticity)> {
+        let sender = message.sender;
+        let (message, authenticity) = self.sessions.authenticate(message);
+        let payload = Payload::decode(&message.text);
+        if let (Authenticity::Forged, Payload::KeyExchange(_)) = (authenticity, payload) {
+            warn!("Ignoring forged key exchange from {}", sender.to_str());
+            return None;
+        }
+        let (opened, answers) = self.sessions.open(message);
+        if !answers.is_empty() {
+            self.save_sessions();
+        }
+        self.deliver(sender, answers);
+        opened.map(|opened| (opened, authenticity))
+    }
+
+    /// Saves the sessions where they were loaded from, if they were. Called before anything they
+    /// sealed is sent, so that a crash can't bring back keys that have been used.
+    fn save_sessions(&self) {
+        if let Some(ref path) = self.sessions_path {
+            if let Err(err) = save_sessions(path, &self.sessions) {
+                warn!("Failed to save sessions: {}", err);
+            }
+        }
+    }
+
+    /// Signs `messages` if this identity signs messages, and schedules their delivery.
+    fn deliver(&self, recipient: Address, messages: Vec<comm::client::messages::TextMessage>) {
+        let commands = self.commands();
+        for message in messages {
+            let message = if self.signs_messages { self.sessions.sign(recipient, message) } else { message };
+            commands.send(comm::client::Task::ScheduleMessageDelivery(recipient, message))
+                .expect("Couldn't send message");
+        }
+    }
+
+    /// Whether the identity key of `recipient` has been verified.
+    pub fn verification(&self, recipient: Address) -> Verification {
+        self.sessions.verification(recipient)
+    }
+
+    pub fn safety_number(&self, recipient: Address) -> Option<SafetyNumber> {
+        self.sessions.safety_number(recipient)
+    }
+
+    pub fn sessions(&self) -> &Sessions {
+        &self.sessions
+    }
+
+    /// Addresses whose messages to this identity are dropped unread.
+    pub fn blocked(&self) -> &Vec<Address> {
+        &self.blocked
+    }
+
+    pub fn is_blocked(&self, address: Address) -> bool {
+        self.blocked.contains(&address)
+    }
+
+    pub fn block(&mut self, address: Address) {
+        if self.is_blocked(address) {
+            return;
+        }
+        self.blocked.push(address);
+        self.changed();
+        self.announce_blocklist();
+    }
+
+    pub fn unblock(&mut self, address: Address) {
+        if !self.is_blocked(address) {
+            return;
+        }
+        self.blocked.retain(|&blocked| blocked != address);
+        self.changed();
+        self.announce_blocklist();
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limiter.limits()
+    }
+
+    /// Changes how much other people may send this identity. Messages already admitted still
+    /// count against the new limits.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limiter.set_limits(limits);
+    }
+
+    /// Whether a message from `sender` arriving now is within the limits. Only a `stranger` is held
+    /// to the total limit.
+    fn admit(&mut self, sender: Address, stranger: bool) -> bool {
+        self.limiter.admit(sender, stranger, Instant::now())
+    }
+
+    /// Notes that something stored for this identity has changed, so that it's saved.
+    fn changed(&self) {
+        self.unsaved.set(true);
+    }
+
+    /// Whether anything stored for this identity has changed since the last call.
+    fn take_changes(&self) -> bool {
+        self.unsaved.replace(false)
+    }
+
+    fn announce_blocklist(&mut self) {
+        let blocked = self.blocked.clone();
+        self.observers.notify(move |observer| {
+            observer.borrow_mut().blocklist_was_changed(blocked.clone());
+        });
+    }
+
+    fn handle_shutdown(&mut self) {
+        self.state = ConnectionState::Stopped;
+        self.commands = None;
+        self.self_address = None;
+        self.observers.notify(move |observer| {
+            observer.borrow_mut().connection_shutdown();
+        });
+    }
+
+    fn handle_started(&mut self) {
+        self.state = ConnectionState::Running;
+        let self_address = self.self_address();
+        self.observers.notify(move |observer| {
+            observer.borrow_mut().connection_started(self_address);
+        });
+    }
+}
+
+impl EventObserver for Connection {
+    fn handle_event(&mut self, _connection: &Rc<RefCell<Connection>>, event: comm::client::Event) {
+        match event {
+            comm::client::Event::Started => self.handle_started(),
+            comm::client::Event::Shutdown => self.handle_shutdown(),
+            _ => { }
+        }
+    }
+}
+
+impl Observable for Connection {
+    type Observer = ConnectionObserver;
+
+    fn observers(&mut self) -> &mut ObserverSet<ConnectionObserver> {
+        &mut self.observers
+    }
+}
+
+#[derive(PartialEq, Eq)]
+pub enum MessageDirection {
+    Sent, Received
+}
+
+/// How often, in seconds, typing notices are sent while the user keeps typing.
+const TYPING_INTERVAL_SECS: u64 = 3;
+
+/// How long, in seconds, the recipient is taken to be typing after their last typing notice.
+pub const TYPING_TIMEOUT_SECS: u64 = 6;
+
+/// How many characters of a message are quoted in replies to it.
+const QUOTE_LENGTH: usize = 80;
+
+/// Shown in place of messages of a kind this client can't show.
+const UNSUPPORTED_TEXT: &str = "Unsupported message";
+
+/// The copy of a message sent to one member of a group.
+struct Delivery {
+    member: Address,
+    id: Address,
+    acknowledged: bool
+}
+
+pub struct Message {
+    id: Address,
+    text: String,
+    direction: MessageDirection,
+    sender: Option<Address>,
+    acknowledged: bool,
+    deliveries: Vec<Delivery>,
+    supported: bool,
+    authentic: bool,
+    quote: Option<Quote>,
+    reactions: Vec<(Address, String)>,
+    history: Vec<String>,
+    deleted: bool,
+    read: bool,
+    hidden: usize,
+    failed: bool,
+    observers: ObserverSet<MessageObserver>
+}
+
+impl Message {
+    pub fn new(id: Address, text: String, direction: MessageDirection) -> Message {
+        Message {
+            id: id,
+            text: text,
+            direction: direction,
+            sender: None,
+            acknowledged: false,
+            deliveries: vec![],
+            supported: true,
+            authentic: true,
+            quote: None,
+            reactions: vec![],
+            history: vec![],
+            deleted: false,
+            read: false,
+            hidden: 0,
+            failed: false,
+            observers: ObserverSet::new()
+        }
+
+    }
+    pub fn sent(id: Address, text: String) -> Message {
+        Self::new(id, text, MessageDirection::Sent)
+    }
+
+    pub fn received(id: Address, text: String) -> Message {
+        Self::new(id, text, MessageDirection::Received)
+    }
+
+    /// A received message of a kind this client can't show.
+    pub fn unsupported(id: Address) -> Message {
+        let mut message = Self::received(id, String::from(UNSUPPORTED_TEXT));
+        message.supported = false;
+        message
+    }
+
+    /// Stands in for a received message that was over the limits, and the ones after it until
+    /// another is shown.
+    pub fn hidden(id: Address) -> Message {
+        let mut message = Self::received(id, hidden_text(1));
+        message.hidden = 1;
+        message
+    }
+
+    /// How many messages this placeholder stands in for, or 0 if it's a message itself.
+    pub fn hidden_count(&self) -> usize {
+        self.hidden
+    }
+
+    fn hide_another(&mut self) {
+        self.hidden += 1;
+        self.text = hidden_text(self.hidden);
+        let hidden = self.hidden;
+        self.observers.notify(move |observer| {
+            observer.borrow().hidden_count_was_changed(hidden);
+        });
+    }
+
+    /// False for messages of a kind this client can't show, whose text just says so.
+    pub fn is_supported(&self) -> bool {
+        self.supported
+    }
+
+    /// False for received messages that can't be told to be from their sender, because they
+    /// weren't signed or were forged.
+    pub fn is_authentic(&self) -> bool {
+        self.authentic
+    }
+
+    pub fn acknowledged(&self) -> bool {
+        self.acknowledged
+    }
+
+    /// For a message sent to a group, how many members have acknowledged it and how many it was
+    /// sent to.
+    pub fn acknowledgements(&self) -> (usize, usize) {
+        let acknowledged = self.deliveries.iter().filter(|delivery| delivery.acknowledged).count();
+        (acknowledged, self.deliveries.len())
+    }
+
+    /// Who sent a received message. Messages received before senders were kept don't say.
+    pub fn sender(&self) -> Option<Address> {
+        self.sender
+    }
+
+    pub fn id(&self) -> Address {
+        self.id
+    }
+
+    pub fn text(&self) -> &str{
+        &self.text
+    }
+
+    /// The message this one replies to.
+    pub fn quote(&self) -> Option<&Quote> {
+        self.quote.as_ref()
+    }
+
+    /// Earlier versions of the text, oldest first.
+    pub fn history(&self) -> &Vec<String> {
+        &self.history
+    }
+
+    pub fn is_edited(&self) -> bool {
+        !self.history.is_empty()
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self.deleted
+    }
+
+    /// For a sent message, whether the recipient has said they've read it. For a received one,
+    /// whether the user has.
+    pub fn is_read(&self) -> bool {
+        self.read
+    }
+
+    /// Whether a sent message was given up on, because encryption couldn't be set up with the
+    /// recipient.
+    pub fn has_failed(&self) -> bool {
+        self.failed
+    }
+
+    fn fail(&mut self) {
+        if self.failed {
+            return;
+        }
+        self.failed = true;
+        self.observers.notify(move |observer| {
+            observer.borrow().did_fail();
+        });
+    }
+
+    fn receive_read_receipt(&mut self) {
+        if self.read {
+            return;
+        }
+        self.read = true;
+        self.observers.notify(move |observer| {
+            observer.borrow().did_receive_read_receipt();
+        });
+    }
+
+    fn edit(&mut self, text: String) {
+        if self.deleted || text == self.text {
+            return;
+        }
+        let previous = ::std::mem::replace(&mut self.text, text.clone());
+        self.history.push(previous);
+        self.observers.notify(move |observer| {
+            observer.borrow().text_was_edited(text.clone());
+        });
+    }
+
+    fn delete(&mut self) {
+        if self.deleted {
+            return;
+        }
+        self.deleted = true;
+        self.text.clear();
+        self.history.clear();
+        self.quote = None;
+        self.observers.notify(move |observer| {
+            observer.borrow().was_deleted();
+        });
+    }
+
+    /// Refers to this message from a reply to it.
+    pub fn to_quote(&self) -> Quote {
+        let mut excerpt: String = self.text.chars().take(QUOTE_LENGTH).collect();
+        if excerpt.len() < self.text.len() {
+            excerpt.push('…');
+        }
+        Quote { message_id: self.id.to_str(), excerpt: excerpt }
+    }
+
+    pub fn was_sent(&self) -> bool {
+        self.direction == MessageDirection::Sent
+    }
+
+    pub fn was_received(&self) -> bool {
+        self.direction == MessageDirection::Received
+    }
+
+    fn receive_acknowledgement(&mut self) {
+        self.acknowledged = true;
+        self.observers.notify(move |observer| {
+            observer.borrow().did_receieve_acknowledgement();
+        });
+    }
+
+    /// Whether `id` is the id of the copy of this message sent to a member of a group.
+    fn was_delivered_as(&self, id: Address) -> bool {
+        self.deliveries.iter().any(|delivery| delivery.id == id)
+    }
+
+    /// Records the acknowledgement of the copy with the id `id`. The message is acknowledged
+    /// once every member's copy is.
+    fn receive_member_acknowledgement(&mut self, id: Address) {
+        match self.deliveries.iter_mut().find(|delivery| delivery.id == id) {
+            Some(delivery) if !delivery.acknowledged => delivery.acknowledged = true,
+            _ => return
+        }
+        let (acknowledged, members) = self.acknowledgements();
+        self.observers.notify(move |observer| {
+            observer.borrow().acknowledgements_were_changed(acknowledged, members);
+        });
+        if acknowledged == members {
+            self.receive_acknowledgement();
+        }
+    }
+
+    /// Each emoji the message has been reacted with and how many times.
+    pub fn reactions(&self) -> Vec<(String, usize)> {
+        let mut reactions: Vec<(String, usize)> = vec![];
+        for &(_, ref emoji) in self.reactions.iter() {
+            match reactions.iter().position(|&(ref e, _)| e == emoji) {
+                Some(index) => reactions[index].1 += 1,
+                None => reactions.push((emoji.clone(), 1))
+            }
+        }
+        reactions
+    }
+
+    pub fn has_reaction(&self, reactor: Address, emoji: &str) -> bool {
+        self.reactions.iter().any(|&(r, ref e)| r == reactor && e == emoji)
+    }
+
+    fn apply_reaction(&mut self, reactor: Address, reaction: &Reaction) {
+        let already_reacted = self.has_reaction(reactor, &reaction.emoji);
+        if reaction.removed {
+            self.reactions.retain(|&(r, ref e)| r != reactor || *e != reaction.emoji);
+        } else if !already_reacted {
+            self.reactions.push((reactor, reaction.emoji.clone()));
+        }
+        if already_reacted == reaction.removed {
+            let reactions = self.reactions();
+            self.observers.notify(move |observer| {
+                observer.borrow().reactions_were_changed(reactions.clone());
+            });
+        }
+    }
+}
+
+/// A random id for a new group.
+fn new_group_id() -> String {
+    let mut id = [0u8; 16];
+    OsRng::new().expect("No source of randomness").fill_bytes(&mut id);
+    id.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The text of a placeholder standing in for `count` hidden messages.
+pub fn hidden_text(count: usize) -> String {
+    if count == 1 {
+        String::from("1 message hidden")
+    } else {
+        format!("{} messages hidden", count)
+    }
+}
+
+impl Observable for Message {
+    type Observer = MessageObserver;
+
+    fn observers(&mut self) -> &mut ObserverSet<MessageObserver> {
+        &mut self.observers
+    }
+}
+
+pub struct Conversation {
+    connection: Rc<RefCell<Connection>>,
+    recipient: Option<Address>,
+    group_id: Option<String>,
+    members: Vec<Address>,
+    pending_message: String,
+    reply_to: Option<Quote>,
+    typing_sent_at: Option<Instant>,
+    recipient_typing_at: Option<Instant>,
+    messages: Vec<Rc<RefCell<Message>>>,
+    request: bool,
+    sends_unencrypted: bool,
+    observers: ObserverSet<ConversationObserver>
+}
+
+impl Conversation {
+    pub fn new(connection: Rc<RefCell<Connection>>) -> Conversation {
+        Conversation {
+            connection: connection,
+            recipient: None,
+            group_id: None,
+            members: vec![],
+            pending_message: String::new(),
+            reply_to: None,
+            typing_sent_at: None,
+            recipient_typing_at: None,
+            messages: vec![],
+            request: false,
+            sends_unencrypted: false,
+            observers: ObserverSet::new()
+        }
+    }
+
+    pub fn has_started(&self) -> bool {
+        !self.messages.is_empty()
+    }
+
+    /// Whether the conversation was started by someone the user hasn't talked to as this identity,
+    /// and hasn't been accepted yet. Read receipts aren't sent until it has.
+    pub fn is_request(&self) -> bool {
+        self.request
+    }
+
+    pub fn connection(&self) -> &Rc<RefCell<Connection>> {
+        &self.connection
+    }
+
+    /// Name of the identity this conversation is held as.
+    pub fn identity(&self) -> String {
+        String::from(self.connection.borrow().name())
+    }
+
+    /// Notes that something stored about the conversation has changed.
+    fn changed(&self) {
+        self.connection.borrow().changed();
+    }
+
+    /// Changes which identity the conversation is held as. Only possible before it has started.
+    pub fn set_connection(&mut self, connection: Rc<RefCell<Connection>>) {
+        if self.has_started() || Rc::ptr_eq(&self.connection, &connection) {
+            return;
+        }
+        self.connection = connection;
+        let identity = self.identity();
+        self.observers.notify(move |observer| {
+            observer.borrow().identity_was_changed(identity.clone());
+        });
+    }
+
+    pub fn messages(&self) -> &Vec<Rc<RefCell<Message>>> {
+        &self.messages
+    }
+
+    pub fn pending_message(&self) -> &str {
+        &self.pending_message
+    }
+
+    pub fn set_pending_message(&mut self, text: String) {
+        self.pending_message = text.clone();
+        self.observers.notify(move |observer| {
+            observer.borrow().pending_message_was_changed(text.clone());
+        });
+        self.announce_typing();
+    }
+
+    /// Tells the recipient the user is typing, at most every `TYPING_INTERVAL_SECS`, or that they've
+    /// stopped once the pending message is cleared.
+    fn announce_typing(&mut self) {
+        // Groups aren't told, as every member typing at once would be more noise than news
+        let recipient = match self.recipient {
+            Some(recipient) => recipient,
+            None => return
+        };
+        {
+            let connection = self.connection.borrow();
+            let running = connection.state() == ConnectionState::Running;
+            if !running || !connection.sends_typing_indicators() {
+                return;
+            }
+            // Typing notices are stale by the time a session is set up, so they don't wait for one
+            let encrypted = connection.encryption(recipient) == EncryptionState::Encrypted;
+            if connection.encrypts_messages() && !encrypted {
+                return;
+            }
+        }
+
+        let typing = !self.pending_message.is_empty();
+        let now = Instant::now();
+        let due = match self.typing_sent_at {
+            Some(sent_at) => !typing || now.duration_since(sent_at) >= Duration::from_secs(TYPING_INTERVAL_SECS),
+            None => typing
+        };
+        if due {
+            self.deliver(&Payload::Typing(Typing { stopped: !typing }));
+            self.typing_sent_at = if typing { Some(now) } else { None };
+        }
+    }
+
+    /// Whether the recipient has said they're typing within the last `TYPING_TIMEOUT_SECS`.
+    pub fn recipient_is_typing(&self) -> bool {
+        let timeout = Duration::from_secs(TYPING_TIMEOUT_SECS);
+        self.recipient_typing_at.map_or(false, |at| at.elapsed() < timeout)
+    }
+
+    fn set_recipient_typing(&mut self, typing: bool) {
+        let was_typing = self.recipient_is_typing();
+        self.recipient_typing_at = if typing { Some(Instant::now()) } else { None };
+        if typing != was_typing {
+            self.observers.notify(move |observer| {
+                observer.borrow().recipient_typing_was_changed(typing);
+            });
+        }
+    }
+
+    /// The message the next one sent will reply to.
+    pub fn reply_to(&self) -> Option<&Quote> {
+        self.reply_to.as_ref()
+    }
+
+    /// Makes the next message sent a reply to `message`, or a plain message if it's `None`.
+    pub fn set_reply_to(&mut self, message: Option<&Rc<RefCell<Message>>>) {
+        let quote = message.map(|message| message.borrow().to_quote());
+        self.reply_to = quote.clone();
+        self.observers.notify(move |observer| {
+            observer.borrow().reply_was_changed(quote.clone());
+        });
+    }
+
+    /// Makes the conversation one with `recipient` alone.
+    pub fn set_recipient(&mut self, recipient: Option<Address>) {
+        self.recipient = recipient;
+        self.group_id = None;
+        self.members.clear();
+        self.observers.notify(move |observer| {
+            observer.borrow().recipient_was_changed(recipient);
+        });
+        self.announce_encryption();
+        self.announce_verification();
+    }
+
+    pub fn is_group(&self) -> bool {
+        self.group_id.is_some()
+    }
+
+    /// Identifies the group among its members.
+    pub fn group_id(&self) -> Option<&str> {
+        self.group_id.as_ref().map(|id| id.as_str())
+    }
+
+    /// Everyone in the group but the user.
+    pub fn members(&self) -> &Vec<Address> {
+        &self.members
+    }
+
+    /// Everyone else in the conversation: the members of a group, or the recipient.
+    pub fn recipients(&self) -> Vec<Address> {
+        match self.group_id {
+            Some(_) => self.members.clone(),
+            None => self.recipient.into_iter().collect()
+        }
+    }
+
+    /// Makes the conversation a group with `members`, who are invited along with the first message.
+    /// Only possible before it has started. The user's own address is left out.
+    pub fn set_members(&mut self, members: Vec<Address>) {
+        if self.has_started() {
+            return;
+        }
+        if self.group_id.is_none() {
+            self.group_id = Some(new_group_id());
+        }
+        self.recipient = None;
+        let self_address = self.connection.borrow().started_address();
+        let members = members.into_iter().filter(|&member| Some(member) != self_address).collect();
+        self.change_members(members);
+    }
+
+    /// Adds `member` to the group, and once it has started, tells everyone in it, the new member
+    /// included. Before then, they're invited along with the first message like everyone else.
+    pub fn invite(&mut self, member: Address) {
+        let self_address = self.connection.borrow().started_address();
+        if !self.is_group() || Some(member) == self_address || self.members.contains(&member) {
+            return;
+        }
+        let mut members = self.members.clone();
+        members.push(member);
+        self.change_members(members);
+        if self.has_started() {
+            self.send_invites();
+        }
+    }
+
+    /// Tells every member who's in the group, which adds whoever wasn't yet.
+    fn send_invites(&self) {
+        if let Some(ref group_id) = self.group_id {
+            let mut members: Vec<String> = self.members.iter().map(|member| member.to_str()).collect();
+            members.push(self.connection.borrow().self_address().to_str());
+            let invite = Invite { group_id: group_id.clone(), members: members };
+            self.deliver_to_members(&Payload::Invite(invite));
+        }
+    }
+
+    /// Takes `member`, who has left, out of the group.
+    fn receive_leave(&mut self, member: Address) {
+        if self.members.contains(&member) {
+            let members = self.members.iter().cloned().filter(|&other| other != member).collect();
+            self.change_members(members);
+        }
+    }
+
+    /// Tells the members of the group that the user has left it.
+    fn leave(&mut self) {
+        if let Some(group_id) = self.group_id.clone() {
+            self.deliver_to_members(&Payload::Leave(Leave { group_id: group_id }));
+            self.change_members(vec![]);
+        }
+    }
+
+    fn change_members(&mut self, members: Vec<Address>) {
+        self.members = members.clone();
+        self.changed();
+        self.observers.notify(move |observer| {
+            observer.borrow().members_were_changed(members.clone());
+        });
+        self.announce_encryption();
+        self.announce_verification();
+    }
+
+    /// How what's sent in this conversation is protected.
+    pub fn encryption(&self) -> EncryptionState {
+        if self.sends_unencrypted {
+            return EncryptionState::Unencrypted;
+        }
+        if self.is_group() {
+            // A group is only as well protected as the session with its least protected member
+            let connection = self.connection.borrow();
+            let states: Vec<EncryptionState> = self.members.iter()
+                .map(|&member| connection.encryption(member))
+                .collect();
+            return if states.is_empty() || states.contains(&EncryptionState::Unencrypted) {
+                EncryptionState::Unencrypted
+            } else if states.contains(&EncryptionState::Pending) {
+                EncryptionState::Pending
+            } else {
+                EncryptionState::Encrypted
+            };
+        }
+        match self.recipient {
+            Some(recipient) => self.connection.borrow().encryption(recipient),
+            None => EncryptionState::Unencrypted
+        }
+    }
+
+    /// Whether the user has given up on encrypting what's sent in this conversation.
+    pub fn sends_unencrypted(&self) -> bool {
+        self.sends_unencrypted
+    }
+
+    /// Gives up on encrypting what's sent in this conversation, for recipients that never answer
+    /// the key exchange, such as clients that only read plain text. Whatever is waiting for them
+    /// is sent at once.
+    pub fn send_unencrypted(&mut self) {
+        if self.sends_unencrypted {
+            return;
+        }
+        self.sends_unencrypted = true;
+        for recipient in self.recipients() {
+            self.connection.borrow_mut().send_waiting_unencrypted(recipient);
+        }
+        self.changed();
+        self.announce_encryption();
+    }
+
+    fn announce_encryption(&self) {
+        let encryption = self.encryption();
+        self.observers.notify(move |observer| {
+            observer.borrow().encryption_was_changed(encryption);
+        });
+    }
+
+    /// Whether the recipient's identity key has been verified.
+    pub fn verification(&self) -> Verification {
+        match self.recipient {
+            Some(recipient) => self.connection.borrow().verification(recipient),
+            None => Verification::Unverified
+        }
+    }
+
+    /// The safety number to compare with the recipient's, once a session has been set up.
+    pub fn safety_number(&self) -> Option<SafetyNumber> {
+        self.recipient.and_then(|recipient| self.connection.borrow().safety_number(recipient))
+    }
+
+    /// Marks the recipient's identity key as verified, or not, after comparing safety numbers.
+    pub fn set_verified(&mut self, verified: bool) {
+        if let Some(recipient) = self.recipient {
+            self.connection.borrow_mut().sessions.set_verified(recipient, verified);
+            self.changed();
+            self.announce_verification();
+        }
+    }
+
+    /// Stops warning that the recipient's identity key has changed, without verifying it.
+    pub fn dismiss_key_change(&mut self) {
+        if let Some(recipient) = self.recipient {
+            self.connection.borrow_mut().sessions.dismiss_key_change(recipient);
+            self.changed();
+            self.announce_verification();
+        }
+    }
+
+    fn announce_verification(&self) {
+        let verification = self.verification();
+        self.observers.notify(move |observer| {
+            observer.borrow().verification_was_changed(verification);
+        });
+    }
+
+    pub fn receive_message(&mut self, message: Rc<RefCell<Message>>) {
+        if message.borrow().was_received() {
+            self.set_recipient_typing(false);
+        }
+        self.messages.push(message.clone());
+        self.changed();
+        self.observers.notify(move |observer| {
+            observer.borrow_mut().did_receive_message(message.clone());
+        })
+    }
+
+    /// Collapses the received message with the id `id` into the placeholder at the end of the
+    /// transcript, starting one if the last message isn't already.
+    fn hide_message(&mut self, id: Address) {
+        let placeholder = self.messages.last()
+            .and_then(|message| if message.borrow().hidden > 0 { Some(message.clone()) } else { None });
+        match placeholder {
+            Some(placeholder) => {
+                placeholder.borrow_mut().hide_another();
+                self.changed();
+            }
+            None => self.receive_message(Rc::new(RefCell::new(Message::hidden(id))))
+        }
+    }
+
+    pub fn recipient(&self) -> Option<Address> {
+        self.recipient
+    }
+
+    /// The message in this conversation with the id `id`, if there is one.
+    pub fn find_message(&self, id: &str) -> Option<Rc<RefCell<Message>>> {
+        self.messages.iter().find(|message| message.borrow().id.to_str() == id).cloned()
+    }
+
+    /// Sends `payload` to everyone else in the conversation.
+    fn deliver(&self, payload: &Payload) -> comm::client::messages::TextMessage {
+        self.deliver_to(&self.recipients(), payload).0
+    }
+
+    /// Sends `payload` to `recipients`. In a group, each of them is sent a copy of their own,
+    /// which are returned along with the message as the group knows it.
+    fn deliver_to(&self, recipients: &[Address], payload: &Payload)
+        -> (comm::client::messages::TextMessage, Vec<Delivery>) {
+        let self_address = self.connection.borrow().self_address();
+        let tm = comm::client::messages::TextMessage::new(self_address, payload.encode());
+
+        let encryption = self.encryption();
+        let mut deliveries = vec![];
+        let mut failed = vec![];
+        for &recipient in recipients {
+            match self.group_id {
+                Some(ref group_id) => {
+                    let group = GroupMessage {
+                        group_id: group_id.clone(),
+                        message_id: tm.id.to_str(),
+                        text: tm.text.clone()
+                    };
+                    let copy = comm::client::messages::TextMessage::new(
+                        self_address, Payload::Group(group).encode());
+                    deliveries.push(Delivery { member: recipient, id: copy.id, acknowledged: false });
+                    failed.append(&mut self.send(recipient, copy));
+                }
+                None => failed.append(&mut self.send(recipient, tm.clone()))
+            }
+        }
+        self.fail_messages(&failed);
+        if self.encryption() != encryption {
+            self.announce_encryption();
+        }
+        (tm, deliveries)
+    }
+
+    /// Sends `payload` about the group itself to each member, outside of a group message.
+    fn deliver_to_members(&self, payload: &Payload) {
+        let self_address = self.connection.borrow().self_address();
+        let encryption = self.encryption();
+        let mut failed = vec![];
+        for &member in self.members.iter() {
+            let tm = comm::client::messages::TextMessage::new(self_address, payload.encode());
+            failed.append(&mut self.send(member, tm));
+        }
+        self.fail_messages(&failed);
+        if self.encryption() != encryption {
+            self.announce_encryption();
+        }
+    }
+
+    /// Sends `tm` to `recipient`, encrypted unless the user has given up on that here. Returns the
+    /// ids of messages given up on because the recipient never answered the key exchange.
+    fn send(&self, recipient: Address, tm: comm::client::messages::TextMessage) -> Vec<Address> {
+        let mut connection = self.connection.borrow_mut();
+        if self.sends_unencrypted {
+            connection.send_unencrypted(recipient, tm);
+            return vec![];
+        }
+        connection.send(recipient, tm)
+    }
+
+    /// Marks the messages sent with one of `ids`, or sent to a member with one, as given up on.
+    fn fail_messages(&self, ids: &[Address]) {
+        for message in self.messages.iter() {
+            let failed = {
+                let message = message.borrow();
+                ids.contains(&message.id) ||
+                    message.deliveries.iter().any(|delivery| ids.contains(&delivery.id))
+            };
+            if failed {
+                message.borrow_mut().fail();
+                self.changed();
+            }
+        }
+    }
+
+    pub fn send_message(&mut self) {
+        let recipients = self.recipients();
+        if !recipients.is_empty() {
+            // Members are invited along with the first message, once the user has settled on them
+            if self.is_group() && !self.has_started() {
+                self.send_invites();
+            }
+            let text = self.pending_message.clone();
+            let payload = match self.reply_to.clone() {
+                Some(quote) => Payload::Reply(Reply { text: text.clone(), in_reply_to: quote }),
+                None => Payload::Text(text.clone())
+            };
+            let (tm, deliveries) = self.deliver_to(&recipients, &payload);
+
+            // The message itself tells the recipient the user has stopped typing
+            self.typing_sent_at = None;
+            self.set_pending_message(String::new());
+
+            let mut message = Message::sent(tm.id, text);
+            message.deliveries = deliveries;
+            if self.reply_to.is_some() {
+                message.quote = self.reply_to.clone();
+                self.set_reply_to(None);
+            }
+            let message = Rc::new(RefCell::new(message));
+            self.messages.push(message.clone());
+            self.changed();
+
+            self.observers.notify(move |observer| {
+                observer.borrow_mut().did_send_message(message.clone());
+            });
+        }
+    }
+}
+
+impl Conversation {
+    /// Reacts to `message` with `emoji`, or takes the reaction back if it has already been made.
+    pub fn react(&mut self, message: &Rc<RefCell<Message>>, emoji: &str) {
+        if !self.recipients().is_empty() {
+            let self_address = self.connection.borrow().self_address();
+            let reaction = Reaction {
+                message_id: message.borrow().id.to_str(),
+                emoji: String::from(emoji),
+                removed: message.borrow().has_reaction(self_address, emoji)
+            };
+            self.deliver(&Payload::Reaction(reaction.clone()));
+            message.borrow_mut().apply_reaction(self_address, &reaction);
+            self.changed();
+        }
+    }
+
+    fn receive_reaction(&mut self, reactor: Address, reaction: &Reaction) {
+        match self.find_message(&reaction.message_id) {
+            Some(message) => {
+                message.borrow_mut().apply_reaction(reactor, reaction);
+                self.changed();
+            }
+            None => debug!("Ignoring reaction to unknown message {}", reaction.message_id)
+        }
+    }
+
+    /// Replaces the text of `message`, which must have been sent in this conversation, for
+    /// everyone.
+    pub fn edit_message(&mut self, message: &Rc<RefCell<Message>>, text: String) {
+        if !self.recipients().is_empty() {
+            if !message.borrow().was_sent() || message.borrow().is_deleted() {
+                return;
+            }
+            let edit = Edit { message_id: message.borrow().id.to_str(), text: text.clone() };
+            self.deliver(&Payload::Edit(edit));
+            message.borrow_mut().edit(text);
+            self.changed();
+        }
+    }
+
+    /// Deletes `message`, which must have been sent in this conversation, for everyone.
+    pub fn delete_message(&mut self, message: &Rc<RefCell<Message>>) {
+        if !self.recipients().is_empty() {
+            if !message.borrow().was_sent() || message.borrow().is_deleted() {
+                return;
+            }
+            let deletion = Deletion { message_id: message.borrow().id.to_str() };
+            self.deliver(&Payload::Deletion(deletion));
+            message.borrow_mut().delete();
+            self.changed();
+        }
+    }
+
+    /// The message with the id `id` that `sender` sent, which is all they may change.
+    fn find_received_message(&self, sender: Address, id: &str) -> Option<Rc<RefCell<Message>>> {
+        let message = self.find_message(id).and_then(|message| {
+            let changeable = {
+                let message = message.borrow();
+                message.was_received() && message.hidden == 0 &&
+                    message.sender.map_or(true, |message_sender| message_sender == sender)
+            };
+            if changeable { Some(message) } else { None }
+        });
+        if message.is_none() {
+            debug!("Ignoring change to unknown message {}", id);
+        }
+        message
+    }
+
+    fn receive_edit(&mut self, sender: Address, edit: Edit) {
+        if let Some(message) = self.find_received_message(sender, &edit.message_id) {
+            message.borrow_mut().edit(edit.text);
+            self.changed();
+        }
+    }
+
+    fn receive_deletion(&mut self, sender: Address, deletion: &Deletion) {
+        if let Some(message) = self.find_received_message(sender, &deletion.message_id) {
+            message.borrow_mut().delete();
+            self.changed();
+        }
+    }
+
+    /// Marks every received message as read, telling whoever sent each one if the identity sends
+    /// read receipts. Frontends call this once the user has seen the messages.
+    pub fn mark_read(&mut self) {
+        let unread: Vec<Rc<RefCell<Message>>> = self.messages.iter()
+            .filter(|message| {
+                let message = message.borrow();
+                message.was_received() && !message.read && message.hidden == 0
+            })
+            .cloned()
+            .collect();
+        if unread.is_empty() {
+            return;
+        }
+        for message in unread.iter() {
+            message.borrow_mut().read = true;
+        }
+        self.changed();
+
+        if self.connection.borrow().sends_read_receipts() && !self.request {
+            let sender = |message: &Rc<RefCell<Message>>| message.borrow().sender.or(self.recipient);
+            let mut senders: Vec<Address> = vec![];
+            for message in unread.iter() {
+                match sender(message) {
+                    Some(address) if !senders.contains(&address) => senders.push(address),
+                    _ => { }
+                }
+            }
+            for address in senders {
+                let receipt = ReadReceipt {
+                    message_ids: unread.iter()
+                        .filter(|message| sender(message) == Some(address))
+                        .map(|message| message.borrow().id.to_str())
+                        .collect()
+                };
+                self.deliver_to(&[address], &Payload::ReadReceipt(receipt));
+            }
+        }
+    }
+
+    fn receive_read_receipt(&mut self, receipt: &ReadReceipt) {
+        for id in receipt.message_ids.iter() {
+            match self.find_message(id) {
+                Some(ref message) if message.borrow().was_sent() => {
+                    message.borrow_mut().receive_read_receipt();
+                    self.changed();
+                }
+                _ => debug!("Ignoring read receipt for unknown message {}", id)
+            }
+        }
+    }
+}
+
+impl Observable for Conversation {
+    type Observer = ConversationObserver;
+
+    fn observers(&mut self) -> &mut ObserverSet<ConversationObserver> {
+        &mut self.observers
+    }
+}
+
+pub struct ConversationList {
+    connections: Vec<Rc<RefCell<Connection>>>,
+    conversations: Vec<Rc<RefCell<Conversation>>>,
+    selected: Option<usize>,
+    observers: ObserverSet<ConversationListObserver>
+}
+
+impl ConversationList {
+    pub fn new() -> ConversationList {
+        ConversationList {
+            connections: vec![],
+            conversations: vec![],
+            selected: None,
+            observers: ObserverSet::new()
+        }
+    }
+
+    pub fn add_connection(&mut self, connection: Rc<RefCell<Connection>>) {
+        self.connections.push(connection);
+    }
+
+    /// Removes an identity along with every conversation held as it.
+    pub fn remove_connection(&mut self, connection: &Rc<RefCell<Connection>>) {
+        self.connections.retain(|c| !Rc::ptr_eq(c, connection));
+        let mut index = self.conversations.len();
+        while index > 0 {
+            index -= 1;
+            if Rc::ptr_eq(self.conversations[index].borrow().connection(), connection) {
+                self.remove_conversation(index);
+            }
+        }
+    }
+
+    pub fn connections(&self) -> &Vec<Rc<RefCell<Connection>>> {
+        &self.connections
+    }
+
+    /// Changes whether messages sent as `connection` are end-to-end encrypted, and tells each
+    /// conversation held as it.
+    pub fn set_encrypts_messages(&mut self, connection: &Rc<RefCell<Connection>>, encrypts: bool) {
+        connection.borrow_mut().set_encrypts_messages(encrypts);
+        self.announce_encryption(connection);
+    }
+
+    /// Tells each conversation held as `connection` how what's sent in it is protected, after the
+    /// connection's settings have changed.
+    fn announce_encryption(&self, connection: &Rc<RefCell<Connection>>) {
+        for conversation in self.conversations.iter() {
+            let conversation = conversation.borrow();
+            if Rc::ptr_eq(conversation.connection(), connection) {
+                conversation.announce_encryption();
+            }
+        }
+    }
+
+    pub fn add_conversation(&mut self, conversation: Rc<RefCell<Conversation>>) {
+        self.conversations.insert(0, conversation.clone());
+        self.selected = self.selected.map(|index| index + 1);
+        self.observers.notify(move |observer| {
+            observer.borrow_mut().conversation_was_added(conversation.clone());
+        });
+    }
+
+    /// Starts a conversation held as the same identity as the selected one, or as the first
+    /// identity if nothing is selected. Does nothing if there are no identities.
+    pub fn start_new_conversation(&mut self) {
+        let connection = self.selected
+            .and_then(|index| self.get(index))
+            .map(|conversation| conversation.borrow().connection().clone())
+            .or_else(|| self.connections.first().cloned());
+
+        if let Some(connection) = connection {
+            let conversation = Rc::new(RefCell::new(Conversation::new(connection)));
+            self.add_conversation(conversation);
+            self.select_conversation(0);
+        }
+    }
+
+    /// Removes the conversation at `index`, selecting its neighbor if it was selected. As long as
+    /// there is an identity, the list is never left empty; removing the last conversation starts a
+    /// new one.
+    pub fn remove_conversation(&mut self, index: usize) {
+        if index >= self.conversations.len() {
+            return;
+        }
+
+        let conversation = self.conversations.remove(index);
+        conversation.borrow().changed();
+        self.observers.notify(move |observer| {
+            observer.borrow_mut().conversation_was_removed(conversation.clone());
+        });
+
+        match self.selected {
+            Some(selected) if selected > index => {
+                self.selected = Some(selected - 1);
+            }
+            Some(selected) if selected == index => {
+                self.selected = None;
+                if self.conversations.is_empty() {
+                    self.start_new_conversation();
+                } else {
+                    let neighbor = if index < self.conversations.len() { index } else { index - 1 };
+                    self.select_conversation(neighbor);
+                }
+            }
+            _ => { }
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Rc<RefCell<Conversation>>> {
+        self.conversations.get(index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.conversations.len()
+    }
+
+    pub fn selected(&self) -> Option<usize> {
+        self.selected
+    }
+
+    pub fn select_conversation(&mut self, index: usize) {
+        if self.selected == Some(index) {
+            return;
+        }
+        let conversation = self.get(index).unwrap().clone();
+        self.selected = Some(index);
+        self.observers.notify(move |observer| {
+            observer.borrow_mut().conversation_was_selected(conversation.clone());
+        });
+    }
+
+    /// Accepts the message request at `index`, moving it to the top of the list with the other
+    /// conversations.
+    pub fn accept_request(&mut self, index: usize) {
+        match self.get(index) {
+            Some(conversation) if conversation.borrow().is_request() => { }
+            _ => return
+        }
+        let conversation = self.conversations.remove(index);
+        conversation.borrow_mut().request = false;
+        conversation.borrow().changed();
+        self.conversations.insert(0, conversation.clone());
+        self.selected = self.selected.map(|selected| {
+            if selected == index {
+                0
+            } else if selected < index {
+                selected + 1
+            } else {
+                selected
+            }
+        });
+        self.observers.notify(move |observer| {
+            observer.borrow_mut().request_was_accepted(conversation.clone());
+        });
+    }
+
+    /// Blocks the recipient of the conversation at `index`, or every member of a group, and
+    /// removes the conversation.
+    pub fn block_conversation(&mut self, index: usize) {
+        let (connection, recipients) = match self.get(index) {
+            Some(conversation) => {
+                let conversation = conversation.borrow();
+                (conversation.connection().clone(), conversation.recipients())
+            }
+            None => return
+        };
+        for recipient in recipients {
+            connection.borrow_mut().block(recipient);
+        }
+        self.remove_conversation(index);
+    }
+
+    /// Leaves the group at `index`, telling its members, and removes the conversation.
+    pub fn leave_group(&mut self, index: usize) {
+        match self.get(index) {
+            Some(conversation) if conversation.borrow().is_group() => {
+                conversation.borrow_mut().leave();
+            }
+            _ => return
+        }
+        self.remove_conversation(index);
+    }
+
+    /// Joins the group `invite` is for, as a request, or adds whoever it names to the group if
+    /// `sender` is in it already. Invites never take anyone out: members only leave by saying so.
+    fn receive_invite(&mut self, connection: &Rc<RefCell<Connection>>, sender: Address, invite: Invite) {
+        let self_address = connection.borrow().self_address();
+        let mut members = vec![sender];
+        for member in invite.members.iter().filter_map(|member| Address::from_str(member).ok()) {
+            if member != self_address && !members.contains(&member) {
+                members.push(member);
+            }
+        }
+
+        match self.find_group(connection, &invite.group_id) {
+            Some(c) => {
+                if c.borrow().members.contains(&sender) {
+                    let mut joined = c.borrow().members.clone();
+                    let added: Vec<Address> = members.into_iter()
+                        .filter(|member| !joined.contains(member))
+                        .collect();
+                    if !added.is_empty() {
+                        joined.extend(added);
+                        c.borrow_mut().change_members(joined);
+                    }
+                } else {
+                    debug!("Ignoring invite to {} from {}, who isn't in it", invite.group_id,
+                           sender.to_str());
+                }
+            }
+            None => {
+                let c = Rc::new(RefCell::new(Conversation::new(connection.clone())));
+                {
+                    let mut conversation = c.borrow_mut();
+                    conversation.request = true;
+                    conversation.group_id = Some(invite.group_id);
+                    conversation.members = members;
+                    conversation.changed();
+                }
+                self.add_conversation(c);
+            }
+        }
+    }
+
+    /// The group with the id `group_id` held as `connection`.
+    fn find_group(&self, connection: &Rc<RefCell<Connection>>, group_id: &str)
+        -> Option<Rc<RefCell<Conversation>>> {
+        self.conversations.iter().find(|conversation| {
+            let conversation = conversation.borrow();
+            conversation.group_id() == Some(group_id) && Rc::ptr_eq(conversation.connection(), connection)
+        }).cloned()
+    }
+
+    /// Selects the conversation `offset` places away from the selected one, wrapping around.
+    pub fn select_relative(&mut self, offset: isize) {
+        let len = self.conversations.len() as isize;
+        if len == 0 {
+            return;
+        }
+        let current = self.selected.unwrap_or(0) as isize;
+        let index = ((current + offset) % len + len) % len;
+        self.select_conversation(index as usize);
+    }
+}
+
+impl EventObserver for ConversationList {
+    fn handle_event(&mut self, connection: &Rc<RefCell<Connection>>, event: comm::client::Event) {
+        match event {
+            comm::client::Event::ReceivedTextMessage(tm) => {
+                let sender = tm.sender;
+                if connection.borrow().is_blocked(sender) {
+                    debug!("Dropping message {} from blocked {}", tm.id.to_str(), sender.to_str());
+                    return;
+                }
+                let max_message_bytes = connection.borrow().limits().max_message_bytes;
+                if tm.text.len() > max_message_bytes {
+                    warn!("Discarding {} byte message {} from {}", tm.text.len(), tm.id.to_str(),
+                          sender.to_str());
+                    return;
+                }
+                let involves_sender = |conversation: &&Rc<RefCell<Conversation>>| {
+                    let conversation = conversation.borrow();
+                    conversation.recipients().contains(&sender) &&
+                        Rc::ptr_eq(conversation.connection(), connection)
+                };
+                // Everything a stranger sends counts towards the limits, before it's opened, so that
+                // neither key exchanges nor control messages can be used to get around them.
+                // Contacts are counted once their messages are opened, further down.
+                let stranger = !self.conversations.iter().filter(involves_sender)
+                    .any(|conversation| !conversation.borrow().is_request());
+                if stranger && !connection.borrow_mut().admit(sender, true) {
+                    let with_sender = |conversation: &&Rc<RefCell<Conversation>>| {
+                        let conversation = conversation.borrow();
+                        !conversation.is_group() && conversation.recipient() == Some(sender) &&
+                            Rc::ptr_eq(conversation.connection(), connection)
+                    };
+                    match self.conversations.iter().find(with_sender) {
+                        Some(c) => c.borrow_mut().hide_message(tm.id),
+                        None => debug!("Dropping message {} from {} over the limits", tm.id.to_str(),
+                                       sender.to_str())
+                    }
+                    return;
+                }
+
+                let encryption = connection.borrow().encryption(sender);
+                let verification = connection.borrow().verification(sender);
+                let tm = connection.borrow_mut().open(tm);
+                let encryption_changed = connection.borrow().encryption(sender) != encryption;
+                let verification_changed = connection.borrow().verification(sender) != verification;
+                for c in self.conversations.iter().filter(involves_sender) {
+                    if encryption_changed {
+                        c.borrow().announce_encryption();
+                    }
+                    if verification_changed {
+                        c.borrow().announce_verification();
+                    }
+                }
+                let (tm, authenticity) = match tm {
+                    Some(opened) => opened,
+                    None => return
+                };
+
+                // Messages to a group say which, and are known there by the id they were sent with
+                let (group_id, id, payload) = match Payload::decode(&tm.text) {
+                    Payload::Group(group) => match Address::from_str(&group.message_id) {
+                        Ok(id) => (Some(group.group_id), id, Payload::decode(&group.text)),
+                        Err(_) => {
+                            warn!("Ignoring group message with an invalid id from {}", sender.to_str());
+                            return;
+                        }
+                    },
+                    payload => (None, tm.id, payload)
+                };
+                let is_control = match payload {
+                    Payload::Text(_) | Payload::Reply(_) | Payload::Unsupported { .. } |
+                    Payload::Encrypted(_) | Payload::KeyExchange(_) | Payload::Signed(_) |
+                    Payload::Group(_) => false,
+                    _ => true
+                };
+                // Forged messages are still shown, with a warning, but can't change anything
+                if is_control && authenticity == Authenticity::Forged {
+                    warn!("Ignoring forged {:?} from {}", payload, sender.to_str());
+                    return;
+                }
+
+                let is_match = |conversation: &&Rc<RefCell<Conversation>>| {
+                    let conversation = conversation.borrow();
+                    let in_conversation = match group_id {
+                        Some(ref group_id) => conversation.group_id() == Some(group_id.as_str()),
+                        None => !conversation.is_group() && conversation.recipient() == Some(sender)
+                    };
+                    in_conversation && Rc::ptr_eq(conversation.connection(), connection)
+                };
+                if let Some(c) = self.conversations.iter().find(is_match) {
+                    if c.borrow().is_group() && !c.borrow().members.contains(&sender) {
+                        debug!("Ignoring message {} from {}, who isn't in the group", id.to_str(),
+                               sender.to_str());
+                        return;
+                    }
+                }
+                // Only what a contact sends that would be shown counts, so that typing, receipts
+                // and the sessions with them are never held back
+                if !stranger && !is_control && !connection.borrow_mut().admit(sender, false) {
+                    match self.conversations.iter().find(is_match) {
+                        Some(c) => c.borrow_mut().hide_message(id),
+                        None => debug!("Dropping message {} from {} over the limits", id.to_str(),
+                                       sender.to_str())
+                    }
+                    return;
+                }
+                let mut message = match payload {
+                    Payload::Text(text) => Message::received(id, text),
+                    Payload::Reply(reply) => {
+                        let mut message = Message::received(id, reply.text);
+                        message.quote = Some(reply.in_reply_to);
+                        message
+                    }
+                    Payload::Unsupported { .. } => Message::unsupported(id),
+                    // Sessions open what they can, so this couldn't be decrypted
+                    Payload::Encrypted(_) | Payload::KeyExchange(_) => Message::unsupported(id),
+                    // Signed or sent to a group twice over
+                    Payload::Signed(_) | Payload::Group(_) => Message::unsupported(id),
+                    Payload::Reaction(reaction) => {
+                        if let Some(c) = self.conversations.iter().find(is_match) {
+                            c.borrow_mut().receive_reaction(sender, &reaction);
+                        }
+                        return;
+                    }
+                    Payload::Edit(edit) => {
+                        if let Some(c) = self.conversations.iter().find(is_match) {
+                            c.borrow_mut().receive_edit(sender, edit);
+                        }
+                        return;
+                    }
+                    Payload::Deletion(deletion) => {
+                        if let Some(c) = self.conversations.iter().find(is_match) {
+                            c.borrow_mut().receive_deletion(sender, &deletion);
+                        }
+                        return;
+                    }
+                    Payload::Typing(typing) => {
+                        match self.conversations.iter().find(is_match) {
+                            Some(c) if !c.borrow().is_group() => {
+                                c.borrow_mut().set_recipient_typing(!typing.stopped);
+                            }
+                            _ => { }
+                        }
+                        return;
+                    }
+                    Payload::ReadReceipt(receipt) => {
+                        if let Some(c) = self.conversations.iter().find(is_match) {
+                            c.borrow_mut().receive_read_receipt(&receipt);
+                        }
+                        return;
+                    }
+                    Payload::Invite(invite) => {
+                        self.receive_invite(connection, sender, invite);
+                        return;
+                    }
+                    Payload::Leave(leave) => {
+                        if let Some(c) = self.find_group(connection, &leave.group_id) {
+                            c.borrow_mut().receive_leave(sender);
+                        }
+                        return;
+                    }
+                };
+                message.authentic = authenticity == Authenticity::Authentic;
+                message.sender = Some(sender);
+                let message = Rc::new(RefCell::new(message));
+                let existing_conversation = self.conversations.iter().any(|c| is_match(&c));
+
+                if existing_conversation {
+                    let c = self.conversations.iter().find(is_match).unwrap();
+                    c.borrow_mut().receive_message(message);
+                } else {
+                    let c = Rc::new(RefCell::new(Conversation::new(connection.clone())));
+                    c.borrow_mut().request = true;
+                    match group_id.clone() {
+                        // Members are invited before anything is said, but the invite may still be
+                        // on its way
+                        Some(group_id) => {
+                            c.borrow_mut().group_id = Some(group_id);
+                            c.borrow_mut().members = vec![sender];
+                        }
+                        None => c.borrow_mut().set_recipient(Some(sender))
+                    }
+                    self.add_conversation(c.clone());
+                    c.borrow_mut().receive_message(message);
+                }
+            }
+
+            comm::client::Event::ReceivedMessageAcknowledgement(ack) => {
+                for conversation in self.conversations.iter() {
+                    let conversation = conversation.borrow();
+                    for message in conversation.messages.iter() {
+                        if message.borrow().id == ack.message_id {
+                            message.update(|message| message.receive_acknowledgement());
+                            conversation.changed();
+                        } else if message.borrow().was_delivered_as(ack.message_id) {
+                            let id = ack.message_id;
+                            message.update(|message| message.receive_member_acknowledgement(id));
+                            conversation.changed();
+                        }
+                    }
+                }
+            }
+
+            // Starting applies the profile's settings
+            comm::client::Event::Started => self.announce_encryption(connection),
+
+            _ => { }
+        }
+    }
+}
+
+impl Observable for ConversationList {
+    type Observer = ConversationListObserver;
+
+    fn observers(&mut self) -> &mut ObserverSet<ConversationListObserver> {
+        &mut self.observers
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredMessage {
+    id: String,
+    text: String,
+    sent: bool,
+    acknowledged: bool,
+    #[serde(default = "supported", skip_serializing_if = "is_supported")]
+    supported: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    unauthentic: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    quote: Option<Quote>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    reactions: Vec<StoredReaction>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    history: Vec<String>,
+    #[serde(default)]
+    deleted: bool,
+    #[serde(default)]
+    read: bool,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    hidden: usize,
+    #[serde(default, skip_serializing_if = "is_false")]
+    failed: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sender: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    deliveries: Vec<StoredDelivery>
+}
+
+fn supported() -> bool {
+    true
+}
+
+fn is_supported(supported: &bool) -> bool {
+    *supported
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+fn is_zero(value: &usize) -> bool {
+    *value == 0
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredReaction {
+    reactor: String,
+    emoji: String
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredDelivery {
+    member: String,
+    id: String,
+    #[serde(default)]
+    acknowledged: bool
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredGroup {
+    id: String,
+    members: Vec<String>
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredConversation {
+    #[serde(default)]
+    recipient: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    group: Option<StoredGroup>,
+    messages: Vec<StoredMessage>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    request: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    unencrypted: bool
+}
+
+/// Persists the conversations held as one identity so they survive restarts and profile switches,
+/// along with its encrypted sessions, which are kept beside them in a file only the user can read,
+/// and the addresses it has blocked.
+pub struct MessageStore {
+    path: path::PathBuf,
+    sessions_path: path::PathBuf,
+    blocklist_path: path::PathBuf
+}
+
+impl MessageStore {
+    pub fn new(path: path::PathBuf) -> MessageStore {
+        MessageStore {
+            sessions_path: path.with_extension("sessions.yml"),
+            blocklist_path: path.with_extension("blocked.yml"),
+            path: path
+        }
+    }
+
+    pub fn load(&self, connection: &Rc<RefCell<Connection>>, conversations: &mut ConversationList) {
+        debug!("Loading messages from {:?}", self.path);
+        let stored: Vec<StoredConversation> = fs::File::open(&self.path).ok()
+            .and_then(|file| serde_yaml::from_reader(file).ok())
+            .unwrap_or_else(|| vec![]);
+
+        // Stored newest first, and each added conversation goes to the top of the list
+        for stored_conversation in stored.into_iter().rev() {
+            let mut conversation = Conversation::new(connection.clone());
+            match stored_conversation.group {
+                Some(group) => {
+                    conversation.group_id = Some(group.id);
+                    conversation.members = group.members.iter()
+                        .filter_map(|member| Address::from_str(member).ok())
+                        .collect();
+                }
+                None => match Address::from_str(&stored_conversation.recipient) {
+                    Ok(recipient) => conversation.recipient = Some(recipient),
+                    Err(_) => {
+                        warn!("Skipping stored conversation with invalid recipient");
+                        continue;
+                    }
+                }
+            }
+            conversation.request = stored_conversation.request;
+            conversation.sends_unencrypted = stored_conversation.unencrypted;
+            for stored_message in stored_conversation.messages {
+                if let Ok(id) = Address::from_str(&stored_message.id) {
+                    let direction = if stored_message.sent {
+                        MessageDirection::Sent
+                    } else {
+                        MessageDirection::Received
+                    };
+                    let mut message = Message::new(id, stored_message.text, direction);
+                    message.acknowledged = stored_message.acknowledged;
+                    message.supported = stored_message.supported;
+                    message.authentic = !stored_message.unauthentic;
+                    message.quote = stored_message.quote;
+                    message.history = stored_message.history;
+                    message.deleted = stored_message.deleted;
+                    message.read = stored_message.read;
+                    message.hidden = stored_message.hidden;
+                    message.failed = stored_message.failed;
+                    message.sender = stored_message.sender.and_then(|sender| Address::from_str(&sender).ok());
+                    message.deliveries = stored_message.deliveries.into_iter()
+                        .filter_map(|delivery| {
+                            match (Address::from_str(&delivery.member), Address::from_str(&delivery.id)) {
+                                (Ok(member), Ok(id)) => Some(Delivery {
+                                    member: member,
+                                    id: id,
+                                    acknowledged: delivery.acknowledged
+                                }),
+                                _ => None
+                            }
+                        })
+                        .collect();
+                    message.reactions = stored_message.reactions.into_iter()
+                        .filter_map(|reaction| {
+                            Address::from_str(&reaction.reactor).ok().map(|r| (r, reaction.emoji))
+                        })
+                        .collect();
+                    conversation.messages.push(Rc::new(RefCell::new(message)));
+                }
+            }
+            conversations.add_conversation(Rc::new(RefCell::new(conversation)));
+        }
+
+        let sessions: Option<Sessions> = fs::File::open(&self.sessions_path).ok()
+            .and_then(|file| serde_yaml::from_reader(file).ok());
+        if let Some(sessions) = sessions {
+            connection.borrow_mut().sessions.restore(sessions);
+        }
+        connection.borrow_mut().sessions_path = Some(self.sessions_path.clone());
+
+        let blocked: Vec<String> = fs::File::open(&self.blocklist_path).ok()
+            .and_then(|file| serde_yaml::from_reader(file).ok())
+            .unwrap_or_else(|| vec![]);
+        let mut connection = connection.borrow_mut();
+        connection.blocked = blocked.iter().filter_map(|address| Address::from_str(address).ok()).collect();
+        connection.announce_blocklist();
+    }
+
+    pub fn save(&self, connection: &Rc<RefCell<Connection>>, conversations: &ConversationList) -> io::Result<()> {
+        use std::io::prelude::*;
+        let stored: Vec<StoredConversation> = conversations.conversations.iter().filter_map(|conversation| {
+            let conversation = conversation.borrow();
+            if !Rc::ptr_eq(conversation.connection(), connection) {
+                return None;
+            }
+            let group = conversation.group_id.clone().map(|id| StoredGroup {
+                id: id,
+                members: conversation.members.iter().map(|member| member.to_str()).collect()
+            });
+            // Groups are kept from when they're joined, before anything has been said in them
+            let joined = group.is_some() && conversation.is_request();
+            let recipient = conversation.recipient().map(|recipient| recipient.to_str());
+            match (recipient, group) {
+                (None, None) => None,
+                (_, _) if !conversation.has_started() && !joined => None,
+                (recipient, group) => Some(StoredConversation {
+                    recipient: recipient.unwrap_or_default(),
+                    group: group,
+                    messages: conversation.messages().iter().map(|message| {
+                        let message = message.borrow();
+                        StoredMessage {
+                            id: message.id.to_str(),
+                            text: message.text.clone(),
+                            sent: message.was_sent(),
+                            acknowledged: message.acknowledged(),
+                            supported: message.is_supported(),
+                            unauthentic: !message.is_authentic(),
+                            quote: message.quote.clone(),
+                            history: message.history.clone(),
+                            deleted: message.deleted,
+                            read: message.read,
+                            hidden: message.hidden,
+                            failed: message.failed,
+                            sender: message.sender.map(|sender| sender.to_str()),
+                            deliveries: message.deliveries.iter().map(|delivery| StoredDelivery {
+                                member: delivery.member.to_str(),
+                                id: delivery.id.to_str(),
+                                acknowledged: delivery.acknowledged
+                            }).collect(),
+                            reactions: message.reactions.iter().map(|&(reactor, ref emoji)| {
+                                StoredReaction { reactor: reactor.to_str(), emoji: emoji.clone() }
+                            }).collect()
+                        }
+                    }).collect(),
+                    request: conversation.is_request(),
+                    unencrypted: conversation.sends_unencrypted()
+                })
+            }
+        }).collect();
+
+        match serde_yaml::to_string(&stored) {
+            Ok(yaml) => {
+                if let Some(dir) = self.path.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                fs::File::create(&self.path)
+                    .and_then(|mut file| file.write_all(&yaml.into_bytes()))?;
+            }
+
+            Err(err) => return Err(Error::new(ErrorKind::Other, err))
+        }
+
+        save_sessions(&self.sessions_path, connection.borrow().sessions())?;
+
+        let blocked: Vec<String> = connection.borrow().blocked().iter()
+            .map(|address| address.to_str())
+            .collect();
+        match serde_yaml::to_string(&blocked) {
+            Ok(yaml) => fs::File::create(&self.blocklist_path)
+                .and_then(|mut file| file.write_all(&yaml.into_bytes())),
+            Err(err) => Err(Error::new(ErrorKind::Other, err))
+        }
+    }
+}
+
+/// Writes `sessions` to `path`, where only the user can read them.
+fn save_sessions(path: &path::Path, sessions: &Sessions) -> io::Result<()> {
+    use std::io::prelude::*;
+    let yaml = serde_yaml::to_string(sessions).map_err(|err| Error::new(ErrorKind::Other, err))?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    private_file(path).and_then(|mut file| file.write_all(&yaml.into_bytes()))
+}
+
+#[cfg(unix)]
+fn private_file(path: &path::Path) -> io::Result<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)
+}
+
+#[cfg(not(unix))]
+fn private_file(path: &path::Path) -> io::Result<fs::File> {
+    fs::File::create(path)
+}
+
+struct Identity {
+    connection: Rc<RefCell<Connection>>,
+    store: MessageStore,
+    observers: Vec<(EventKind, Rc<RefCell<EventObserver>>)>
+}
+
+/// Routes each client event to the observers registered for its kind. Connection events go to
+/// the connection of the identity that emitted them, and message events to the conversation list.
+pub struct EventHandler {
+    conversations: Rc<RefCell<ConversationList>>,
+    identities: HashMap<String, Identity>,
+    observers: Vec<(EventKind, Rc<RefCell<EventObserver>>)>
+}
+
+impl EventHandler {
+    pub fn new(conversations: Rc<RefCell<ConversationList>>) -> EventHandler {
+        let mut event_handler = EventHandler {
+            conversations: conversations.clone(),
+            identities: HashMap::new(),
+            observers: vec![]
+        };
+        event_handler.register_observer(EventKind::Message, conversations.clone());
+        event_handler.register_observer(EventKind::Connection, conversations);
+        event_handler
+    }
+
+    /// Registers `observer` for events of `kind` from every identity.
+    pub fn register_observer(&mut self, kind: EventKind, observer: Rc<RefCell<EventObserver>>) {
+        self.observers.push((kind, observer));
+    }
+
+    pub fn deregister_observer(&mut self, observer: &Rc<RefCell<EventObserver>>) {
+        self.observers.retain(|&(_, ref o)| !Rc::ptr_eq(o, observer));
+    }
+
+    /// Loads the identity's stored conversations and starts routing its events.
+    pub fn add_identity(&mut self, connection: Rc<RefCell<Connection>>, store: MessageStore) {
+        let name = String::from(connection.borrow().name());
+        self.conversations.update(|conversations| {
+            conversations.add_connection(connection.clone());
+            store.load(&connection, conversations);
+        });
+        let connection_observer: Rc<RefCell<EventObserver>> = connection.clone();
+        self.identities.insert(name, Identity {
+            connection: connection,
+            store: store,
+            observers: vec![(EventKind::Connection, connection_observer)]
+        });
+    }
+
+    /// Saves the identity's conversations and removes them from the list.
+    pub fn remove_identity(&mut self, name: &str) {
+        if let Some(identity) = self.identities.remove(name) {
+            self.save_identity(&identity);
+            self.conversations.update(|conversations| conversations.remove_connection(&identity.connection));
+        }
+    }
+
+    pub fn handle_event(&self, name: &str, event: comm::client::Event) {
+        debug!("Received event for {}: {:?}", name, event.clone());
+        let identity = match self.identities.get(name) {
+            Some(identity) => identity,
+            None => {
+                debug!("Dropping event for closed identity {}", name);
+                return;
+            }
+        };
+
+        let kind = EventKind::of(&event);
+        for &(observer_kind, ref observer) in identity.observers.iter().chain(self.observers.iter()) {
+            if observer_kind == kind {
+                observer.update(|observer| observer.handle_event(&identity.connection, event.clone()));
+            }
+        }
+
+        self.save_changes();
+    }
+
+    /// Saves each identity whose conversations, sessions or blocklist have changed since it was
+    /// last saved. Frontends call this after whatever the user does, such as sending.
+    pub fn save_changes(&self) {
+        for identity in self.identities.values() {
+            if identity.connection.borrow().take_changes() {
+                self.save_identity(identity);
+            }
+        }
+    }
+
+    pub fn save(&self) {
+        for identity in self.identities.values() {
+            self.save_identity(identity);
+        }
+    }
+
+    fn save_identity(&self, identity: &Identity) {
+        if let Err(err) = identity.store.save(&identity.connection, &self.conversations.borrow()) {
+            warn!("Failed to save messages: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+    use std::net::UdpSocket;
+    use std::sync::mpsc;
+    use std::time::{Duration, Instant};
+    use std::{env, fs, path, process, thread};
+
+    use comm;
+    use comm::address::Address;
+    use comm::client::{Event, Task};
+    use super::{subscribe, CommTransport, Configuration, Connection, ConnectionObserver,
+        ConnectionState, Conversation, ConversationList, ConversationObserver, EncryptionState,
+        EventHandler, EventObserver, Limits, Message, MessageObserver, MessageStore, Observable,
+        Profile, Update, Verification};
+    use encryption::{KeyPair, Sessions, SigningKey};
+    use payload::{Deletion, Edit, GroupMessage, Invite, Leave, Payload, Quote, Reaction, ReadReceipt, Reply,
+        Typing};
+    use testing::{profile, started_connection, FakeTransport};
+
+    fn delivered_text(task: &Task) -> comm::client::messages::TextMessage {
+        match *task {
+            Task::ScheduleMessageDelivery(_, ref text_message) => text_message.clone(),
+            _ => panic!("Expected a message delivery")
+        }
+    }
+
+    fn temp_dir(name: &str) -> path::PathBuf {
+        env::temp_dir().join(format!("comm-gtk-{}-{}", name, process::id()))
+    }
+
+    struct CountingObserver {
+        notified: Rc<Cell<usize>>
+    }
+
+    impl MessageObserver for CountingObserver {
+        fn did_receieve_acknowledgement(&self) {
+            self.notified.set(self.notified.get() + 1);
+        }
+
+        fn reactions_were_changed(&self, _: Vec<(String, usize)>) { }
+        fn text_was_edited(&self, _: String) { }
+        fn was_deleted(&self) { }
+
+        fn did_receive_read_receipt(&self) {
+            self.notified.set(self.notified.get() + 1);
+        }
+
+        fn hidden_count_was_changed(&self, _: usize) { }
+        fn acknowledgements_were_changed(&self, _: usize, _: usize) { }
+        fn did_fail(&self) { }
+    }
+
+    fn message() -> Rc<RefCell<Message>> {
+        let id = Address::for_content("message");
+        Rc::new(RefCell::new(Message::sent(id, String::from("hello"))))
+    }
+
+    fn observer(notified: &Rc<Cell<usize>>) -> Rc<RefCell<CountingObserver>> {
+        Rc::new(RefCell::new(CountingObserver { notified: notified.clone() }))
+    }
+
+    #[test]
+    fn registering_does_not_keep_observer_alive() {
+        let message = message();
+        let notified = Rc::new(Cell::new(0));
+        let observer = observer(&notified);
+        message.borrow_mut().register_observer(observer.clone());
+
+        let weak = Rc::downgrade(&observer);
+        drop(observer);
+
+        assert!(weak.upgrade().is_none());
+        assert_eq!(message.borrow_mut().observers().len(), 0);
+    }
+
+    #[test]
+    fn dropped_observers_are_not_notified_and_are_pruned() {
+        let message = message();
+        let notified = Rc::new(Cell::new(0));
+        let alive = observer(&notified);
+        let dead = observer(&notified);
+        message.borrow_mut().register_observer(alive.clone());
+        message.borrow_mut().register_observer(dead.clone());
+        drop(dead);
+
+        message.update(|message| message.receive_acknowledgement());
+
+        assert_eq!(notified.get(), 1);
+        assert_eq!(message.borrow_mut().observers().observers.borrow().len(), 1);
+    }
+
+    #[test]
+    fn dropping_subscription_deregisters() {
+        let message = message();
+        let notified = Rc::new(Cell::new(0));
+        let observer = observer(&notified);
+        let subscription = subscribe(&message, observer.clone());
+        assert_eq!(message.borrow_mut().observers().len(), 1);
+
+        drop(subscription);
+
+        assert_eq!(message.borrow_mut().observers().len(), 0);
+        message.update(|message| message.receive_acknowledgement());
+        assert_eq!(notified.get(), 0);
+    }
+
+    #[test]
+    fn changes_made_outside_of_an_update_are_delivered_when_idle() {
+        let message = message();
+        let notified = Rc::new(Cell::new(0));
+        let observer = observer(&notified);
+        message.borrow_mut().register_observer(observer.clone());
+
+        message.borrow_mut().receive_acknowledgement();
+        assert_eq!(notified.get(), 0);
+
+        deliver_stray_notifications();
+        assert_eq!(notified.get(), 1);
+    }
+
+    #[test]
+    fn subscription_outliving_model_is_harmless() {
+        let message = message();
+        let notified = Rc::new(Cell::new(0));
+        let subscription = subscribe(&message, observer(&notified));
+
+        drop(message);
+        drop(subscription);
+    }
+
+    /// Logs every notification, and reads the conversation back while being notified.
+    struct LoggingObserver {
+        name: &'static str,
+        conversation: Rc<RefCell<Conversation>>,
+        log: Rc<RefCell<Vec<String>>>
+    }
+
+    impl ConversationObserver for LoggingObserver {
+        fn identity_was_changed(&self, _: String) { }
+        fn recipient_was_changed(&self, _: Option<Address>) { }
+
+        fn pending_message_was_changed(&self, text: String) {
+            let current = String::from(self.conversation.borrow().pending_message());
+            self.log.borrow_mut().push(format!("{}: pending {:?}, now {:?}", self.name, text, current));
+        }
+
+        fn reply_was_changed(&self, _: Option<Quote>) { }
+        fn recipient_typing_was_changed(&self, _: bool) { }
+        fn encryption_was_changed(&self, _: EncryptionState) { }
+        fn verification_was_changed(&self, _: Verification) { }
+        fn members_were_changed(&self, _: Vec<Address>) { }
+
+        fn did_receive_message(&mut self, message: Rc<RefCell<Message>>) {
+            let count = self.conversation.borrow().messages().len();
+            self.log.borrow_mut().push(
+                format!("{}: received {:?} of {}", self.name, message.borrow().text(), count));
+        }
+
+        fn did_send_message(&mut self, _: Rc<RefCell<Message>>) { }
+    }
+
+    /// Replies to every received message by changing the pending message.
+    struct ReplyingObserver {
+        conversation: Rc<RefCell<Conversation>>
+    }
+
+    impl ConversationObserver for ReplyingObserver {
+        fn identity_was_changed(&self, _: String) { }
+        fn recipient_was_changed(&self, _: Option<Address>) { }
+        fn pending_message_was_changed(&self, _: String) { }
+        fn reply_was_changed(&self, _: Option<Quote>) { }
+        fn recipient_typing_was_changed(&self, _: bool) { }
+        fn encryption_was_changed(&self, _: EncryptionState) { }
+        fn verification_was_changed(&self, _: Verification) { }
+        fn members_were_changed(&self, _: Vec<Address>) { }
+
+        fn did_receive_message(&mut self, message: Rc<RefCell<Message>>) {
+            let reply = format!("re: {}", message.borrow().text());
+            self.conversation.update(|conversation| conversation.set_pending_message(reply));
+        }
+
+        fn did_send_message(&mut self, _: Rc<RefCell<Message>>) { }
+    }
+
+    fn conversation() -> Rc<RefCell<Conversation>> {
+        let (connection, _) = Connection::new("test");
+        Rc::new(RefCell::new(Conversation::new(connection)))
+    }
+
+    fn received(text: &str) -> Rc<RefCell<Message>> {
+        Rc::new(RefCell::new(Message::received(Address::for_content(text), String::from(text))))
+    }
+
+    fn logging(name: &'static str, conversation: &Rc<RefCell<Conversation>>,
+               log: &Rc<RefCell<Vec<String>>>) -> Rc<RefCell<LoggingObserver>> {
+        Rc::new(RefCell::new(LoggingObserver {
+            name: name,
+            conversation: conversation.clone(),
+            log: log.clone()
+        }))
+    }
+
+    #[test]
+    fn observers_can_read_the_model_they_observe() {
+        let conversation = conversation();
+        let log = Rc::new(RefCell::new(vec![]));
+        let observer = logging("a", &conversation, &log);
+        conversation.borrow_mut().register_observer(observer.clone());
+
+        conversation.update(|conversation| conversation.receive_message(received("hi")));
+
+        assert_eq!(*log.borrow(), vec![String::from("a: received \"hi\" of 1")]);
+    }
+
+    #[test]
+    fn observers_are_notified_in_registration_order() {
+        let conversation = conversation();
+        let log = Rc::new(RefCell::new(vec![]));
+        let names = ["a", "b", "c", "d", "e", "f", "g", "h"];
+        let observers: Vec<_> = names.iter().map(|name| logging(*name, &conversation, &log)).collect();
+        for observer in observers.iter() {
+            conversation.borrow_mut().register_observer(observer.clone());
+        }
+
+        conversation.update(|conversation| conversation.receive_message(received("hi")));
+
+        let expected: Vec<String> = names.iter()
+            .map(|name| format!("{}: received \"hi\" of 1", name))
+            .collect();
+        assert_eq!(*log.borrow(), expected);
+    }
+
+    #[test]
+    fn nested_notifications_follow_the_one_being_delivered() {
+        let conversation = conversation();
+        let log = Rc::new(RefCell::new(vec![]));
+        let first = logging("a", &conversation, &log);
+        let replying = Rc::new(RefCell::new(ReplyingObserver { conversation: conversation.clone() }));
+        let last = logging("b", &conversation, &log);
+        conversation.borrow_mut().register_observer(first.clone());
+        conversation.borrow_mut().register_observer(replying.clone());
+        conversation.borrow_mut().register_observer(last.clone());
+
+        conversation.update(|conversation| {
+            conversation.receive_message(received("one"));
+            conversation.receive_message(received("two"));
+        });
+
+        // Both messages had arrived by the time observers ran, and every observer saw the first
+        // message before any saw the replies it caused
+        assert_eq!(*log.borrow(), vec![
+            String::from("a: received \"one\" of 2"),
+            String::from("b: received \"one\" of 2"),
+            String::from("a: received \"two\" of 2"),
+            String::from("b: received \"two\" of 2"),
+            String::from("a: pending \"re: one\", now \"re: two\""),
+            String::from("b: pending \"re: one\", now \"re: two\""),
+            String::from("a: pending \"re: two\", now \"re: two\""),
+            String::from("b: pending \"re: two\", now \"re: two\"")
+        ]);
+    }
+
+    #[test]
+    fn nested_updates_deliver_once_the_outermost_finishes() {
+        let outer = conversation();
+        let inner = conversation();
+        let log = Rc::new(RefCell::new(vec![]));
+        let observer = logging("a", &outer, &log);
+        inner.borrow_mut().register_observer(observer.clone());
+
+        outer.update(|outer| {
+            // The observer reads `outer`, which is still borrowed here
+            inner.update(|inner| inner.set_pending_message(String::from("inner")));
+            outer.set_pending_message(String::from("outer"));
+            assert!(log.borrow().is_empty());
+        });
+
+        assert_eq!(*log.borrow(), vec![String::from("a: pending \"inner\", now \"outer\"")]);
+    }
+
+    #[test]
+    fn observers_freed_during_delivery_are_skipped() {
+        struct Dropping {
+            victim: RefCell<Option<Rc<RefCell<LoggingObserver>>>>
+        }
+
+        impl ConversationObserver for Dropping {
+            fn identity_was_changed(&self, _: String) { }
+            fn recipient_was_changed(&self, _: Option<Address>) { }
+            fn pending_message_was_changed(&self, _: String) {
+                self.victim.borrow_mut().take();
+            }
+
+            fn reply_was_changed(&self, _: Option<Quote>) { }
+            fn recipient_typing_was_changed(&self, _: bool) { }
+            fn encryption_was_changed(&self, _: EncryptionState) { }
+            fn verification_was_changed(&self, _: Verification) { }
+            fn members_were_changed(&self, _: Vec<Address>) { }
+            fn did_receive_message(&mut self, _: Rc<RefCell<Message>>) { }
+            fn did_send_message(&mut self, _: Rc<RefCell<Message>>) { }
+        }
+
+        let conversation = conversation();
+        let log = Rc::new(RefCell::new(vec![]));
+        let victim = logging("victim", &conversation, &log);
+        let dropping = Rc::new(RefCell::new(Dropping { victim: RefCell::new(Some(victim.clone())) }));
+        conversation.borrow_mut().register_observer(dropping.clone());
+        conversation.borrow_mut().register_observer(victim);
+
+        conversation.update(|conversation| conversation.set_pending_message(String::from("hi")));
+
+        assert!(log.borrow().is_empty());
+        assert_eq!(conversation.borrow_mut().observers().len(), 1);
+    }
+
+    #[test]
+    fn sending_schedules_delivery_and_records_the_message() {
+        let (connection, transport) = started_connection("alice");
+        connection.borrow_mut().set_encrypts_messages(false);
+        connection.borrow_mut().set_signs_messages(false);
+        connection.borrow_mut().set_sends_typing_indicators(false);
+        let recipient = Address::for_content("bob");
+        let conversation = Rc::new(RefCell::new(Conversation::new(connection)));
+
+        conversation.update(|conversation| {
+            conversation.set_recipient(Some(recipient));
+            conversation.set_pending_message(String::from("hello"));
+            conversation.send_message();
+        });
+
+        let tasks = transport.tasks();
+        assert_eq!(tasks.len(), 1);
+        match tasks[0] {
+            Task::ScheduleMessageDelivery(address, ref text_message) => {
+                assert!(address == recipient);
+                assert!(text_message.sender == Address::for_content("alice"));
+                assert_eq!(text_message.text, "hello");
+            }
+            _ => panic!("Expected a message delivery")
+        }
+
+        let conversation = conversation.borrow();
+        assert_eq!(conversation.pending_message(), "");
+        assert_eq!(conversation.messages().len(), 1);
+        let message = conversation.messages()[0].borrow();
+        assert!(message.was_sent());
+        assert!(!message.acknowledged());
+        assert_eq!(message.text(), "hello");
+    }
+
+    #[test]
+    fn sending_without_a_recipient_does_nothing() {
+        let (connection, transport) = started_connection("alice");
+        connection.borrow_mut().set_encrypts_messages(false);
+        connection.borrow_mut().set_signs_messages(false);
+        let conversation = Rc::new(RefCell::new(Conversation::new(connection)));
+
+        conversation.update(|conversation| {
+            conversation.set_pending_message(String::from("hello"));
+            conversation.send_message();
+        });
+
+        assert!(transport.tasks().is_empty());
+        assert_eq!(conversation.borrow().pending_message(), "hello");
+        assert!(!conversation.borrow().has_started());
+    }
+
+    #[test]
+    fn acknowledgements_mark_only_the_matching_message() {
+        let (connection, transport) = started_connection("alice");
+        connection.borrow_mut().set_encrypts_messages(false);
+        connection.borrow_mut().set_signs_messages(false);
+        connection.borrow_mut().set_sends_typing_indicators(false);
+        let conversations = Rc::new(RefCell::new(ConversationList::new()));
+        conversations.update(|conversations| {
+            conversations.add_connection(connection.clone());
+            conversations.start_new_conversation();
+        });
+        let conversation = conversations.borrow().get(0).unwrap().clone();
+
+        conversation.update(|conversation| {
+            conversation.set_recipient(Some(Address::for_content("bob")));
+            conversation.set_pending_message(String::from("one"));
+            conversation.send_message();
+            conversation.set_pending_message(String::from("two"));
+            conversation.send_message();
+        });
+        let first = delivered_text(&transport.tasks()[0]);
+
+        let ack = comm::client::messages::MessageAcknowledgement { message_id: first.id };
+        conversations.update(|conversations| {
+            conversations.handle_event(&connection, Event::ReceivedMessageAcknowledgement(ack));
+        });
+
+        let conversation = conversation.borrow();
+        assert!(conversation.messages()[0].borrow().acknowledged());
+        assert!(!conversation.messages()[1].borrow().acknowledged());
+    }
+
+    #[test]
+    fn received_messages_are_grouped_by_sender() {
+        let (connection, _) = started_connection("alice");
+        let conversations = Rc::new(RefCell::new(ConversationList::new()));
+        conversations.update(|conversations| conversations.add_connection(connection.clone()));
+
+        let bob = Address::for_content("bob");
+        let carol = Address::for_content("carol");
+        for &(sender, text) in [(bob, "hi"), (carol, "hey"), (bob, "again")].iter() {
+            let text_message = comm::client::messages::TextMessage::new(sender, String::from(text));
+            conversations.update(|conversations| {
+                conversations.handle_event(&connection, Event::ReceivedTextMessage(text_message));
+            });
+        }
+
+        let conversations = conversations.borrow();
+        assert_eq!(conversations.len(), 2);
+        let carols = conversations.get(0).unwrap().borrow();
+        let bobs = conversations.get(1).unwrap().borrow();
+        assert!(carols.recipient() == Some(carol));
+        assert!(bobs.recipient() == Some(bob));
+        let texts: Vec<String> = bobs.messages().iter()
+            .map(|message| String::from(message.borrow().text()))
+            .collect();
+        assert_eq!(texts, vec!["hi", "again"]);
+    }
+
+    #[test]
+    fn reactions_are_aggregated_on_the_message_they_refer_to() {
+        let (connection, transport) = started_connection("alice");
+        connection.borrow_mut().set_encrypts_messages(false);
+        connection.borrow_mut().set_signs_messages(false);
+        let conversations = Rc::new(RefCell::new(ConversationList::new()));
+        conversations.update(|conversations| conversations.add_connection(connection.clone()));
+
+        let bob = Address::for_content("bob");
+        let hi = comm::client::messages::TextMessage::new(bob, String::from("hi"));
+        let hi_id = hi.id.to_str();
+        conversations.update(|conversations| {
+            conversations.handle_event(&connection, Event::ReceivedTextMessage(hi));
+        });
+        let conversation = conversations.borrow().get(0).unwrap().clone();
+        let message = conversation.borrow().messages()[0].clone();
+
+        let react = |sender: Address, emoji: &str, removed: bool| {
+            let payload = Payload::Reaction(Reaction {
+                message_id: hi_id.clone(),
+                emoji: String::from(emoji),
+                removed: removed
+            });
+            let text_message = comm::client::messages::TextMessage::new(sender, payload.encode());
+            conversations.update(|conversations| {
+                conversations.handle_event(&connection, Event::ReceivedTextMessage(text_message));
+            });
+        };
+        react(bob, "👍", false);
+        react(bob, "🎉", false);
+        react(bob, "🎉", true);
+        conversation.update(|conversation| conversation.react(&message, "👍"));
+
+        assert_eq!(conversation.borrow().messages().len(), 1);
+        assert_eq!(message.borrow().reactions(), vec![(String::from("👍"), 2)]);
+        match Payload::decode(&delivered_text(&transport.tasks()[0]).text) {
+            Payload::Reaction(reaction) => {
+                assert_eq!(reaction.message_id, hi_id);
+                assert!(!reaction.removed);
+            }
+            _ => panic!("Expected a reaction")
+        }
+
+        conversation.update(|conversation| conversation.react(&message, "👍"));
+        assert_eq!(message.borrow().reactions(), vec![(String::from("👍"), 1)]);
+    }
+
+    #[test]
+    fn replies_quote_the_message_they_reply_to() {
+        let (connection, transport) = started_connection("alice");
+        connection.borrow_mut().set_encrypts_messages(false);
+        connection.borrow_mut().set_signs_messages(false);
+        connection.borrow_mut().set_sends_typing_indicators(false);
+        let conversations = Rc::new(RefCell::new(ConversationList::new()));
+        conversations.update(|conversations| conversations.add_connection(connection.clone()));
+
+        let bob = Address::for_content("bob");
+        let question = comm::client::messages::TextMessage::new(bob, String::from("lunch?"));
+        let question_id = question.id.to_str();
+        conversations.update(|conversations| {
+            conversations.handle_event(&connection, Event::ReceivedTextMessage(question));
+        });
+        let conversation = conversations.borrow().get(0).unwrap().clone();
+        let original = conversation.borrow().messages()[0].clone();
+
+        conversation.update(|conversation| {
+            conversation.set_reply_to(Some(&original));
+            conversation.set_pending_message(String::from("yes"));
+            conversation.send_message();
+        });
+
+        let quote = Quote { message_id: question_id.clone(), excerpt: String::from("lunch?") };
+        assert_eq!(Payload::decode(&delivered_text(&transport.tasks()[0]).text),
+                   Payload::Reply(Reply { text: String::from("yes"), in_reply_to: quote.clone() }));
+        assert!(conversation.borrow().reply_to().is_none());
+        let reply = conversation.borrow().messages()[1].clone();
+        assert_eq!(reply.borrow().text(), "yes");
+        assert_eq!(reply.borrow().quote(), Some(&quote));
+
+        let answer = Payload::Reply(Reply { text: String::from("great"), in_reply_to: quote.clone() });
+        let answer = comm::client::messages::TextMessage::new(bob, answer.encode());
+        conversations.update(|conversations| {
+            conversations.handle_event(&connection, Event::ReceivedTextMessage(answer));
+        });
+        let received = conversation.borrow().messages()[2].clone();
+        assert_eq!(received.borrow().text(), "great");
+        assert_eq!(received.borrow().quote(), Some(&quote));
+    }
+
+    #[test]
+    fn senders_can_edit_and_delete_only_their_own_messages() {
+        let (connection, transport) = started_connection("alice");
+        connection.borrow_mut().set_encrypts_messages(false);
+        connection.borrow_mut().set_signs_messages(false);
+        connection.borrow_mut().set_sends_typing_indicators(false);
+        let conversations = Rc::new(RefCell::new(ConversationList::new()));
+        conversations.update(|conversations| conversations.add_connection(connection.clone()));
+
+        let bob = Address::for_content("bob");
+        let receive = |payload: Payload| {
+            let text_message = comm::client::messages::TextMessage::new(bob, payload.encode());
+            conversations.update(|conversations| {
+                conversations.handle_event(&connection, Event::ReceivedTextMessage(text_message));
+            });
+        };
+        receive(Payload::Text(String::from("helo")));
+        let conversation = conversations.borrow().get(0).unwrap().clone();
+        conversation.update(|conversation| {
+            conversation.set_pending_message(String::from("hi"));
+            conversation.send_message();
+        });
+        let (theirs, ours) = {
+            let conversation = conversation.borrow();
+            (conversation.messages()[0].clone(), conversation.messages()[1].clone())
+        };
+        let their_id = theirs.borrow().id().to_str();
+        let our_id = ours.borrow().id().to_str();
+
+        receive(Payload::Edit(Edit { message_id: their_id.clone(), text: String::from("hello") }));
+        receive(Payload::Edit(Edit { message_id: our_id.clone(), text: String::from("bye") }));
+        receive(Payload::Deletion(Deletion { message_id: our_id.clone() }));
+
+        assert_eq!(theirs.borrow().text(), "hello");
+        assert_eq!(theirs.borrow().history(), &vec![String::from("helo")]);
+        assert_eq!(ours.borrow().text(), "hi");
+        assert!(!ours.borrow().is_edited());
+        assert!(!ours.borrow().is_deleted());
+
+        receive(Payload::Deletion(Deletion { message_id: their_id.clone() }));
+        assert!(theirs.borrow().is_deleted());
+        assert_eq!(theirs.borrow().text(), "");
+        assert!(!theirs.borrow().is_edited());
+
+        conversation.update(|conversation| conversation.edit_message(&ours, String::from("hey")));
+        conversation.update(|conversation| conversation.delete_message(&ours));
+        let tasks = transport.tasks();
+        assert_eq!(Payload::decode(&delivered_text(&tasks[1]).text),
+                   Payload::Edit(Edit { message_id: our_id.clone(), text: String::from("hey") }));
+        assert_eq!(Payload::decode(&delivered_text(&tasks[2]).text),
+                   Payload::Deletion(Deletion { message_id: our_id.clone() }));
+        assert!(ours.borrow().is_deleted());
+    }
+
+    #[test]
+    fn typing_is_announced_while_the_pending_message_changes() {
+        let (connection, transport) = started_connection("alice");
+        connection.borrow_mut().set_encrypts_messages(false);
+        connection.borrow_mut().set_signs_messages(false);
+        let conversation = Rc::new(RefCell::new(Conversation::new(connection.clone())));
+        conversation.update(|conversation| {
+            conversation.set_recipient(Some(Address::for_content("bob")));
+            conversation.set_pending_message(String::from("h"));
+            conversation.set_pending_message(String::from("he"));
+            conversation.set_pending_message(String::new());
+            conversation.set_pending_message(String::from("hi"));
+            conversation.send_message();
+        });
+
+        let payloads: Vec<Payload> = transport.tasks().iter()
+            .map(|task| Payload::decode(&delivered_text(task).text))
+            .collect();
+        assert_eq!(payloads, vec![
+            Payload::Typing(Typing { stopped: false }),
+            Payload::Typing(Typing { stopped: true }),
+            Payload::Typing(Typing { stopped: false }),
+            Payload::Text(String::from("hi"))
+        ]);
+
+        connection.borrow_mut().set_sends_typing_indicators(false);
+        conversation.update(|conversation| conversation.set_pending_message(String::from("again")));
+        assert!(transport.tasks().is_empty());
+    }
+
+    #[test]
+    fn recipients_are_typing_until_they_stop_or_send() {
+        let (connection, _) = started_connection("alice");
+        let conversations = Rc::new(RefCell::new(ConversationList::new()));
+        conversations.update(|conversations| conversations.add_connection(connection.clone()));
+
+        let bob = Address::for_content("bob");
+        let receive = |payload: Payload| {
+            let text_message = comm::client::messages::TextMessage::new(bob, payload.encode());
+            conversations.update(|conversations| {
+                conversations.handle_event(&connection, Event::ReceivedTextMessage(text_message));
+            });
+        };
+        receive(Payload::Typing(Typing { stopped: false }));
+        assert_eq!(conversations.borrow().len(), 0);
+
+        receive(Payload::Text(String::from("hi")));
+        let conversation = conversations.borrow().get(0).unwrap().clone();
+        assert!(!conversation.borrow().recipient_is_typing());
+
+        receive(Payload::Typing(Typing { stopped: false }));
+        assert!(conversation.borrow().recipient_is_typing());
+        receive(Payload::Typing(Typing { stopped: true }));
+        assert!(!conversation.borrow().recipient_is_typing());
+
+        receive(Payload::Typing(Typing { stopped: false }));
+        receive(Payload::Text(String::from("there")));
+        assert!(!conversation.borrow().recipient_is_typing());
+    }
+
+    #[test]
+    fn read_receipts_are_sent_only_when_enabled() {
+        let (connection, transport) = started_connection("alice");
+        connection.borrow_mut().set_encrypts_messages(false);
+        connection.borrow_mut().set_signs_messages(false);
+        let conversations = Rc::new(RefCell::new(ConversationList::new()));
+        conversations.update(|conversations| conversations.add_connection(connection.clone()));
+
+        let bob = Address::for_content("bob");
+        let receive = |text: &str| {
+            let text_message = comm::client::messages::TextMessage::new(bob, String::from(text));
+            let id = text_message.id.to_str();
+            conversations.update(|conversations| {
+                conversations.handle_event(&connection, Event::ReceivedTextMessage(text_message));
+            });
+            id
+        };
+        receive("one");
+        let conversation = conversations.borrow().get(0).unwrap().clone();
+        conversations.update(|conversations| conversations.accept_request(0));
+
+        conversation.update(|conversation| conversation.mark_read());
+        assert!(conversation.borrow().messages()[0].borrow().is_read());
+        assert!(transport.tasks().is_empty());
+
+        connection.borrow_mut().set_sends_read_receipts(true);
+        let two = receive("two");
+        let three = receive("three");
+        conversation.update(|conversation| conversation.mark_read());
+        conversation.update(|conversation| conversation.mark_read());
+
+        let tasks = transport.tasks();
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(Payload::decode(&delivered_text(&tasks[0]).text),
+                   Payload::ReadReceipt(ReadReceipt { message_ids: vec![two, three] }));
+    }
+
+    #[test]
+    fn read_receipts_mark_sent_messages_read() {
+        let (connection, transport) = started_connection("alice");
+        connection.borrow_mut().set_encrypts_messages(false);
+        connection.borrow_mut().set_signs_messages(false);
+        connection.borrow_mut().set_sends_typing_indicators(false);
+        let conversations = Rc::new(RefCell::new(ConversationList::new()));
+        conversations.update(|conversations| {
+            conversations.add_connection(connection.clone());
+            conversations.start_new_conversation();
+        });
+        let conversation = conversations.borrow().get(0).unwrap().clone();
+        let bob = Address::for_content("bob");
+        conversation.update(|conversation| {
+            conversation.set_recipient(Some(bob));
+            conversation.set_pending_message(String::from("hello"));
+            conversation.send_message();
+        });
+        let id = delivered_text(&transport.tasks()[0]).id.to_str();
+        let message = conversation.borrow().messages()[0].clone();
+        let notified = Rc::new(Cell::new(0));
+        let observer = observer(&notified);
+        message.borrow_mut().register_observer(observer.clone());
+
+        let receipt = Payload::ReadReceipt(ReadReceipt { message_ids: vec![id] });
+        let text_message = comm::client::messages::TextMessage::new(bob, receipt.encode());
+        conversations.update(|conversations| {
+            conversations.handle_event(&connection, Event::ReceivedTextMessage(text_message));
+        });
+
+        assert!(message.borrow().is_read());
+        assert!(!message.borrow().acknowledged());
+        assert_eq!(notified.get(), 1);
+    }
+
+    #[test]
+    fn unsupported_messages_are_shown_as_such() {
+        let (connection, _) = started_connection("alice");
+        let conversations = Rc::new(RefCell::new(ConversationList::new()));
+        conversations.update(|conversations| conversations.add_connection(connection.clone()));
+
+        let bob = Address::for_content("bob");
+        let texts = ["hi", "\u{1}{\"version\":3,\"kind\":\"sticker\",\"body\":{\"id\":9}}"];
+        for text in texts.iter() {
+            let text_message = comm::client::messages::TextMessage::new(bob, String::from(*text));
+            conversations.update(|conversations| {
+                conversations.handle_event(&connection, Event::ReceivedTextMessage(text_message));
+            });
+        }
+
+        let conversations = conversations.borrow();
+        let messages = conversations.get(0).unwrap().borrow().messages().clone();
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].borrow().is_supported());
+        assert_eq!(messages[0].borrow().text(), "hi");
+        assert!(!messages[1].borrow().is_supported());
+        assert_eq!(messages[1].borrow().text(), "Unsupported message");
+    }
+
+    #[test]
+    fn conversations_are_encrypted_end_to_end() {
+        let identities = [started_connection("alice"), started_connection("bob")];
+        let lists: Vec<_> = identities.iter().map(|&(ref connection, _)| {
+            let conversations = Rc::new(RefCell::new(ConversationList::new()));
+            conversations.update(|conversations| conversations.add_connection(connection.clone()));
+            conversations
+        }).collect();
+        // Hands what each side has sent to the other, until neither has anything more to say
+        let relay = || {
+            let mut relayed = vec![];
+            loop {
+                let mut quiet = true;
+                for (from, to) in [(0, 1), (1, 0)].iter().cloned() {
+                    for task in identities[from].1.tasks() {
+                        let text_message = delivered_text(&task);
+                        relayed.push(text_message.text.clone());
+                        let connection = &identities[to].0;
+                        let event = Event::ReceivedTextMessage(text_message);
+                        lists[to].update(|list| list.handle_event(connection, event));
+                        quiet = false;
+                    }
+                }
+                if quiet {
+                    return relayed;
+                }
+            }
+        };
+
+        let (alice, bob) = (Address::for_content("alice"), Address::for_content("bob"));
+        let sent = Rc::new(RefCell::new(Conversation::new(identities[0].0.clone())));
+        lists[0].update(|list| list.add_conversation(sent.clone()));
+        sent.update(|conversation| {
+            conversation.set_recipient(Some(bob));
+            conversation.set_pending_message(String::from("hello bob"));
+            conversation.send_message();
+        });
+        assert_eq!(sent.borrow().encryption(), EncryptionState::Pending);
+
+        let relayed = relay();
+        assert!(relayed.iter().all(|text| !text.contains("hello bob")));
+        assert_eq!(sent.borrow().encryption(), EncryptionState::Encrypted);
+        let received = lists[1].borrow().get(0).unwrap().clone();
+        assert_eq!(received.borrow().recipient(), Some(alice));
+        assert_eq!(received.borrow().encryption(), EncryptionState::Encrypted);
+        let message = received.borrow().messages()[0].clone();
+        assert_eq!(message.borrow().text(), "hello bob");
+        assert_eq!(message.borrow().id(), sent.borrow().messages()[0].borrow().id());
+        assert!(message.borrow().is_authentic());
+
+        received.update(|conversation| {
+            conversation.set_pending_message(String::from("hi alice"));
+            conversation.send_message();
+            conversation.react(&message, "👍");
+        });
+        assert!(relay().iter().all(|text| !text.contains("hi alice")));
+        assert_eq!(sent.borrow().messages()[1].borrow().text(), "hi alice");
+        assert_eq!(sent.borrow().messages()[0].borrow().reactions(), vec![(String::from("👍"), 1)]);
+    }
+
+    #[test]
+    fn messages_are_given_up_on_when_the_key_exchange_goes_unanswered() {
+        let (connection, transport) = started_connection("alice");
+        connection.borrow_mut().set_signs_messages(false);
+        connection.borrow_mut().set_sends_typing_indicators(false);
+        let conversation = Rc::new(RefCell::new(Conversation::new(connection)));
+        conversation.update(|conversation| conversation.set_recipient(Some(Address::for_content("bob"))));
+        let send = |text: &str| {
+            conversation.update(|conversation| {
+                conversation.set_pending_message(String::from(text));
+                conversation.send_message();
+            });
+        };
+
+        for text in ["one", "two", "three", "four"].iter() {
+            send(text);
+        }
+        let failed: Vec<bool> = conversation.borrow().messages().iter()
+            .map(|message| message.borrow().has_failed())
+            .collect();
+        assert_eq!(failed, vec![true, true, true, false]);
+        assert_eq!(conversation.borrow().encryption(), EncryptionState::Pending);
+        transport.tasks();
+
+        // The user stops waiting, and what's waiting goes out with what follows
+        conversation.update(|conversation| conversation.send_unencrypted());
+        assert_eq!(conversation.borrow().encryption(), EncryptionState::Unencrypted);
+        send("five");
+        let texts: Vec<String> = transport.tasks().iter().map(|task| delivered_text(task).text).collect();
+        assert_eq!(texts, vec!["four", "five"]);
+    }
+
+    #[test]
+    fn changed_identity_keys_are_announced() {
+        struct Recorder {
+            verifications: RefCell<Vec<Verification>>
+        }
+
+        impl ConversationObserver for Recorder {
+            fn identity_was_changed(&self, _: String) { }
+            fn recipient_was_changed(&self, _: Option<Address>) { }
+            fn pending_message_was_changed(&self, _: String) { }
+            fn reply_was_changed(&self, _: Option<Quote>) { }
+            fn recipient_typing_was_changed(&self, _: bool) { }
+            fn encryption_was_changed(&self, _: EncryptionState) { }
+            fn verification_was_changed(&self, verification: Verification) {
+                self.verifications.borrow_mut().push(verification);
+            }
+            fn members_were_changed(&self, _: Vec<Address>) { }
+            fn did_receive_message(&mut self, _: Rc<RefCell<Message>>) { }
+            fn did_send_message(&mut self, _: Rc<RefCell<Message>>) { }
+        }
+
+        let (connection, transport) = started_connection("alice");
+        connection.borrow_mut().set_sends_typing_indicators(false);
+        connection.borrow_mut().set_signs_messages(false);
+        let conversations = Rc::new(RefCell::new(ConversationList::new()));
+        conversations.update(|conversations| conversations.add_connection(connection.clone()));
+        let receive = |text_message| {
+            conversations.update(|conversations| {
+                conversations.handle_event(&connection, Event::ReceivedTextMessage(text_message));
+            });
+        };
+
+        let bob = Address::for_content("bob");
+        let conversation = Rc::new(RefCell::new(Conversation::new(connection.clone())));
+        conversations.update(|conversations| conversations.add_conversation(conversation.clone()));
+        conversation.update(|conversation| {
+            conversation.set_recipient(Some(bob));
+            conversation.set_pending_message(String::from("hi"));
+            conversation.send_message();
+        });
+        let exchange = delivered_text(&transport.tasks()[0]);
+        let mut bob_sessions = Sessions::default();
+        bob_sessions.set_identity(bob, KeyPair::for_secret("bob"));
+        receive(bob_sessions.open(exchange).1.remove(0));
+        assert!(conversation.borrow().safety_number().is_some());
+
+        let recorder = Rc::new(RefCell::new(Recorder { verifications: RefCell::new(vec![]) }));
+        let _subscription = subscribe(&conversation, recorder.clone());
+        conversation.update(|conversation| conversation.set_verified(true));
+
+        // Someone else starts a session in bob's name
+        let mut mallory = Sessions::default();
+        mallory.set_identity(bob, KeyPair::for_secret("mallory"));
+        let hi = comm::client::messages::TextMessage::new(bob, String::from("hi"));
+        let exchange = mallory.seal(Address::for_content("alice"), hi);
+        receive(exchange[0].clone());
+        conversation.update(|conversation| conversation.dismiss_key_change());
+
+        assert_eq!(*recorder.borrow().verifications.borrow(),
+                   vec![Verification::Verified, Verification::KeyChanged, Verification::Unverified]);
+    }
+
+    #[test]
+    fn changing_whether_to_encrypt_is_announced() {
+        struct Recorder {
+            encryptions: RefCell<Vec<EncryptionState>>
+        }
+
+        impl ConversationObserver for Recorder {
+            fn identity_was_changed(&self, _: String) { }
+            fn recipient_was_changed(&self, _: Option<Address>) { }
+            fn pending_message_was_changed(&self, _: String) { }
+            fn reply_was_changed(&self, _: Option<Quote>) { }
+            fn recipient_typing_was_changed(&self, _: bool) { }
+            fn encryption_was_changed(&self, encryption: EncryptionState) {
+                self.encryptions.borrow_mut().push(encryption);
+            }
+            fn verification_was_changed(&self, _: Verification) { }
+            fn members_were_changed(&self, _: Vec<Address>) { }
+            fn did_receive_message(&mut self, _: Rc<RefCell<Message>>) { }
+            fn did_send_message(&mut self, _: Rc<RefCell<Message>>) { }
+        }
+
+        let (connection, _) = started_connection("alice");
+        connection.borrow_mut().set_sends_typing_indicators(false);
+        let conversations = Rc::new(RefCell::new(ConversationList::new()));
+        conversations.update(|conversations| conversations.add_connection(connection.clone()));
+        let conversation = Rc::new(RefCell::new(Conversation::new(connection.clone())));
+        conversations.update(|conversations| conversations.add_conversation(conversation.clone()));
+        conversation.update(|conversation| {
+            conversation.set_recipient(Some(Address::for_content("bob")));
+            conversation.set_pending_message(String::from("hi"));
+            conversation.send_message();
+        });
+
+        let recorder = Rc::new(RefCell::new(Recorder { encryptions: RefCell::new(vec![]) }));
+        let _subscription = subscribe(&conversation, recorder.clone());
+        conversations.update(|conversations| conversations.set_encrypts_messages(&connection, false));
+        conversations.update(|conversations| conversations.set_encrypts_messages(&connection, true));
+
+        assert_eq!(*recorder.borrow().encryptions.borrow(),
+                   vec![EncryptionState::Unencrypted, EncryptionState::Pending]);
+    }
+
+    #[test]
+    fn strangers_start_requests_until_accepted() {
+        let (connection, _) = started_connection("alice");
+        let conversations = Rc::new(RefCell::new(ConversationList::new()));
+        conversations.update(|conversations| {
+            conversations.add_connection(connection.clone());
+            conversations.start_new_conversation();
+        });
+        let receive = |sender: &str, text: &str| {
+            let text_message = comm::client::messages::TextMessage::new(
+                Address::for_content(sender), String::from(text));
+            conversations.update(|conversations| {
+                conversations.handle_event(&connection, Event::ReceivedTextMessage(text_message));
+            });
+        };
+
+        receive("bob", "hi");
+        receive("carol", "hello");
+        let (carol, bob) = {
+            let conversations = conversations.borrow();
+            (conversations.get(0).unwrap().clone(), conversations.get(1).unwrap().clone())
+        };
+        assert!(carol.borrow().is_request() && bob.borrow().is_request());
+        assert_eq!(conversations.borrow().selected(), Some(2));
+
+        conversations.update(|conversations| conversations.accept_request(1));
+        assert!(!bob.borrow().is_request());
+        assert!(Rc::ptr_eq(conversations.borrow().get(0).unwrap(), &bob));
+        assert_eq!(conversations.borrow().selected(), Some(2));
+
+        // Once accepted, the conversation carries on as any other
+        receive("bob", "still there?");
+        assert_eq!(bob.borrow().messages().len(), 2);
+        assert!(!bob.borrow().is_request());
+    }
+
+    #[test]
+    fn blocked_senders_are_dropped_and_remembered() {
+        let dir = temp_dir("blocklist");
+        let store = MessageStore::new(dir.join("messages.yml"));
+        let (connection, _) = started_connection("alice");
+        let conversations = Rc::new(RefCell::new(ConversationList::new()));
+        conversations.update(|conversations| conversations.add_connection(connection.clone()));
+        let (bob, carol) = (Address::for_content("bob"), Address::for_content("carol"));
+        let receive = |sender, text: &str| {
+            let text_message = comm::client::messages::TextMessage::new(sender, String::from(text));
+            conversations.update(|conversations| {
+                conversations.handle_event(&connection, Event::ReceivedTextMessage(text_message));
+            });
+        };
+
+        receive(bob, "buy now");
+        receive(carol, "hi");
+        conversations.update(|conversations| conversations.block_conversation(1));
+        assert!(*connection.borrow().blocked() == vec![bob]);
+        assert_eq!(conversations.borrow().len(), 1);
+        receive(bob, "buy now!!");
+        assert_eq!(conversations.borrow().len(), 1);
+        store.save(&connection, &conversations.borrow()).unwrap();
+
+        let (restored, _) = started_connection("alice");
+        let mut restored_conversations = ConversationList::new();
+        store.load(&restored, &mut restored_conversations);
+        assert!(restored.borrow().is_blocked(bob));
+        assert_eq!(restored_conversations.len(), 1);
+        assert!(restored_conversations.get(0).unwrap().borrow().is_request());
+
+        restored.borrow_mut().unblock(bob);
+        assert!(restored.borrow().blocked().is_empty());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn floods_are_hidden_and_huge_messages_discarded() {
+        let (connection, transport) = started_connection("alice");
+        connection.borrow_mut().set_limits(Limits {
+            messages_per_sender: 2,
+            messages_in_total: 3,
+            period_secs: 60,
+            max_message_bytes: 400
+        });
+        let conversations = Rc::new(RefCell::new(ConversationList::new()));
+        conversations.update(|conversations| conversations.add_connection(connection.clone()));
+        let receive = |sender: &str, text: &str| {
+            let text_message = comm::client::messages::TextMessage::new(
+                Address::for_content(sender), String::from(text));
+            conversations.update(|conversations| {
+                conversations.handle_event(&connection, Event::ReceivedTextMessage(text_message));
+            });
+        };
+
+        receive("bob", &"spam".repeat(101));
+        assert_eq!(conversations.borrow().len(), 0);
+
+        for _ in 0..5 {
+            receive("bob", "buy now");
+        }
+        let bob = conversations.borrow().get(0).unwrap().clone();
+        let texts: Vec<String> = bob.borrow().messages().iter()
+            .map(|message| String::from(message.borrow().text()))
+            .collect();
+        assert_eq!(texts, vec!["buy now", "buy now", "3 messages hidden"]);
+        assert_eq!(bob.borrow().messages()[2].borrow().hidden_count(), 3);
+
+        // Control messages and key exchanges are limited too, before they're opened
+        let hidden_id = bob.borrow().messages()[2].borrow().id().to_str();
+        receive("bob", &Payload::Deletion(Deletion { message_id: hidden_id }).encode());
+        assert!(!bob.borrow().messages()[2].borrow().is_deleted());
+        let (alice, bob_address) = (Address::for_content("alice"), Address::for_content("bob"));
+        let mut bob_sessions = Sessions::default();
+        bob_sessions.set_identity(bob_address, KeyPair::for_secret("bob"));
+        let hi = comm::client::messages::TextMessage::new(bob_address, String::from("hi"));
+        receive("bob", &bob_sessions.seal(alice, hi)[0].text);
+        assert!(transport.tasks().is_empty());
+        assert_eq!(bob.borrow().messages()[2].borrow().hidden_count(), 5);
+
+        // Strangers together are limited too, and new senders over it don't start conversations
+        receive("carol", "hi");
+        receive("dave", "hi");
+        assert_eq!(conversations.borrow().len(), 2);
+
+        // but contacts are only held to their own limit
+        let carol = conversations.borrow().get(0).unwrap().clone();
+        conversations.update(|conversations| conversations.accept_request(0));
+        receive("carol", "it's me");
+        let texts: Vec<String> = carol.borrow().messages().iter()
+            .map(|message| String::from(message.borrow().text()))
+            .collect();
+        assert_eq!(texts, vec!["hi", "it's me"]);
+    }
+
+    #[test]
+    fn contacts_typing_is_not_held_against_their_limit() {
+        let (connection, _) = started_connection("alice");
+        connection.borrow_mut().set_limits(Limits { messages_per_sender: 2, ..Limits::default() });
+        let conversations = Rc::new(RefCell::new(ConversationList::new()));
+        conversations.update(|conversations| conversations.add_connection(connection.clone()));
+        let receive = |payload: Payload| {
+            let text_message = comm::client::messages::TextMessage::new(
+                Address::for_content("bob"), payload.encode());
+            conversations.update(|conversations| {
+                conversations.handle_event(&connection, Event::ReceivedTextMessage(text_message));
+            });
+        };
+
+        receive(Payload::Text(String::from("hi")));
+        conversations.update(|conversations| conversations.accept_request(0));
+        for _ in 0..20 {
+            receive(Payload::Typing(Typing { stopped: false }));
+        }
+        receive(Payload::Text(String::from("it's me")));
+        receive(Payload::Text(String::from("again")));
+
+        let bob = conversations.borrow().get(0).unwrap().clone();
+        let texts: Vec<String> = bob.borrow().messages().iter()
+            .map(|message| String::from(message.borrow().text()))
+            .collect();
+        assert_eq!(texts, vec!["hi", "it's me", "1 message hidden"]);
+        assert_eq!(bob.borrow().messages()[2].borrow().hidden_count(), 1);
+    }
+
+    #[test]
+    fn forged_messages_are_marked_and_change_nothing() {
+        let (connection, _) = started_connection("alice");
+        let conversations = Rc::new(RefCell::new(ConversationList::new()));
+        conversations.update(|conversations| conversations.add_connection(connection.clone()));
+        let receive = |text_message| {
+            conversations.update(|conversations| {
+                conversations.handle_event(&connection, Event::ReceivedTextMessage(text_message));
+            });
+        };
+        let text = |sender, text: &str| comm::client::messages::TextMessage::new(sender, String::from(text));
+
+        let (alice, bob) = (Address::for_content("alice"), Address::for_content("bob"));
+        let mut bob_sessions = Sessions::default();
+        bob_sessions.set_signing_key(SigningKey::for_secret("bob"));
+        let mut mallory = Sessions::default();
+        mallory.set_signing_key(SigningKey::for_secret("mallory"));
+
+        let hi = text(bob, "hi");
+        let deletion = Payload::Deletion(Deletion { message_id: hi.id.to_str() }).encode();
+        receive(bob_sessions.sign(alice, hi));
+        receive(mallory.sign(alice, text(bob, &deletion)));
+        receive(mallory.sign(alice, text(bob, "send money")));
+        receive(text(bob, "unsigned"));
+
+        let conversation = conversations.borrow().get(0).unwrap().clone();
+        let conversation = conversation.borrow();
+        let messages = conversation.messages();
+        assert_eq!(messages.len(), 3);
+        assert!(messages[0].borrow().is_authentic());
+        assert!(!messages[0].borrow().is_deleted());
+        assert_eq!(messages[1].borrow().text(), "send money");
+        assert!(!messages[1].borrow().is_authentic());
+        assert!(!messages[2].borrow().is_authentic());
+        assert_eq!(conversation.verification(), Verification::KeyChanged);
+    }
+
+    #[test]
+    fn connection_moves_through_its_states() {
+        struct Recorder {
+            events: Vec<String>
+        }
+
+        impl ConnectionObserver for Recorder {
+            fn connection_started(&mut self, self_address: Address) {
+                self.events.push(format!("started as {}", self_address.to_str()));
+            }
+
+            fn connection_shutdown(&mut self) {
+                self.events.push(String::from("shutdown"));
+            }
+
+            fn blocklist_was_changed(&mut self, _: Vec<Address>) { }
+        }
+
+        let transport = Rc::new(FakeTransport::default());
+        let (connection, _) = Connection::with_transport("alice", transport.clone());
+        let recorder = Rc::new(RefCell::new(Recorder { events: vec![] }));
+        let _subscription = subscribe(&connection, recorder.clone());
+        assert_eq!(connection.borrow().state(), ConnectionState::Stopped);
+
+        assert!(connection.borrow_mut().start(&Profile::default()).is_err());
+        assert_eq!(connection.borrow().state(), ConnectionState::Stopped);
+
+        connection.borrow_mut().start(&profile("alice")).unwrap();
+        assert_eq!(connection.borrow().state(), ConnectionState::Starting);
+
+        connection.update(|c| c.handle_event(&connection, Event::Started));
+        assert_eq!(connection.borrow().state(), ConnectionState::Running);
+
+        connection.borrow_mut().shutdown();
+        assert_eq!(connection.borrow().state(), ConnectionState::Stopping);
+        match transport.tasks().as_slice() {
+            [Task::Shutdown] => { }
+            _ => panic!("Expected a single shutdown task")
+        }
+
+        connection.update(|c| c.handle_event(&connection, Event::Shutdown));
+        assert_eq!(connection.borrow().state(), ConnectionState::Stopped);
+
+        let alice = Address::for_content("alice").to_str();
+        assert_eq!(recorder.borrow().events, vec![format!("started as {}", alice), String::from("shutdown")]);
+    }
+
+    #[test]
+    fn configuration_round_trips_through_its_file() {
+        let dir = temp_dir("configuration");
+        let path = dir.join("comm.yml");
+
+        let mut configuration = Configuration::empty();
+        configuration.select_profile("work");
+        configuration.profile_mut("work")
+            .update(Some(String::from("secret")), vec![String::from("127.0.0.1:6667")], Some(6668));
+        configuration.add_profile("home");
+        configuration.profile_mut("home").set_sends_typing_indicators(false);
+        configuration.keybindings.insert(String::from("next-conversation"), String::from("<Alt>Down"));
+        configuration.save(path.clone()).unwrap();
+
+        let loaded = Configuration::load_from_config_or_empty(path);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(loaded, configuration);
+        assert_eq!(loaded.current_profile_name(), "work");
+        assert_eq!(loaded.profile_names(), vec!["home", "work"]);
+    }
+
+    #[test]
+    fn legacy_configuration_becomes_the_default_profile() {
+        let dir = temp_dir("legacy-configuration");
+        let path = dir.join("comm.yml");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&path, "secret: secret\nrouter: 127.0.0.1:6667\nport: 6668\n").unwrap();
+
+        let loaded = Configuration::load_from_config_or_empty(path);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut expected = Profile::default();
+        expected.update(Some(String::from("secret")), vec![String::from("127.0.0.1:6667")], Some(6668));
+        assert_eq!(loaded.profile(super::DEFAULT_PROFILE), expected);
+    }
+
+    #[test]
+    fn limits_are_read_from_the_configuration() {
+        let dir = temp_dir("limits-configuration");
+        let path = dir.join("comm.yml");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&path, "profiles:\n  default:\n    limits:\n      messages_per_sender: 5\n").unwrap();
+
+        let loaded = Configuration::load_from_config_or_empty(path);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let limits = loaded.profile(super::DEFAULT_PROFILE).limits();
+        assert_eq!(limits.messages_per_sender, 5);
+        assert_eq!(limits.max_message_bytes, Limits::default().max_message_bytes);
+        assert!(Profile::default().limits().is_default());
+    }
+
+    #[test]
+    fn groups_send_each_member_a_copy_and_count_their_acknowledgements() {
+        let (connection, transport) = started_connection("alice");
+        connection.borrow_mut().set_encrypts_messages(false);
+        connection.borrow_mut().set_signs_messages(false);
+        let conversations = Rc::new(RefCell::new(ConversationList::new()));
+        conversations.update(|conversations| {
+            conversations.add_connection(connection.clone());
+            conversations.start_new_conversation();
+        });
+        let conversation = conversations.borrow().get(0).unwrap().clone();
+        let (alice, bob, carol) = (Address::for_content("alice"), Address::for_content("bob"),
+                                   Address::for_content("carol"));
+
+        conversation.update(|conversation| {
+            conversation.set_members(vec![bob, carol]);
+            conversation.set_pending_message(String::from("hi all"));
+            conversation.send_message();
+        });
+        assert!(conversation.borrow().is_group());
+        assert!(conversation.borrow().recipient().is_none());
+        let group_id = conversation.borrow().group_id().unwrap().to_owned();
+        let message = conversation.borrow().messages()[0].clone();
+
+        // Members are invited before the first message reaches them
+        let tasks = transport.tasks();
+        assert_eq!(tasks.len(), 4);
+        let invite = Payload::Invite(Invite {
+            group_id: group_id.clone(),
+            members: vec![bob.to_str(), carol.to_str(), alice.to_str()]
+        });
+        assert_eq!(Payload::decode(&delivered_text(&tasks[0]).text), invite);
+        assert_eq!(Payload::decode(&delivered_text(&tasks[1]).text), invite);
+        let copies: Vec<comm::client::messages::TextMessage> = tasks[2..].iter()
+            .map(delivered_text)
+            .collect();
+        assert!(copies[0].id != copies[1].id);
+        for copy in copies.iter() {
+            assert_eq!(Payload::decode(&copy.text), Payload::Group(GroupMessage {
+                group_id: group_id.clone(),
+                message_id: message.borrow().id().to_str(),
+                text: String::from("hi all")
+            }));
+        }
+
+        let acknowledge = |copy: &comm::client::messages::TextMessage| {
+            let ack = comm::client::messages::MessageAcknowledgement { message_id: copy.id };
+            conversations.update(|conversations| {
+                conversations.handle_event(&connection, Event::ReceivedMessageAcknowledgement(ack));
+            });
+        };
+        acknowledge(&copies[0]);
+        acknowledge(&copies[0]);
+        assert_eq!(message.borrow().acknowledgements(), (1, 2));
+        assert!(!message.borrow().acknowledged());
+        acknowledge(&copies[1]);
+        assert_eq!(message.borrow().acknowledgements(), (2, 2));
+        assert!(message.borrow().acknowledged());
+
+        conversations.update(|conversations| conversations.leave_group(0));
+        assert_eq!(conversations.borrow().len(), 0);
+        let tasks = transport.tasks();
+        assert_eq!(tasks.len(), 2);
+        assert_eq!(Payload::decode(&delivered_text(&tasks[0]).text),
+                   Payload::Leave(Leave { group_id: group_id }));
+    }
+
+    #[test]
+    fn groups_leave_out_the_user_and_can_be_formed_before_the_connection_starts() {
+        let (bob, carol) = (Address::for_content("bob"), Address::for_content("carol"));
+        let unstarted = conversation();
+        unstarted.update(|conversation| {
+            conversation.set_members(vec![bob]);
+            conversation.invite(carol);
+        });
+        assert!(*unstarted.borrow().members() == vec![bob, carol]);
+
+        let (connection, _) = started_connection("alice");
+        let alice = Address::for_content("alice");
+        let conversation = Rc::new(RefCell::new(Conversation::new(connection)));
+        conversation.update(|conversation| {
+            conversation.set_members(vec![bob, alice]);
+            conversation.invite(alice);
+        });
+        assert!(*conversation.borrow().members() == vec![bob]);
+    }
+
+    #[test]
+    fn group_members_are_kept_up_to_date() {
+        let dir = temp_dir("groups");
+        let store = MessageStore::new(dir.join("messages.yml"));
+        let (connection, _) = started_connection("alice");
+        let conversations = Rc::new(RefCell::new(ConversationList::new()));
+        conversations.update(|conversations| conversations.add_connection(connection.clone()));
+        let (alice, bob, carol, dave) = (Address::for_content("alice"), Address::for_content("bob"),
+                                         Address::for_content("carol"), Address::for_content("dave"));
+        let receive = |sender: Address, payload: Payload| {
+            let text_message = comm::client::messages::TextMessage::new(sender, payload.encode());
+            conversations.update(|conversations| {
+                conversations.handle_event(&connection, Event::ReceivedTextMessage(text_message));
+            });
+        };
+        let invite = |members: Vec<Address>| Payload::Invite(Invite {
+            group_id: String::from("friends"),
+            members: members.iter().map(|member| member.to_str()).collect()
+        });
+        let in_group = |id: &str, payload: Payload| Payload::Group(GroupMessage {
+            group_id: String::from("friends"),
+            message_id: Address::for_content(id).to_str(),
+            text: payload.encode()
+        });
+
+        receive(bob, invite(vec![bob, carol, alice]));
+        let group = conversations.borrow().get(0).unwrap().clone();
+        assert!(group.borrow().is_group() && group.borrow().is_request());
+        assert_eq!(group.borrow().group_id(), Some("friends"));
+        assert!(*group.borrow().members() == vec![bob, carol]);
+
+        // Messages are known by their id in the group, and only members may send them
+        receive(carol, in_group("hello", Payload::Text(String::from("hello"))));
+        receive(dave, in_group("let me in", Payload::Text(String::from("let me in"))));
+        assert_eq!(conversations.borrow().len(), 1);
+        assert_eq!(group.borrow().messages().len(), 1);
+        let hello = group.borrow().messages()[0].clone();
+        assert!(hello.borrow().id() == Address::for_content("hello"));
+        assert!(hello.borrow().sender() == Some(carol));
+
+        // Only whoever sent a message can delete it
+        let deletion = Payload::Deletion(Deletion { message_id: hello.borrow().id().to_str() });
+        receive(bob, in_group("deletion by bob", deletion.clone()));
+        assert!(!hello.borrow().is_deleted());
+        receive(carol, in_group("deletion by carol", deletion));
+        assert!(hello.borrow().is_deleted());
+
+        receive(bob, invite(vec![bob, carol, dave, alice]));
+        assert!(*group.borrow().members() == vec![bob, carol, dave]);
+        // An invite can't drop anyone, only their own leave can
+        receive(bob, invite(vec![bob, alice]));
+        assert!(*group.borrow().members() == vec![bob, carol, dave]);
+        receive(carol, Payload::Leave(Leave { group_id: String::from("friends") }));
+        assert!(*group.borrow().members() == vec![bob, dave]);
+        receive(carol, invite(vec![carol, alice]));
+        assert!(*group.borrow().members() == vec![bob, dave]);
+
+        store.save(&connection, &conversations.borrow()).unwrap();
+        let (restored, _) = started_connection("alice");
+        let mut restored_conversations = ConversationList::new();
+        store.load(&restored, &mut restored_conversations);
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(restored_conversations.len(), 1);
+        let restored_group = restored_conversations.get(0).unwrap().borrow();
+        assert_eq!(restored_group.group_id(), Some("friends"));
+        assert!(*restored_group.members() == vec![bob, dave]);
+        assert!(restored_group.is_request());
+        assert!(restored_group.messages()[0].borrow().sender() == Some(carol));
+    }
+
+    #[test]
+    fn identities_are_saved_only_once_something_stored_changes() {
+        let dir = temp_dir("save-changes");
+        let path = dir.join("alice.yml");
+        let (connection, _) = started_connection("alice");
+        connection.borrow_mut().set_encrypts_messages(false);
+        let conversations = Rc::new(RefCell::new(ConversationList::new()));
+        let mut event_handler = EventHandler::new(conversations.clone());
+        event_handler.add_identity(connection.clone(), MessageStore::new(path.clone()));
+        let bob = Address::for_content("bob");
+        let receive = |payload: Payload| {
+            let text_message = comm::client::messages::TextMessage::new(bob, payload.encode());
+            event_handler.handle_event("alice", Event::ReceivedTextMessage(text_message));
+        };
+
+        // Sending is saved without waiting for an event
+        conversations.update(|conversations| conversations.start_new_conversation());
+        let conversation = conversations.borrow().get(0).unwrap().clone();
+        conversation.update(|conversation| {
+            conversation.set_recipient(Some(bob));
+            conversation.set_pending_message(String::from("hi bob"));
+            conversation.send_message();
+        });
+        event_handler.save_changes();
+        assert!(fs::read_to_string(&path).unwrap().contains("hi bob"));
+
+        fs::remove_file(&path).unwrap();
+        receive(Payload::Typing(Typing { stopped: false }));
+        event_handler.save_changes();
+        assert!(!path.exists());
+
+        receive(Payload::Text(String::from("hi alice")));
+        assert!(fs::read_to_string(&path).unwrap().contains("hi alice"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn sessions_are_saved_before_what_they_sealed_is_sent() {
+        let dir = temp_dir("save-sessions");
+        let (alice, bob) = (Address::for_content("alice"), Address::for_content("bob"));
+        let mut bob_sessions = Sessions::default();
+        bob_sessions.set_identity(bob, KeyPair::for_secret("bob"));
+        // Starts alice's client from whatever it has saved
+        let start = || {
+            let (connection, transport) = started_connection("alice");
+            connection.borrow_mut().set_signs_messages(false);
+            MessageStore::new(dir.join("alice.yml")).load(&connection, &mut ConversationList::new());
+            (connection, transport)
+        };
+        let send = |connection: &Rc<RefCell<Connection>>, text: &str| {
+            let message = comm::client::messages::TextMessage::new(alice, String::from(text));
+            connection.borrow_mut().send(bob, message);
+        };
+
+        let (connection, transport) = start();
+        send(&connection, "one");
+        let (_, answer) = bob_sessions.open(delivered_text(&transport.tasks()[0]));
+        connection.borrow_mut().open(answer[0].clone());
+        send(&connection, "two");
+        let mut sent: Vec<_> = transport.tasks().iter().map(delivered_text).collect();
+
+        // It crashes before anything else is saved, and the next message doesn't reuse a key
+        drop(connection);
+        let (connection, transport) = start();
+        send(&connection, "three");
+        sent.extend(transport.tasks().iter().map(delivered_text));
+
+        let texts: Vec<String> = sent.into_iter()
+            .filter_map(|message| bob_sessions.open(message).0)
+            .map(|message| message.text)
+            .collect();
+        assert_eq!(texts, vec!["one", "two", "three"]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn missing_configuration_is_empty() {
+        let loaded = Configuration::load_from_config_or_empty(temp_dir("missing").join("comm.yml"));
+        assert_eq!(loaded, Configuration::empty());
+    }
+
+    fn free_port() -> u16 {
+        UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+    }
+
+    /// Routes the events both clients have emitted so far, until `done` or the timeout.
+    fn pump_until<F: Fn() -> bool>(event_handler: &EventHandler,
+                                   events: &[(&str, &mpsc::Receiver<Event>)], done: F) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while Instant::now() < deadline {
+            for &(name, receiver) in events.iter() {
+                while let Ok(event) = receiver.try_recv() {
+                    event_handler.handle_event(name, event);
+                }
+            }
+            if done() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        false
+    }
+
+    /// Runs two real clients on the loopback interface, the second bootstrapping from the first.
+    #[test]
+    fn messages_are_delivered_and_acknowledged_over_loopback() {
+        let dir = temp_dir("loopback");
+        let (alice_port, bob_port) = (free_port(), free_port());
+        let transport = Rc::new(CommTransport::on_host("127.0.0.1"));
+
+        let (alice, alice_events) = Connection::with_transport("alice", transport.clone());
+        let (bob, bob_events) = Connection::with_transport("bob", transport);
+
+        let mut alice_profile = Profile::default();
+        alice_profile.update(Some(String::from("alice secret")), vec![], Some(alice_port));
+        let mut bob_profile = Profile::default();
+        bob_profile.update(Some(String::from("bob secret")),
+                           vec![format!("127.0.0.1:{}", alice_port)], Some(bob_port));
+
+        let conversations = Rc::new(RefCell::new(ConversationList::new()));
+        let mut event_handler = EventHandler::new(conversations.clone());
+        event_handler.add_identity(alice.clone(), MessageStore::new(dir.join("alice.yml")));
+        event_handler.add_identity(bob.clone(), MessageStore::new(dir.join("bob.yml")));
+        let events = [("alice", &alice_events), ("bob", &bob_events)];
+
+        alice.borrow_mut().start(&alice_profile).unwrap();
+        bob.borrow_mut().start(&bob_profile).unwrap();
+        assert!(pump_until(&event_handler, &events, || {
+            alice.borrow().state() == ConnectionState::Running &&
+                bob.borrow().state() == ConnectionState::Running
+        }), "Clients didn't start");
+
+        // The list starts conversations as its first identity, alice
+        conversations.update(|conversations| conversations.start_new_conversation());
+        let sent = conversations.borrow().get(0).unwrap().clone();
+        sent.update(|conversation| {
+            conversation.set_recipient(Some(Address::for_content("bob secret")));
+            conversation.set_pending_message(String::from("hello bob"));
+            conversation.send_message();
+        });
+
+        let alice_address = Address::for_content("alice secret");
+        let received = || {
+            let conversations = conversations.borrow();
+            (0..conversations.len()).filter_map(|index| conversations.get(index).cloned()).find(|c| {
+                let c = c.borrow();
+                Rc::ptr_eq(c.connection(), &bob) && c.recipient() == Some(alice_address)
+            })
+        };
+        assert!(pump_until(&event_handler, &events, || {
+            received().is_some() && sent.borrow().messages()[0].borrow().acknowledged()
+        }), "Message wasn't delivered and acknowledged");
+
+        let received = received().unwrap();
+        let received = received.borrow();
+        assert_eq!(received.messages().len(), 1);
+        assert!(received.messages()[0].borrow().was_received());
+        assert_eq!(received.messages()[0].borrow().text(), "hello bob");
+
+        alice.borrow_mut().shutdown();
+        bob.borrow_mut().shutdown();
+        pump_until(&event_handler, &events, || {
+            alice.borrow().state() == ConnectionState::Stopped &&
+                bob.borrow().state() == ConnectionState::Stopped
+        });
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
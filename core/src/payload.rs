@@ -0,0 +1,348 @@
+//! What the text of a comm text message carries.
+//!
+//! Typed messages are sent as they are, so that clients which only know plain text can still
+//! read them. Anything else, such as a reaction or a reply, is sent as an envelope: JSON naming
+//! the format version and the kind of payload, behind a control character typed text never
+//! starts with. Versions only ever add kinds and optional fields, so an envelope from a newer
+//! client is read as far as this one understands it, and a kind it doesn't know at all is shown
+//! as unsupported rather than as the JSON it arrived as.
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json;
+
+const PREFIX: char = '\u{1}';
+
+/// Version of the envelope format this client writes.
+pub const VERSION: u32 = 1;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Payload {
+    Text(String),
+    Reaction(Reaction),
+    Reply(Reply),
+    Edit(Edit),
+    Deletion(Deletion),
+    Typing(Typing),
+    ReadReceipt(ReadReceipt),
+    KeyExchange(KeyExchange),
+    Encrypted(Encrypted),
+    Signed(Signed),
+    Group(GroupMessage),
+    Invite(Invite),
+    Leave(Leave),
+    /// An envelope of a kind this client doesn't know, with the text it arrived as.
+    Unsupported { kind: String, text: String }
+}
+
+/// Adds an emoji reaction to the message with the id `message_id`, or takes it back.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Reaction {
+    pub message_id: String,
+    pub emoji: String,
+    #[serde(default)]
+    pub removed: bool
+}
+
+/// Text sent in reply to another message.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Reply {
+    pub text: String,
+    pub in_reply_to: Quote
+}
+
+/// Replaces the text of the sender's message with the id `message_id`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Edit {
+    pub message_id: String,
+    pub text: String
+}
+
+/// Deletes the sender's message with the id `message_id` for everyone in the conversation.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Deletion {
+    pub message_id: String
+}
+
+/// Tells the recipient that the sender is typing a message to them, or has stopped.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Typing {
+    #[serde(default)]
+    pub stopped: bool
+}
+
+/// Tells the sender that the recipient has read the messages with the ids `message_ids`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ReadReceipt {
+    pub message_ids: Vec<String>
+}
+
+/// Starts an encrypted session with the recipient, or answers their request to start one. Keys
+/// are hex encoded X25519 public keys.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct KeyExchange {
+    pub identity_key: String,
+    pub ephemeral_key: String,
+    #[serde(default)]
+    pub response: bool,
+    /// Hex encoded Ed25519 public key the sender signs with, so that the safety number covers it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signing_key: Option<String>
+}
+
+/// Another payload, encrypted for the recipient by the session set up with a key exchange.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Encrypted {
+    pub header: Header,
+    /// Hex encoded ciphertext followed by its authentication tag.
+    pub ciphertext: String
+}
+
+/// Another payload as the sender sent it, with their signature over it. The signature also covers
+/// the message's id and the sender's and the recipient's addresses, so it can't be passed off as
+/// another message or as from or to anyone else.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Signed {
+    /// Hex encoded Ed25519 public key of the sender.
+    pub signing_key: String,
+    /// Hex encoded Ed25519 signature.
+    pub signature: String,
+    pub text: String
+}
+
+/// Another payload sent to each member of the group `group_id`. Every member is sent a copy of
+/// their own, so the payload is known in the group by `message_id` rather than by the copy's id.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GroupMessage {
+    pub group_id: String,
+    pub message_id: String,
+    pub text: String
+}
+
+/// Adds the recipient to the group `group_id`, or tells a member who's in it now that someone has
+/// been added. `members` are the addresses of everyone in the group, the sender included.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Invite {
+    pub group_id: String,
+    pub members: Vec<String>
+}
+
+/// Tells the members of the group `group_id` that the sender has left it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Leave {
+    pub group_id: String
+}
+
+/// What the recipient needs to find the key an encrypted payload was sealed with: the sender's
+/// current ratchet key, how many payloads were sent under the previous one, and this one's number.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Header {
+    pub ratchet_key: String,
+    pub previous: u32,
+    pub number: u32
+}
+
+/// Refers to a message by its id, with the start of its text for showing above the reply.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Quote {
+    pub message_id: String,
+    pub excerpt: String
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    version: u32,
+    kind: String,
+    body: serde_json::Value
+}
+
+impl Payload {
+    /// The text to send.
+    pub fn encode(&self) -> String {
+        match *self {
+            Payload::Text(ref text) => text.clone(),
+            Payload::Reaction(ref reaction) => seal("reaction", reaction),
+            Payload::Reply(ref reply) => seal("reply", reply),
+            Payload::Edit(ref edit) => seal("edit", edit),
+            Payload::Deletion(ref deletion) => seal("delete", deletion),
+            Payload::Typing(ref typing) => seal("typing", typing),
+            Payload::ReadReceipt(ref receipt) => seal("read", receipt),
+            Payload::KeyExchange(ref exchange) => seal("key_exchange", exchange),
+            Payload::Encrypted(ref encrypted) => seal("encrypted", encrypted),
+            Payload::Signed(ref signed) => seal("signed", signed),
+            Payload::Group(ref group) => seal("group", group),
+            Payload::Invite(ref invite) => seal("invite", invite),
+            Payload::Leave(ref leave) => seal("leave", leave),
+            Payload::Unsupported { ref text, .. } => text.clone()
+        }
+    }
+
+    /// Reads the text of a received message. Text that isn't an envelope is plain text.
+    pub fn decode(text: &str) -> Payload {
+        if !text.starts_with(PREFIX) {
+            return Payload::Text(String::from(text));
+        }
+        let envelope: Envelope = match serde_json::from_str(&text[PREFIX.len_utf8()..]) {
+            Ok(envelope) => envelope,
+            Err(_) => return Payload::Text(String::from(text))
+        };
+
+        let payload = match envelope.kind.as_str() {
+            "reaction" => open(envelope.body).map(Payload::Reaction),
+            "reply" => open(envelope.body).map(Payload::Reply),
+            "edit" => open(envelope.body).map(Payload::Edit),
+            "delete" => open(envelope.body).map(Payload::Deletion),
+            "typing" => open(envelope.body).map(Payload::Typing),
+            "read" => open(envelope.body).map(Payload::ReadReceipt),
+            "key_exchange" => open(envelope.body).map(Payload::KeyExchange),
+            "encrypted" => open(envelope.body).map(Payload::Encrypted),
+            "signed" => open(envelope.body).map(Payload::Signed),
+            "group" => open(envelope.body).map(Payload::Group),
+            "invite" => open(envelope.body).map(Payload::Invite),
+            "leave" => open(envelope.body).map(Payload::Leave),
+            _ => None
+        };
+        payload.unwrap_or_else(|| {
+            warn!("Couldn't read {} envelope of version {}", envelope.kind, envelope.version);
+            Payload::Unsupported { kind: envelope.kind, text: String::from(text) }
+        })
+    }
+}
+
+fn seal<T: Serialize>(kind: &str, body: &T) -> String {
+    let envelope = Envelope {
+        version: VERSION,
+        kind: String::from(kind),
+        body: serde_json::to_value(body).expect("Couldn't encode payload")
+    };
+    let json = serde_json::to_string(&envelope).expect("Couldn't encode payload");
+    format!("{}{}", PREFIX, json)
+}
+
+fn open<T: DeserializeOwned>(body: serde_json::Value) -> Option<T> {
+    serde_json::from_value(body).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_is_sent_as_is() {
+        let payload = Payload::Text(String::from("hello"));
+        assert_eq!(payload.encode(), "hello");
+        assert_eq!(Payload::decode("hello"), payload);
+    }
+
+    #[test]
+    fn reactions_survive_a_round_trip() {
+        let payload = Payload::Reaction(Reaction {
+            message_id: String::from("abc"),
+            emoji: String::from("👍"),
+            removed: true
+        });
+        assert_eq!(Payload::decode(&payload.encode()), payload);
+    }
+
+    #[test]
+    fn replies_survive_a_round_trip() {
+        let payload = Payload::Reply(Reply {
+            text: String::from("me too"),
+            in_reply_to: Quote { message_id: String::from("abc"), excerpt: String::from("hungry") }
+        });
+        assert_eq!(Payload::decode(&payload.encode()), payload);
+    }
+
+    #[test]
+    fn edits_and_deletions_survive_a_round_trip() {
+        let edit = Payload::Edit(Edit { message_id: String::from("abc"), text: String::from("fixed") });
+        let deletion = Payload::Deletion(Deletion { message_id: String::from("abc") });
+        assert_eq!(Payload::decode(&edit.encode()), edit);
+        assert_eq!(Payload::decode(&deletion.encode()), deletion);
+    }
+
+    #[test]
+    fn encryption_payloads_survive_a_round_trip() {
+        let exchange = Payload::KeyExchange(KeyExchange {
+            identity_key: String::from("ab"),
+            ephemeral_key: String::from("cd"),
+            response: true,
+            signing_key: Some(String::from("ef"))
+        });
+        let encrypted = Payload::Encrypted(Encrypted {
+            header: Header { ratchet_key: String::from("ef"), previous: 2, number: 5 },
+            ciphertext: String::from("0123")
+        });
+        assert_eq!(Payload::decode(&exchange.encode()), exchange);
+        assert_eq!(Payload::decode(&encrypted.encode()), encrypted);
+    }
+
+    #[test]
+    fn signed_payloads_survive_a_round_trip() {
+        let inner = Payload::Edit(Edit { message_id: String::from("abc"), text: String::from("fixed") });
+        let signed = Payload::Signed(Signed {
+            signing_key: String::from("ab"),
+            signature: String::from("cd"),
+            text: inner.encode()
+        });
+        match Payload::decode(&signed.encode()) {
+            Payload::Signed(decoded) => assert_eq!(Payload::decode(&decoded.text), inner),
+            payload => panic!("Expected a signed payload, got {:?}", payload)
+        }
+    }
+
+    #[test]
+    fn group_payloads_survive_a_round_trip() {
+        let group = Payload::Group(GroupMessage {
+            group_id: String::from("ab"),
+            message_id: String::from("cd"),
+            text: Payload::Text(String::from("hi all")).encode()
+        });
+        let invite = Payload::Invite(Invite {
+            group_id: String::from("ab"),
+            members: vec![String::from("ef"), String::from("01")]
+        });
+        let leave = Payload::Leave(Leave { group_id: String::from("ab") });
+        assert_eq!(Payload::decode(&group.encode()), group);
+        assert_eq!(Payload::decode(&invite.encode()), invite);
+        assert_eq!(Payload::decode(&leave.encode()), leave);
+    }
+
+    #[test]
+    fn envelopes_name_their_version_and_kind() {
+        let payload = Payload::Reaction(Reaction {
+            message_id: String::from("abc"),
+            emoji: String::from("🎉"),
+            removed: false
+        });
+        let text = payload.encode();
+        let envelope: Envelope = serde_json::from_str(&text[PREFIX.len_utf8()..]).unwrap();
+        assert_eq!(envelope.version, VERSION);
+        assert_eq!(envelope.kind, "reaction");
+    }
+
+    #[test]
+    fn newer_envelopes_are_read_as_far_as_they_are_understood() {
+        let text = "\u{1}{\"version\":7,\"kind\":\"reaction\",\
+                    \"body\":{\"message_id\":\"abc\",\"emoji\":\"👍\",\"skin_tone\":3}}";
+        assert_eq!(Payload::decode(text), Payload::Reaction(Reaction {
+            message_id: String::from("abc"),
+            emoji: String::from("👍"),
+            removed: false
+        }));
+    }
+
+    #[test]
+    fn unknown_kinds_are_unsupported() {
+        let text = "\u{1}{\"version\":2,\"kind\":\"poll\",\"body\":{}}";
+        let payload = Payload::decode(text);
+        assert_eq!(payload, Payload::Unsupported { kind: String::from("poll"), text: String::from(text) });
+        assert_eq!(payload.encode(), text);
+    }
+
+    #[test]
+    fn text_that_isnt_an_envelope_is_plain_text() {
+        let text = "\u{1}{\"reaction\":{}}";
+        assert_eq!(Payload::decode(text), Payload::Text(String::from(text)));
+    }
+}
@@ -0,0 +1,50 @@
+//! Fakes for testing code built on the models without a network.
+
+use comm;
+use comm::address::Address;
+use comm::client::{Event, Task};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::mpsc;
+
+use models::{Connection, EventObserver, Profile, Transport, Update};
+
+/// Keeps the tasks a connection sends instead of running a client.
+#[derive(Default)]
+pub struct FakeTransport {
+    tasks: RefCell<Option<mpsc::Receiver<Task>>>
+}
+
+impl FakeTransport {
+    /// Tasks sent since the last call.
+    pub fn tasks(&self) -> Vec<Task> {
+        self.tasks.borrow().as_ref()
+            .map(|tasks| tasks.try_iter().collect())
+            .unwrap_or_else(|| vec![])
+    }
+}
+
+impl Transport for FakeTransport {
+    fn start(&self, _: Address, _: u16, _: Vec<comm::node::Node>, _: mpsc::Sender<Event>)
+        -> comm::client::TaskSender {
+        let (sender, receiver) = mpsc::channel();
+        *self.tasks.borrow_mut() = Some(receiver);
+        sender
+    }
+}
+
+/// A profile with no routers whose secret is `secret`.
+pub fn profile(secret: &str) -> Profile {
+    let mut profile = Profile::default();
+    profile.update(Some(String::from(secret)), vec![], Some(6667));
+    profile
+}
+
+/// A running connection on a fake transport, whose address is the one for the content `name`.
+pub fn started_connection(name: &str) -> (Rc<RefCell<Connection>>, Rc<FakeTransport>) {
+    let transport = Rc::new(FakeTransport::default());
+    let (connection, _) = Connection::with_transport(name, transport.clone());
+    connection.borrow_mut().start(&profile(name)).unwrap();
+    connection.update(|c| c.handle_event(&connection, Event::Started));
+    (connection, transport)
+}
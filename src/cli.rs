@@ -0,0 +1,438 @@
+use std::cell::RefCell;
+use std::io::{self, BufRead};
+use std::process;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use comm::address::Address;
+use headless::Session;
+use models;
+use models::{ConversationListObserver, ConversationObserver, EncryptionState, MessageObserver, Quote,
+    Subscription, Update, Verification};
+
+const HELP: &str = "\
+Type a message and press Enter to send it to the current conversation.
+  /to ADDRESS   talk to ADDRESS
+  /group ADDRESS ADDRESS...  start a group, whose members are invited with its first message
+  /invite ADDRESS  add ADDRESS to the current group
+  /leave        leave the current group
+  /list         list conversations
+  /go N         switch to conversation N
+  /accept       accept the current message request
+  /ignore       ignore the current message request
+  /block        block the current contact and close the conversation
+  /unblock ADDRESS  let ADDRESS message the current identity again
+  /blocked      list addresses the current identity has blocked
+  /safety       show the safety number to compare with the current contact
+  /verify       mark the current contact's safety number as checked
+  /unencrypted  stop waiting for the current contact to set up encryption and send in plain text
+  /whoami       show the address of each open profile
+  /quit         disconnect and exit";
+
+/// Runs a line-based client on stdin and stdout.
+pub fn run(profiles: Vec<String>) {
+    let mut session = Session::new();
+    if let Err(err) = session.open_all(&profiles) {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+
+    let _printer = Printer::new(session.conversations().clone());
+    print_identities(&session);
+    println!("{}", HELP);
+
+    let (line_sender, lines) = mpsc::channel();
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            match line {
+                Ok(line) => if line_sender.send(line).is_err() { break },
+                Err(_) => break
+            }
+        }
+    });
+
+    loop {
+        session.handle_events(Duration::from_millis(50));
+        match lines.try_recv() {
+            Ok(line) => {
+                if !handle_line(&session, line.trim()) {
+                    break;
+                }
+            }
+            Err(mpsc::TryRecvError::Empty) => { }
+            Err(mpsc::TryRecvError::Disconnected) => break
+        }
+    }
+
+    session.close();
+}
+
+/// Carries out a command or sends a message. Returns false once the user has asked to quit.
+fn handle_line(session: &Session, line: &str) -> bool {
+    let conversations = session.conversations();
+    let mut words = line.splitn(2, ' ');
+    match (words.next().unwrap_or(""), words.next().map(|rest| rest.trim())) {
+        ("", _) => { }
+        ("/quit", _) => return false,
+        ("/help", _) => println!("{}", HELP),
+        ("/whoami", _) => print_identities(session),
+        ("/list", _) => {
+            let conversations = conversations.borrow();
+            for index in 0..conversations.len() {
+                let marker = if conversations.selected() == Some(index) { "*" } else { " " };
+                let conversation = conversations.get(index).unwrap().borrow();
+                let request = if conversation.is_request() { ", request" } else { "" };
+                println!("{} {}. {} (as {}{})",
+                         marker, index + 1, title(&conversation), conversation.identity(), request);
+            }
+        }
+        ("/go", Some(n)) => {
+            match usize::from_str(n) {
+                Ok(n) if n >= 1 && n <= conversations.borrow().len() => {
+                    conversations.update(|c| c.select_conversation(n - 1));
+                }
+                _ => println!("There's no conversation {}", n)
+            }
+        }
+        ("/to", Some(address)) => {
+            match Address::from_str(address) {
+                Ok(address) => talk_to(conversations, address),
+                Err(_) => println!("{} isn't an address", address)
+            }
+        }
+        ("/group", Some(addresses)) => {
+            let members: Result<Vec<Address>, _> = addresses.split_whitespace()
+                .map(Address::from_str)
+                .collect();
+            match members {
+                Ok(members) => {
+                    conversations.update(|c| c.start_new_conversation());
+                    let conversation = conversations.borrow().get(0).cloned();
+                    if let Some(conversation) = conversation {
+                        conversation.update(|c| c.set_members(members));
+                    }
+                }
+                Err(_) => println!("{} aren't all addresses", addresses)
+            }
+        }
+        ("/invite", Some(address)) => {
+            match (Address::from_str(address), selected(conversations)) {
+                (Ok(address), Some(ref conversation)) if conversation.borrow().is_group() => {
+                    conversation.update(|c| c.invite(address));
+                }
+                (Err(_), _) => println!("{} isn't an address", address),
+                _ => println!("This conversation isn't a group")
+            }
+        }
+        ("/leave", _) => {
+            let index = match conversations.borrow().selected() {
+                Some(index) => index,
+                None => return true
+            };
+            if conversations.borrow().get(index).unwrap().borrow().is_group() {
+                conversations.update(|c| c.leave_group(index));
+            } else {
+                println!("This conversation isn't a group");
+            }
+        }
+        (command @ "/accept", _) | (command @ "/ignore", _) | (command @ "/block", _) => {
+            let index = match conversations.borrow().selected() {
+                Some(index) => index,
+                None => return true
+            };
+            let is_request = conversations.borrow().get(index).unwrap().borrow().is_request();
+            match command {
+                "/accept" if is_request => conversations.update(|c| c.accept_request(index)),
+                "/ignore" if is_request => conversations.update(|c| c.remove_conversation(index)),
+                "/block" => conversations.update(|c| c.block_conversation(index)),
+                _ => println!("This conversation isn't a message request")
+            }
+        }
+        ("/unblock", Some(address)) => {
+            match (Address::from_str(address), selected(conversations)) {
+                (Ok(address), Some(conversation)) => {
+                    let connection = conversation.borrow().connection().clone();
+                    connection.update(|c| c.unblock(address));
+                }
+                (Err(_), _) => println!("{} isn't an address", address),
+                _ => { }
+            }
+        }
+        ("/blocked", _) => {
+            if let Some(conversation) = selected(conversations) {
+                let connection = conversation.borrow().connection().clone();
+                for address in connection.borrow().blocked().iter() {
+                    println!("    {}", address.to_str());
+                }
+            }
+        }
+        ("/safety", _) => {
+            match selected(conversations).and_then(|c| c.borrow().safety_number()) {
+                Some(number) => {
+                    for row in number.groups().chunks(4) {
+                        println!("    {}", row.join(" "));
+                    }
+                }
+                None => println!("There's no safety number until encryption is set up")
+            }
+        }
+        ("/verify", _) => {
+            match selected(conversations) {
+                Some(ref conversation) if conversation.borrow().safety_number().is_some() => {
+                    conversation.update(|c| c.set_verified(true));
+                    println!("Marked {} as verified", title(&conversation.borrow()));
+                }
+                _ => println!("There's no safety number until encryption is set up")
+            }
+        }
+        ("/unencrypted", _) => {
+            if let Some(conversation) = selected(conversations) {
+                conversation.update(|c| c.send_unencrypted());
+                println!("Messages to {} are sent unencrypted", title(&conversation.borrow()));
+            }
+        }
+        (command, _) if command.starts_with('/') => println!("Unknown command. /help lists them."),
+        _ => {
+            match selected(conversations) {
+                Some(ref conversation) if conversation.borrow().is_request() => {
+                    println!("Accept the request with /accept before replying")
+                }
+                Some(ref conversation) if !conversation.borrow().recipients().is_empty() => {
+                    conversation.update(|c| {
+                        c.set_pending_message(String::from(line));
+                        c.send_message();
+                    });
+                }
+                _ => println!("Pick someone to talk to with /to ADDRESS first")
+            }
+        }
+    }
+    true
+}
+
+fn selected(conversations: &Rc<RefCell<models::ConversationList>>)
+    -> Option<Rc<RefCell<models::Conversation>>> {
+    let conversations = conversations.borrow();
+    conversations.selected().and_then(|index| conversations.get(index).cloned())
+}
+
+/// Selects the conversation with `address`, starting one if there isn't one yet.
+fn talk_to(conversations: &Rc<RefCell<models::ConversationList>>, address: Address) {
+    let existing = {
+        let conversations = conversations.borrow();
+        (0..conversations.len()).find(|&index| {
+            conversations.get(index).unwrap().borrow().recipient() == Some(address)
+        })
+    };
+
+    match existing {
+        Some(index) => conversations.update(|c| c.select_conversation(index)),
+        None => {
+            conversations.update(|c| c.start_new_conversation());
+            let conversation = conversations.borrow().get(0).cloned();
+            if let Some(conversation) = conversation {
+                conversation.update(|c| c.set_recipient(Some(address)));
+            }
+        }
+    }
+}
+
+fn print_identities(session: &Session) {
+    for connection in session.connections().iter() {
+        let connection = connection.borrow();
+        println!("{} is {}", connection.name(), connection.self_address().to_str());
+    }
+}
+
+fn title(conversation: &models::Conversation) -> String {
+    if conversation.is_group() {
+        let members: Vec<String> = conversation.members().iter().map(|member| member.to_str()).collect();
+        return format!("the group with {}", members.join(", "));
+    }
+    conversation.recipient()
+        .map(|address| address.to_str())
+        .unwrap_or_else(|| String::from("New Conversation"))
+}
+
+/// Prints messages as they arrive in any conversation, and follows the selection.
+struct Printer {
+    conversations: Vec<(Rc<RefCell<models::Conversation>>, Rc<RefCell<ConversationPrinter>>)>,
+    subscriptions: Vec<Subscription>
+}
+
+impl Printer {
+    fn new(conversations: Rc<RefCell<models::ConversationList>>) -> Rc<RefCell<Printer>> {
+        let printer = Rc::new(RefCell::new(Printer {
+            conversations: vec![],
+            subscriptions: vec![]
+        }));
+
+        let len = conversations.borrow().len();
+        for index in 0..len {
+            let conversation = conversations.borrow().get(index).unwrap().clone();
+            printer.borrow_mut().conversation_was_added(conversation);
+        }
+
+        let subscription = models::subscribe(&conversations, printer.clone());
+        printer.borrow_mut().subscriptions.push(subscription);
+
+        printer
+    }
+}
+
+impl ConversationListObserver for Printer {
+    fn conversation_was_added(&mut self, conversation: Rc<RefCell<models::Conversation>>) {
+        let conversation_printer = ConversationPrinter::new(conversation.clone());
+        self.conversations.push((conversation, conversation_printer));
+    }
+
+    fn conversation_was_removed(&mut self, conversation: Rc<RefCell<models::Conversation>>) {
+        self.conversations.retain(|&(ref c, _)| !Rc::ptr_eq(c, &conversation));
+    }
+
+    fn conversation_was_selected(&mut self, conversation: Rc<RefCell<models::Conversation>>) {
+        let conversation = conversation.borrow();
+        println!("Talking to {} as {}", title(&conversation), conversation.identity());
+        if conversation.is_group() && conversation.is_request() {
+            println!("You've been added to {}. /accept to reply, or /block them", title(&conversation));
+        } else if conversation.is_request() {
+            println!("{} isn't in your conversations. /accept to reply, or /block them",
+                     title(&conversation));
+        }
+    }
+
+    fn request_was_accepted(&mut self, _: Rc<RefCell<models::Conversation>>) { }
+}
+
+struct ConversationPrinter {
+    conversation: Rc<RefCell<models::Conversation>>,
+    messages: Vec<Rc<RefCell<MessagePrinter>>>,
+    subscriptions: Vec<Subscription>
+}
+
+impl ConversationPrinter {
+    fn new(conversation: Rc<RefCell<models::Conversation>>) -> Rc<RefCell<ConversationPrinter>> {
+        let printer = Rc::new(RefCell::new(ConversationPrinter {
+            conversation: conversation.clone(),
+            messages: vec![],
+            subscriptions: vec![]
+        }));
+
+        let subscription = models::subscribe(&conversation, printer.clone());
+        printer.borrow_mut().subscriptions.push(subscription);
+
+        printer
+    }
+}
+
+impl ConversationObserver for ConversationPrinter {
+    fn identity_was_changed(&self, _: String) { }
+    fn recipient_was_changed(&self, _: Option<Address>) { }
+    fn pending_message_was_changed(&self, _: String) { }
+    fn reply_was_changed(&self, _: Option<Quote>) { }
+    fn recipient_typing_was_changed(&self, _: bool) { }
+
+    fn encryption_was_changed(&self, encryption: EncryptionState) {
+        if encryption == EncryptionState::Encrypted {
+            let conversation = self.conversation.borrow();
+            println!("[{}] Messages with {} are end-to-end encrypted",
+                     conversation.identity(), title(&conversation));
+        }
+    }
+
+    fn verification_was_changed(&self, verification: Verification) {
+        if verification == Verification::KeyChanged {
+            let conversation = self.conversation.borrow();
+            println!("[{}] Warning: the safety number with {} has changed, see /safety",
+                     conversation.identity(), title(&conversation));
+        }
+    }
+
+    fn members_were_changed(&self, _: Vec<Address>) {
+        let conversation = self.conversation.borrow();
+        println!("[{}] Now talking to {}", conversation.identity(), title(&conversation));
+    }
+
+    fn did_receive_message(&mut self, message: Rc<RefCell<models::Message>>) {
+        {
+            let conversation = self.conversation.borrow();
+            let message = message.borrow();
+            let warning = if message.is_authentic() { "" } else { " (may not be from them)" };
+            // In a group, say who in it the message is from
+            let from = match message.sender() {
+                Some(sender) if conversation.is_group() => {
+                    format!("{} in {}", sender.to_str(), title(&conversation))
+                }
+                _ => title(&conversation)
+            };
+            println!("[{}] {}{}: {}", conversation.identity(), from, warning, message.text());
+        }
+        self.messages.push(MessagePrinter::new(message));
+    }
+
+    fn did_send_message(&mut self, message: Rc<RefCell<models::Message>>) {
+        self.messages.push(MessagePrinter::new(message));
+    }
+}
+
+/// Reports when a message is acknowledged, read, reacted to, edited or deleted.
+struct MessagePrinter {
+    text: RefCell<String>,
+    subscriptions: Vec<Subscription>
+}
+
+impl MessagePrinter {
+    fn new(message: Rc<RefCell<models::Message>>) -> Rc<RefCell<MessagePrinter>> {
+        let printer = Rc::new(RefCell::new(MessagePrinter {
+            text: RefCell::new(String::from(message.borrow().text())),
+            subscriptions: vec![]
+        }));
+
+        let subscription = models::subscribe(&message, printer.clone());
+        printer.borrow_mut().subscriptions.push(subscription);
+
+        printer
+    }
+}
+
+impl MessageObserver for MessagePrinter {
+    fn did_receieve_acknowledgement(&self) {
+        println!("Delivered: {}", self.text.borrow());
+    }
+
+    fn reactions_were_changed(&self, reactions: Vec<(String, usize)>) {
+        let reactions: Vec<String> = reactions.iter()
+            .map(|&(ref emoji, count)| format!("{} {}", emoji, count))
+            .collect();
+        println!("Reactions to {}: {}", self.text.borrow(), reactions.join(" "));
+    }
+
+    fn text_was_edited(&self, text: String) {
+        println!("Edited: {} (was {})", text, self.text.borrow());
+        *self.text.borrow_mut() = text;
+    }
+
+    fn was_deleted(&self) {
+        println!("Deleted: {}", self.text.borrow());
+    }
+
+    fn did_receive_read_receipt(&self) {
+        println!("Read: {}", self.text.borrow());
+    }
+
+    fn hidden_count_was_changed(&self, count: usize) {
+        println!("{}", models::hidden_text(count));
+    }
+
+    fn acknowledgements_were_changed(&self, acknowledged: usize, members: usize) {
+        println!("Delivered to {} of {}: {}", acknowledged, members, self.text.borrow());
+    }
+
+    fn did_fail(&self) {
+        println!("Not sent, encryption couldn't be set up: {}", self.text.borrow());
+    }
+}
@@ -3,46 +3,79 @@ use glib;
 use gdk;
 use gtk::prelude::*;
 use gtk;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::path;
-use std::rc::Rc;
+use std::rc::{Rc, Weak};
 use std::str::FromStr;
 
 use comm::address;
 use comm;
 
+use keybindings::{self, Keybindings};
 use models;
-use models::{ConnectionObserver, ConversationListObserver, ConversationObserver, MessageObserver,
-    Observable};
+use models::{ConnectionObserver, ConversationListObserver, ConversationObserver, EncryptionState,
+    MessageObserver, Quote, Subscription, Update, Verification};
 
 pub struct Configuration {
     view: gtk::Window,
-    connect_button: gtk::Button
+    connect_button: gtk::Button,
+    connection: Rc<RefCell<models::Connection>>,
+    blocklist: gtk::ListBox,
+    subscriptions: Vec<Subscription>
 }
 
 impl Configuration {
-    pub fn new(connection: Rc<RefCell<models::Connection>>,
-               configuration: Rc<RefCell<models::Configuration>>,
-               config_file_path: path::PathBuf) -> Rc<RefCell<Configuration>> {
+    /// Configures the profile the connection's identity belongs to. `on_profile_selected` is
+    /// called with the name of a profile when the user switches this identity to it, either by
+    /// picking an existing one or by entering a new name. `on_profile_opened` is called when the
+    /// user asks to go online as that profile alongside this one. `conversations` are told when
+    /// the identity starts or stops encrypting.
+    pub fn new<F, G>(connection: Rc<RefCell<models::Connection>>,
+                     conversations: Rc<RefCell<models::ConversationList>>,
+                     configuration: Rc<RefCell<models::Configuration>>,
+                     config_file_path: path::PathBuf,
+                     on_profile_selected: F,
+                     on_profile_opened: G) -> Rc<RefCell<Configuration>>
+        where F: Fn(String) + 'static, G: Fn(String) + 'static {
         // Build UI
 
+        let profile_name = String::from(connection.borrow().name());
+
         let view = gtk::Window::new(gtk::WindowType::Toplevel);
-        view.set_title("Configuration");
+        view.set_title(&format!("Configuration ({})", profile_name));
         view.set_position(gtk::WindowPosition::Center);
         let grid = gtk::Grid::new();
 
+        let profile_label = gtk::Label::new("Profile");
+        profile_label.set_halign(gtk::Align::Start);
+        let profile_combo = gtk::ComboBoxText::new_with_entry();
+        let open_profile_button = gtk::Button::new_from_icon_name("list-add", 2);
+        open_profile_button.set_tooltip_text("Also go online as this profile");
+        let profile_box = gtk::Box::new(gtk::Orientation::Horizontal, 0);
+        profile_box.pack_start(&profile_combo, true, true, 0);
+        profile_box.pack_start(&open_profile_button, false, false, 0);
+        grid.attach(&profile_label, 0, 0, 100, 12);
+        grid.attach_next_to(&profile_box,
+                            Some(&profile_label),
+                            gtk::PositionType::Right,
+                            100, 12);
+
         let secret_label = gtk::Label::new("Your secret");
         secret_label.set_halign(gtk::Align::Start);
         let secret_entry = gtk::Entry::new();
-        grid.attach(&secret_label, 0, 0, 100, 12);
+        grid.attach_next_to(&secret_label,
+                            Some(&profile_label),
+                            gtk::PositionType::Bottom,
+                            100, 12);
         grid.attach_next_to(&secret_entry,
                             Some(&secret_label),
                             gtk::PositionType::Right,
                             100, 12);
 
-        let bootstrap_label = gtk::Label::new("Bootstrap node");
+        let bootstrap_label = gtk::Label::new("Bootstrap nodes");
         bootstrap_label.set_halign(gtk::Align::Start);
         let bootstrap_entry = gtk::Entry::new();
+        bootstrap_entry.set_placeholder_text("IP:port, IP:port");
         grid.attach_next_to(&bootstrap_label,
                             Some(&secret_label),
                             gtk::PositionType::Bottom,
@@ -64,6 +97,49 @@ impl Configuration {
                             gtk::PositionType::Right,
                             100, 12);
 
+        let typing_check = gtk::CheckButton::new_with_label("Tell recipients when I'm typing");
+        grid.attach_next_to(&typing_check,
+                            Some(&port_label),
+                            gtk::PositionType::Bottom,
+                            200, 12);
+
+        let read_receipts_check = gtk::CheckButton::new_with_label("Send read receipts");
+        grid.attach_next_to(&read_receipts_check,
+                            Some(&typing_check),
+                            gtk::PositionType::Bottom,
+                            200, 12);
+
+        let encryption_check = gtk::CheckButton::new_with_label("Encrypt messages end to end");
+        encryption_check.set_tooltip_text("Turn off to talk to comm clients that only read plain text");
+        grid.attach_next_to(&encryption_check,
+                            Some(&read_receipts_check),
+                            gtk::PositionType::Bottom,
+                            200, 12);
+
+        let signing_check = gtk::CheckButton::new_with_label("Sign messages");
+        signing_check.set_tooltip_text("Lets recipients check that messages are from you. \
+                                        Turn off to talk to comm clients that only read plain text");
+        grid.attach_next_to(&signing_check,
+                            Some(&encryption_check),
+                            gtk::PositionType::Bottom,
+                            200, 12);
+
+        let blocklist_label = gtk::Label::new("Blocked");
+        blocklist_label.set_halign(gtk::Align::Start);
+        blocklist_label.set_valign(gtk::Align::Start);
+        let blocklist = gtk::ListBox::new();
+        blocklist.set_selection_mode(gtk::SelectionMode::None);
+        blocklist.set_placeholder(Some(&gtk::Label::new("Nobody")));
+        blocklist.get_style_context().unwrap().add_class("configuration__blocklist");
+        grid.attach_next_to(&blocklist_label,
+                            Some(&signing_check),
+                            gtk::PositionType::Bottom,
+                            100, 12);
+        grid.attach_next_to(&blocklist,
+                            Some(&blocklist_label),
+                            gtk::PositionType::Right,
+                            100, 12);
+
         let connect_button = gtk::Button::new_with_label("Connect");
 
         let container = gtk::Box::new(gtk::Orientation::Vertical, 0);
@@ -78,40 +154,143 @@ impl Configuration {
 
         // Load view state from config
 
-        if let &Some(ref secret) = configuration.borrow().secret() {
-            secret_entry.set_text(secret);
+        for (index, name) in configuration.borrow().profile_names().iter().enumerate() {
+            profile_combo.append_text(name);
+            if *name == profile_name {
+                profile_combo.set_active(index as i32);
+            }
         }
 
-        if let &Some(ref router) = configuration.borrow().router() {
-            bootstrap_entry.set_text(router);
+        let profile = configuration.borrow().profile(&profile_name);
+
+        if let &Some(ref secret) = profile.secret() {
+            secret_entry.set_text(secret);
         }
 
-        if let &Some(ref port) = configuration.borrow().port() {
+        bootstrap_entry.set_text(&profile.routers().join(", "));
+
+        if let &Some(ref port) = profile.port() {
             port_entry.set_text(&port.to_string());
         }
 
+        typing_check.set_active(profile.sends_typing_indicators());
+        read_receipts_check.set_active(profile.sends_read_receipts());
+        encryption_check.set_active(profile.encrypts_messages());
+        signing_check.set_active(profile.signs_messages());
+
         // Connect view event signals
 
+        let on_profile_selected = Rc::new(on_profile_selected);
+
+        let selected = on_profile_selected.clone();
+        let current_profile = profile_name.clone();
+        profile_combo.connect_changed(move |combo| {
+            // Typing a new name also emits changed, but without an active item
+            if combo.get_active() >= 0 {
+                if let Some(name) = combo.get_active_text() {
+                    if name != current_profile {
+                        selected(name);
+                    }
+                }
+            }
+        });
+
+        let combo = profile_combo.clone();
+        open_profile_button.connect_clicked(move |_| {
+            if let Some(name) = combo.get_active_text() {
+                let name = name.trim();
+                if !name.is_empty() {
+                    on_profile_opened(String::from(name));
+                }
+            }
+        });
+
+        if let Some(profile_entry) = profile_combo.get_child()
+            .and_then(|child| child.downcast::<gtk::Entry>().ok()) {
+            let selected = on_profile_selected.clone();
+            profile_entry.connect_activate(move |entry| {
+                if let Some(name) = entry.get_text() {
+                    let name = name.trim();
+                    if !name.is_empty() {
+                        selected(String::from(name));
+                    }
+                }
+            });
+        }
+
+        let conn = connection.clone();
+        let conf = configuration.clone();
+        let name = profile_name.clone();
+        let path = config_file_path.clone();
+        typing_check.connect_toggled(move |check| {
+            let sends = check.get_active();
+            conf.borrow_mut().profile_mut(&name).set_sends_typing_indicators(sends);
+            conf.borrow().save(path.clone()).expect("Failed to save configuration");
+            conn.update(|c| c.set_sends_typing_indicators(sends));
+        });
+
+        let conn = connection.clone();
+        let conf = configuration.clone();
+        let name = profile_name.clone();
+        let path = config_file_path.clone();
+        read_receipts_check.connect_toggled(move |check| {
+            let sends = check.get_active();
+            conf.borrow_mut().profile_mut(&name).set_sends_read_receipts(sends);
+            conf.borrow().save(path.clone()).expect("Failed to save configuration");
+            conn.update(|c| c.set_sends_read_receipts(sends));
+        });
+
+        let conn = connection.clone();
+        let conf = configuration.clone();
+        let name = profile_name.clone();
+        let path = config_file_path.clone();
+        encryption_check.connect_toggled(move |check| {
+            let encrypts = check.get_active();
+            conf.borrow_mut().profile_mut(&name).set_encrypts_messages(encrypts);
+            conf.borrow().save(path.clone()).expect("Failed to save configuration");
+            conversations.update(|c| c.set_encrypts_messages(&conn, encrypts));
+        });
+
+        let conn = connection.clone();
+        let conf = configuration.clone();
+        let name = profile_name.clone();
+        let path = config_file_path.clone();
+        signing_check.connect_toggled(move |check| {
+            let signs = check.get_active();
+            conf.borrow_mut().profile_mut(&name).set_signs_messages(signs);
+            conf.borrow().save(path.clone()).expect("Failed to save configuration");
+            conn.update(|c| c.set_signs_messages(signs));
+        });
+
         let conn = connection.clone();
         let conf = configuration.clone();
         connect_button.connect_clicked(move |button| {
             let state = conn.borrow().state();
             match state {
                 models::ConnectionState::Stopped => {
-                    conf.borrow_mut().update(
+                    let routers = bootstrap_entry.get_text().map(|text| {
+                        text.split(',')
+                            .map(|router| router.trim())
+                            .filter(|router| !router.is_empty())
+                            .map(String::from)
+                            .collect()
+                    }).unwrap_or_else(|| vec![]);
+
+                    conf.borrow_mut().profile_mut(&profile_name).update(
                         secret_entry.get_text(),
-                        bootstrap_entry.get_text(),
+                        routers,
                         port_entry.get_text().and_then(|port| u16::from_str(port.as_str()).ok())
                         );
                     conf.borrow().save(config_file_path.clone()).expect("Failed to save configuration");
 
-                    if let Ok(_) = conn.borrow_mut().start(conf.borrow()) {
+                    let profile = conf.borrow().profile(&profile_name);
+                    if let Ok(_) = conn.update(|c| c.start(&profile)) {
                         button.set_sensitive(false);
                     };
                 }
 
                 models::ConnectionState::Running => {
-                    conn.borrow_mut().shutdown();
+                    conn.update(|c| c.shutdown());
                     button.set_sensitive(false);
                 }
 
@@ -122,12 +301,18 @@ impl Configuration {
 
         let controller = Rc::new(RefCell::new(Configuration {
             view: view,
-            connect_button: connect_button
+            connect_button: connect_button,
+            connection: connection.clone(),
+            blocklist: blocklist,
+            subscriptions: vec![]
         }));
+        let blocked = connection.borrow().blocked().clone();
+        controller.borrow_mut().blocklist_was_changed(blocked);
 
         // Ovserve connection model
 
-        connection.borrow_mut().register_observer(controller.clone());
+        let subscription = models::subscribe(&connection, controller.clone());
+        controller.borrow_mut().subscriptions.push(subscription);
 
         controller
     }
@@ -138,21 +323,43 @@ impl Configuration {
 }
 
 impl ConnectionObserver for Configuration {
-    fn connection_started(&mut self, _connection: &models::Connection) {
+    fn connection_started(&mut self, _self_address: address::Address) {
         self.connect_button.set_label("Disconnect");
         self.connect_button.set_sensitive(true);
     }
 
-    fn connection_shutdown(&mut self, _connection: &models::Connection) {
+    fn connection_shutdown(&mut self) {
         self.connect_button.set_label("Connect");
         self.connect_button.set_sensitive(true);
     }
+
+    fn blocklist_was_changed(&mut self, blocked: Vec<address::Address>) {
+        for row in self.blocklist.get_children() {
+            row.destroy();
+        }
+        for address in blocked {
+            let row = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+            let label = gtk::Label::new(Some(address.to_str().as_str()));
+            label.set_halign(gtk::Align::Start);
+            label.set_selectable(true);
+            let unblock = gtk::Button::new_with_label("Unblock");
+            let c = self.connection.clone();
+            unblock.connect_clicked(move |_| {
+                c.update(|c| c.unblock(address));
+            });
+            row.pack_start(&label, true, true, 0);
+            row.pack_start(&unblock, false, false, 0);
+            self.blocklist.add(&row);
+        }
+        self.blocklist.show_all();
+    }
 }
 
 struct ConnectionStatus {
     view: gtk::Box,
     self_address_entry: gtk::Entry,
-    self_address: Option<String>
+    self_address: Option<String>,
+    subscriptions: Vec<Subscription>
 }
 
 impl ConnectionStatus {
@@ -160,7 +367,9 @@ impl ConnectionStatus {
         let view = gtk::Box::new(gtk::Orientation::Horizontal, 0);
         let self_address_entry = gtk::Entry::new();
         self_address_entry.set_sensitive(false);
-        self_address_entry.set_tooltip_text("Your address");
+        self_address_entry.set_placeholder_text(connection.borrow().name());
+        self_address_entry.set_tooltip_text(
+            format!("Your address as {}", connection.borrow().name()).as_str());
         let copy_self_address = gtk::Button::new_from_icon_name("edit-copy", 2);
         copy_self_address.set_tooltip_text("Copy your address to clipboard");
         view.pack_start(&self_address_entry, true, true, 0);
@@ -169,20 +378,24 @@ impl ConnectionStatus {
         let controller = Rc::new(RefCell::new(Self {
             view: view,
             self_address_entry: self_address_entry,
-            self_address: None
+            self_address: None,
+            subscriptions: vec![]
         }));
 
         // Connect view event signals
 
-        let c = controller.clone();
+        let c = Rc::downgrade(&controller);
         copy_self_address.connect_clicked(move |_| {
-            if let Some(ref self_address) = c.borrow().self_address {
-                let clipboard = gtk::Clipboard::get(&gdk::Atom::intern("CLIPBOARD"));
-                clipboard.set_text(&self_address);
+            if let Some(c) = c.upgrade() {
+                if let Some(ref self_address) = c.borrow().self_address {
+                    let clipboard = gtk::Clipboard::get(&gdk::Atom::intern("CLIPBOARD"));
+                    clipboard.set_text(&self_address);
+                }
             }
         });
 
-        connection.borrow_mut().register_observer(controller.clone());
+        let subscription = models::subscribe(&connection, controller.clone());
+        controller.borrow_mut().subscriptions.push(subscription);
 
         controller
     }
@@ -193,26 +406,63 @@ impl ConnectionStatus {
 }
 
 impl ConnectionObserver for ConnectionStatus {
-    fn connection_started(&mut self, connection: &models::Connection) {
-        let self_address = connection.self_address().to_str();
+    fn connection_started(&mut self, self_address: address::Address) {
+        let self_address = self_address.to_str();
         self.self_address_entry.set_text(&self_address);
         self.self_address = Some(self_address);
     }
 
-    fn connection_shutdown(&mut self, _connection: &models::Connection) {
+    fn connection_shutdown(&mut self) {
     }
+
+    fn blocklist_was_changed(&mut self, _: Vec<address::Address>) { }
 }
 
 pub struct ConversationRecipient {
     view: gtk::Box,
+    fields: gtk::Box,
+    encryption: gtk::Image,
+    send_unencrypted: gtk::Button,
+    verify: gtk::Button,
     entry: gtk::Entry,
+    identity: gtk::ComboBoxText,
     label: gtk::Label,
-    conversation_has_started: bool
+    conversation_has_started: bool,
+    subscriptions: Vec<Subscription>
 }
 
 impl ConversationRecipient {
-    pub fn new(conversation: Rc<RefCell<models::Conversation>>) -> Rc<RefCell<ConversationRecipient>> {
-        let view = gtk::Box::new(gtk::Orientation::Vertical, 0);
+    /// `identities` are the connections the conversation can be held as. When there's more than
+    /// one, a picker is shown until the conversation starts.
+    pub fn new(conversation: Rc<RefCell<models::Conversation>>,
+               identities: Vec<Rc<RefCell<models::Connection>>>) -> Rc<RefCell<ConversationRecipient>> {
+        let view = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+        let fields = gtk::Box::new(gtk::Orientation::Vertical, 0);
+        fields.set_hexpand(true);
+        let encryption = gtk::Image::new();
+        encryption.get_style_context().unwrap().add_class("conversation_recipient__encryption");
+        show_encryption(&encryption, conversation.borrow().encryption());
+        // For recipients that never answer the key exchange, such as clients that only read plain text
+        let send_unencrypted = gtk::Button::new_with_label("Send Unencrypted");
+        send_unencrypted.set_tooltip_text("Stop waiting for encryption to be set up");
+        send_unencrypted.set_no_show_all(true);
+        send_unencrypted.set_visible(conversation.borrow().encryption() == EncryptionState::Pending);
+        let c = conversation.clone();
+        send_unencrypted.connect_clicked(move |_| c.update(|c| c.send_unencrypted()));
+        let verify = gtk::Button::new_from_icon_name("security-high-symbolic", 2);
+        verify.get_style_context().unwrap().add_class("conversation_recipient__verify");
+        verify.set_sensitive(conversation.borrow().encryption() == EncryptionState::Encrypted);
+        show_verification(&verify, conversation.borrow().verification());
+        let c = conversation.clone();
+        verify.connect_clicked(move |button| show_contact_verification(&c, button));
+        let identity = gtk::ComboBoxText::new();
+        identity.set_tooltip_text("Send as");
+        for (index, connection) in identities.iter().enumerate() {
+            identity.append_text(connection.borrow().name());
+            if Rc::ptr_eq(connection, conversation.borrow().connection()) {
+                identity.set_active(index as i32);
+            }
+        }
         let entry = gtk::Entry::new();
         let label = gtk::Label::new(None);
         let style = label.get_style_context().unwrap();
@@ -227,42 +477,100 @@ impl ConversationRecipient {
                 entry.set_text("New Conversation");
             }
         }
+        // Groups can't be verified as a whole, only with each member on their own
+        verify.set_no_show_all(true);
+        if conversation.borrow().is_group() {
+            let members = members_text(conversation.borrow().members());
+            entry.set_text(&members);
+            label.set_text(&members);
+        } else {
+            verify.show();
+        }
 
         let c = conversation.clone();
         entry.connect_changed(move |entry| {
             let text = entry.get_text().unwrap();
-            if text.len() == 40 {
+            // Several addresses, separated by commas, start a group
+            let addresses: Vec<&str> = text.split(',').map(|address| address.trim()).collect();
+            if addresses.len() > 1 {
+                let members: Option<Vec<address::Address>> = addresses.iter()
+                    .map(|address| {
+                        if address.len() == 40 { address::Address::from_str(address).ok() } else { None }
+                    })
+                    .collect();
+                if let Some(members) = members {
+                    // The user is in the group anyway, and shouldn't be sent their own messages
+                    let self_address = c.borrow().connection().borrow().started_address();
+                    let members = members.into_iter()
+                        .filter(|&member| Some(member) != self_address)
+                        .collect();
+                    c.update(|c| c.set_members(members));
+                }
+            } else if text.len() == 40 {
                 let address = address::Address::from_str(&text).ok();
-                c.borrow_mut().set_recipient(address);
+                c.update(|c| c.set_recipient(address));
+            }
+        });
+
+        let c = conversation.clone();
+        identity.connect_changed(move |identity| {
+            let active = identity.get_active();
+            if active >= 0 {
+                if let Some(connection) = identities.get(active as usize) {
+                    c.update(|c| c.set_connection(connection.clone()));
+                }
             }
         });
 
-        if conversation.borrow().has_started() {
-            view.add(&label);
+        let conversation_has_started = conversation.borrow().has_started();
+        if conversation_has_started {
+            fields.add(&label);
         } else {
-            view.add(&entry);
+            if identity.get_model().map(|model| model.iter_n_children(None) > 1).unwrap_or(false) {
+                fields.add(&identity);
+            }
+            fields.add(&entry);
         }
+        view.add(&fields);
+        view.add(&encryption);
+        view.add(&send_unencrypted);
+        view.add(&verify);
 
         let controller = Rc::new(RefCell::new(ConversationRecipient {
             view: view,
+            fields: fields,
+            encryption: encryption,
+            send_unencrypted: send_unencrypted,
+            verify: verify,
             entry: entry,
+            identity: identity,
             label: label,
-            conversation_has_started: false
+            conversation_has_started: conversation_has_started,
+            subscriptions: vec![]
         }));
 
-        let observer_id = conversation.borrow_mut().register_observer(controller.clone());
-        controller.borrow().view().connect_destroy(move |_| {
-            conversation.borrow_mut().deregister_observer(&observer_id);
-        });
+        let subscription = models::subscribe(&conversation, controller.clone());
+        controller.borrow_mut().subscriptions.push(subscription);
 
         controller
     }
 
+    pub fn focus(&self) {
+        if self.conversation_has_started {
+            return;
+        }
+        self.entry.grab_focus();
+        self.entry.select_region(0, -1);
+    }
+
     fn disable_recipient_editing(&mut self) {
         if !self.conversation_has_started {
-            self.view.remove(&self.entry);
-            self.view.add(&self.label);
-            self.view.show_all();
+            if self.identity.get_parent().is_some() {
+                self.fields.remove(&self.identity);
+            }
+            self.fields.remove(&self.entry);
+            self.fields.add(&self.label);
+            self.fields.show_all();
             self.conversation_has_started = true;
         }
     }
@@ -272,7 +580,60 @@ impl ConversationRecipient {
     }
 }
 
+/// The members of a group, as shown in place of a recipient.
+fn members_text(members: &[comm::address::Address]) -> String {
+    members.iter().map(|member| member.to_str()).collect::<Vec<String>>().join(", ")
+}
+
+/// Shows a lock on `image` saying whether messages to the recipient are end-to-end encrypted.
+fn show_encryption(image: &gtk::Image, encryption: EncryptionState) {
+    let (icon, tooltip) = match encryption {
+        EncryptionState::Encrypted => {
+            ("channel-secure-symbolic", "Messages are end-to-end encrypted")
+        }
+        EncryptionState::Pending => {
+            ("content-loading-symbolic", "Setting up encryption. Messages are sent once the recipient answers")
+        }
+        EncryptionState::Unencrypted => {
+            ("channel-insecure-symbolic", "Messages aren't end-to-end encrypted yet")
+        }
+    };
+    image.set_from_icon_name(icon, 2);
+    image.set_tooltip_text(tooltip);
+}
+
+/// Marks `button`, which opens the safety number, with whether the recipient has been verified.
+fn show_verification(button: &gtk::Button, verification: Verification) {
+    let style = button.get_style_context().unwrap();
+    style.remove_class("conversation_recipient__verify--verified");
+    style.remove_class("conversation_recipient__verify--key_changed");
+    match verification {
+        Verification::Verified => {
+            style.add_class("conversation_recipient__verify--verified");
+            button.set_tooltip_text("Safety number verified");
+        }
+        Verification::KeyChanged => {
+            style.add_class("conversation_recipient__verify--key_changed");
+            button.set_tooltip_text("Safety number changed");
+        }
+        Verification::Unverified => button.set_tooltip_text("Verify safety number")
+    }
+}
+
+/// Opens the safety number of `conversation` over the window holding `widget`.
+fn show_contact_verification<W: IsA<gtk::Widget>>(conversation: &Rc<RefCell<models::Conversation>>,
+                                                  widget: &W) {
+    let verification = ContactVerification::new(conversation.clone());
+    let view = verification.borrow().view().clone();
+    if let Some(window) = widget.get_toplevel().and_then(|toplevel| toplevel.downcast::<gtk::Window>().ok()) {
+        view.set_transient_for(&window);
+    }
+    view.show_all();
+}
+
 impl ConversationObserver for ConversationRecipient {
+    fn identity_was_changed(&self, _: String) { }
+
     fn recipient_was_changed(&self, address: Option<comm::address::Address>) {
         match address {
             Some(a) => {
@@ -283,9 +644,27 @@ impl ConversationObserver for ConversationRecipient {
                 self.entry.set_text("New Conversation")
             }
         }
+        self.verify.show();
     }
 
     fn pending_message_was_changed(&self, _: String) { }
+    fn reply_was_changed(&self, _: Option<Quote>) { }
+    fn recipient_typing_was_changed(&self, _: bool) { }
+
+    fn encryption_was_changed(&self, encryption: EncryptionState) {
+        show_encryption(&self.encryption, encryption);
+        self.send_unencrypted.set_visible(encryption == EncryptionState::Pending);
+        self.verify.set_sensitive(encryption == EncryptionState::Encrypted);
+    }
+
+    fn verification_was_changed(&self, verification: Verification) {
+        show_verification(&self.verify, verification);
+    }
+
+    fn members_were_changed(&self, members: Vec<comm::address::Address>) {
+        self.label.set_text(&members_text(&members));
+        self.verify.hide();
+    }
 
     fn did_receive_message(&mut self, _: Rc<RefCell<models::Message>>) {
         self.disable_recipient_editing();
@@ -296,8 +675,93 @@ impl ConversationObserver for ConversationRecipient {
     }
 }
 
+/// The safety number of a conversation, for comparing with the one the recipient sees, and a
+/// check box for marking the recipient as verified once they match.
+pub struct ContactVerification {
+    view: gtk::Window
+}
+
+impl ContactVerification {
+    pub fn new(conversation: Rc<RefCell<models::Conversation>>) -> Rc<RefCell<ContactVerification>> {
+        let view = gtk::Window::new(gtk::WindowType::Toplevel);
+        view.set_title("Verify Safety Number");
+        view.set_position(gtk::WindowPosition::CenterOnParent);
+        view.set_resizable(false);
+        view.set_modal(true);
+
+        let container = gtk::Box::new(gtk::Orientation::Vertical, 12);
+        container.set_border_width(12);
+
+        let recipient = conversation.borrow().recipient()
+            .map(|address| address.to_str())
+            .unwrap_or_default();
+        let safety_number = conversation.borrow().safety_number();
+        match safety_number {
+            Some(number) => {
+                let explanation = gtk::Label::new(Some(format!(
+                    "Compare these with the safety number {} sees. If they match, nobody is in the \
+                     middle of your conversation.", recipient).as_str()));
+                explanation.set_line_wrap(true);
+                explanation.set_max_width_chars(40);
+                container.add(&explanation);
+
+                let rows: Vec<String> = number.groups().chunks(4).map(|row| row.join(" ")).collect();
+                let digits = gtk::Label::new(Some(rows.join("\n").as_str()));
+                digits.set_selectable(true);
+                digits.get_style_context().unwrap().add_class("verification__digits");
+                container.add(&digits);
+
+                let grid = gtk::Grid::new();
+                grid.set_halign(gtk::Align::Center);
+                grid.get_style_context().unwrap().add_class("verification__grid");
+                for row in 0..models::SafetyNumber::GRID_SIZE {
+                    for column in 0..models::SafetyNumber::GRID_SIZE {
+                        let cell = gtk::Box::new(gtk::Orientation::Horizontal, 0);
+                        cell.set_size_request(8, 8);
+                        let style = cell.get_style_context().unwrap();
+                        style.add_class("verification__cell");
+                        if number.is_filled(row, column) {
+                            style.add_class("verification__cell--filled");
+                        }
+                        grid.attach(&cell, column as i32, row as i32, 1, 1);
+                    }
+                }
+                container.add(&grid);
+
+                let verified = gtk::CheckButton::new_with_label("Mark as verified");
+                verified.set_active(conversation.borrow().verification() == models::Verification::Verified);
+                let c = conversation.clone();
+                verified.connect_toggled(move |check| {
+                    let verified = check.get_active();
+                    c.update(|c| c.set_verified(verified));
+                });
+                container.add(&verified);
+            }
+
+            None => {
+                let explanation = gtk::Label::new(
+                    Some("There's no safety number until encryption is set up with the recipient."));
+                container.add(&explanation);
+            }
+        }
+
+        view.add(&container);
+
+        Rc::new(RefCell::new(ContactVerification {
+            view: view
+        }))
+    }
+
+    pub fn view(&self) -> &gtk::Window {
+        &self.view
+    }
+}
+
+/// Shows how far a sent message has got: acknowledged by the network, then read by the recipient.
 pub struct MessageStatus {
-    view: gtk::Label
+    view: gtk::Label,
+    read: Cell<bool>,
+    subscriptions: Vec<Subscription>
 }
 
 impl MessageStatus {
@@ -308,14 +772,27 @@ impl MessageStatus {
         style.add_class("message__status");
 
         let controller = Rc::new(RefCell::new(MessageStatus {
-            view: view
+            view: view,
+            read: Cell::new(false),
+            subscriptions: vec![]
         }));
 
+        let (acknowledged, members) = message.borrow().acknowledgements();
+        if acknowledged > 0 {
+            controller.borrow().acknowledgements_were_changed(acknowledged, members);
+        }
         if message.borrow().acknowledged() {
             controller.borrow().did_receieve_acknowledgement();
         }
+        if message.borrow().was_sent() && message.borrow().is_read() {
+            controller.borrow().did_receive_read_receipt();
+        }
+        if message.borrow().has_failed() {
+            controller.borrow().did_fail();
+        }
 
-        message.borrow_mut().register_observer(controller.clone());
+        let subscription = models::subscribe(&message, controller.clone());
+        controller.borrow_mut().subscriptions.push(subscription);
 
         controller
     }
@@ -327,17 +804,58 @@ impl MessageStatus {
 
 impl MessageObserver for MessageStatus {
     fn did_receieve_acknowledgement(&self) {
-        self.view().set_text("Acknowledged");
+        // The acknowledgement can arrive after the read receipt
+        if !self.read.get() {
+            self.view().set_text("Acknowledged");
+        }
+    }
+
+    fn reactions_were_changed(&self, _: Vec<(String, usize)>) { }
+    fn text_was_edited(&self, _: String) { }
+    fn was_deleted(&self) { }
+
+    fn did_receive_read_receipt(&self) {
+        self.read.set(true);
+        self.view().set_text("Read");
+    }
+
+    fn hidden_count_was_changed(&self, _: usize) { }
+
+    fn acknowledgements_were_changed(&self, acknowledged: usize, members: usize) {
+        if !self.read.get() {
+            self.view().set_text(&format!("Acknowledged by {} of {}", acknowledged, members));
+        }
+    }
+
+    fn did_fail(&self) {
+        self.view().set_text("Not sent: encryption couldn't be set up");
     }
 }
 
+/// Emoji offered by the reaction picker.
+const REACTIONS: &[&str] = &["👍", "❤️", "😂", "😮", "😢", "🎉"];
+
 pub struct Message {
-    view: gtk::Box
+    view: gtk::EventBox,
+    id: String,
+    quote: Option<gtk::Button>,
+    text: gtk::Label,
+    edited: gtk::Button,
+    reactions: gtk::Box,
+    #[allow(dead_code)]
+    status: Rc<RefCell<MessageStatus>>,
+    subscriptions: Vec<Subscription>
 }
 
 impl Message {
-    pub fn new(message: Rc<RefCell<models::Message>>) -> Rc<RefCell<Message>> {
-        let view = gtk::Box::new(gtk::Orientation::Vertical, 0);
+    /// `on_quote_clicked` is called with the id of the message this one replies to when the user
+    /// clicks the quote of it.
+    pub fn new<F>(conversation: Rc<RefCell<models::Conversation>>,
+                  message: Rc<RefCell<models::Message>>,
+                  on_quote_clicked: F) -> Rc<RefCell<Message>>
+        where F: Fn(String) + 'static {
+        let view = gtk::EventBox::new();
+        let bubble = gtk::Box::new(gtk::Orientation::Vertical, 0);
         let style = view.get_style_context().unwrap();
 
         style.add_class("message");
@@ -348,6 +866,27 @@ impl Message {
             view.set_halign(gtk::Align::Start);
             style.add_class("message--received");
         }
+        if !message.borrow().is_supported() {
+            style.add_class("message--unsupported");
+        }
+        if message.borrow().hidden_count() > 0 {
+            style.add_class("message--hidden");
+            view.set_tooltip_text("These messages were sent faster than allowed, so they aren't shown");
+        } else if !message.borrow().is_authentic() {
+            style.add_class("message--unauthentic");
+            view.set_tooltip_text("This message may not be from who it claims, as it isn't signed by them");
+        }
+
+        let quote = message.borrow().quote().cloned().map(|quote| {
+            let button = gtk::Button::new_with_label(&quote.excerpt);
+            button.set_relief(gtk::ReliefStyle::None);
+            button.set_halign(gtk::Align::Start);
+            button.set_tooltip_text("Show the original message");
+            let quote_style = button.get_style_context().unwrap();
+            quote_style.add_class("message__quote");
+            button.connect_clicked(move |_| on_quote_clicked(quote.message_id.clone()));
+            button
+        });
 
         let text = gtk::Label::new(Some(message.borrow().text()));
         text.set_line_wrap(true);
@@ -355,25 +894,207 @@ impl Message {
         text_style.add_class("message__text");
         text.set_halign(gtk::Align::Start);
 
+        // Replaces the text while the user edits it
+        let edit_entry = gtk::Entry::new();
+        edit_entry.set_no_show_all(true);
+        {
+            let conversation = conversation.clone();
+            let message = message.clone();
+            let text = text.clone();
+            edit_entry.connect_activate(move |entry| {
+                let new_text = entry.get_text().unwrap_or_default();
+                entry.hide();
+                text.show();
+                conversation.update(|c| c.edit_message(&message, new_text));
+            });
+        }
+        {
+            let text = text.clone();
+            edit_entry.connect_key_press_event(move |entry, event| {
+                if event.get_keyval() == gdk::enums::key::Escape {
+                    entry.hide();
+                    text.show();
+                    gtk::Inhibit(true)
+                } else {
+                    gtk::Inhibit(false)
+                }
+            });
+        }
+
+        // Shows the earlier versions of an edited message
+        let edited = gtk::Button::new_with_label("Edited");
+        edited.set_relief(gtk::ReliefStyle::None);
+        edited.set_halign(gtk::Align::Start);
+        edited.set_no_show_all(true);
+        let edited_style = edited.get_style_context().unwrap();
+        edited_style.add_class("message__edited");
+        let history = gtk::Popover::new(Some(&edited));
+        let versions = gtk::Box::new(gtk::Orientation::Vertical, 6);
+        versions.set_border_width(10);
+        history.add(&versions);
+        {
+            let message = message.clone();
+            edited.connect_clicked(move |_| {
+                for child in versions.get_children() {
+                    versions.remove(&child);
+                }
+                let heading = gtk::Label::new(Some("Earlier versions"));
+                let heading_style = heading.get_style_context().unwrap();
+                heading_style.add_class("message__history_heading");
+                versions.pack_start(&heading, false, false, 0);
+                for version in message.borrow().history().iter().rev() {
+                    let label = gtk::Label::new(Some(version.as_str()));
+                    label.set_line_wrap(true);
+                    label.set_halign(gtk::Align::Start);
+                    versions.pack_start(&label, false, false, 0);
+                }
+                history.show_all();
+            });
+        }
+
+        let reactions = gtk::Box::new(gtk::Orientation::Horizontal, 4);
+        let reactions_style = reactions.get_style_context().unwrap();
+        reactions_style.add_class("message__reactions");
+        reactions.set_halign(gtk::Align::Start);
+
+        // Only shown while the pointer is over the message
+        let actions = gtk::Box::new(gtk::Orientation::Horizontal, 0);
+        let actions_style = actions.get_style_context().unwrap();
+        actions_style.add_class("message__actions");
+        actions.set_no_show_all(true);
+
+        let reply_button = gtk::Button::new_with_label("Reply");
+        reply_button.set_relief(gtk::ReliefStyle::None);
+        {
+            let conversation = conversation.clone();
+            let message = message.clone();
+            reply_button.connect_clicked(move |_| {
+                conversation.update(|c| c.set_reply_to(Some(&message)));
+            });
+        }
+        reply_button.show();
+        actions.pack_start(&reply_button, false, false, 0);
+
+        if message.borrow().was_sent() {
+            let edit_button = gtk::Button::new_with_label("Edit");
+            edit_button.set_relief(gtk::ReliefStyle::None);
+            {
+                let message = message.clone();
+                let text = text.clone();
+                let edit_entry = edit_entry.clone();
+                edit_button.connect_clicked(move |_| {
+                    edit_entry.set_text(message.borrow().text());
+                    text.hide();
+                    edit_entry.show();
+                    edit_entry.grab_focus();
+                });
+            }
+            edit_button.show();
+            actions.pack_start(&edit_button, false, false, 0);
+
+            let delete_button = gtk::Button::new_with_label("Delete for everyone");
+            delete_button.set_relief(gtk::ReliefStyle::None);
+            {
+                let conversation = conversation.clone();
+                let message = message.clone();
+                delete_button.connect_clicked(move |_| {
+                    conversation.update(|c| c.delete_message(&message));
+                });
+            }
+            delete_button.show();
+            actions.pack_start(&delete_button, false, false, 0);
+        }
+
+        for &emoji in REACTIONS.iter() {
+            let button = gtk::Button::new_with_label(emoji);
+            button.set_relief(gtk::ReliefStyle::None);
+            button.set_tooltip_text(&format!("React with {}", emoji));
+            let conversation = conversation.clone();
+            let message = message.clone();
+            button.connect_clicked(move |_| {
+                conversation.update(|c| c.react(&message, emoji));
+            });
+            button.show();
+            actions.pack_start(&button, false, false, 0);
+        }
+
         let status = MessageStatus::new(message.clone());
 
-        view.pack_start(&text, false, false, 0);
-        view.pack_start(status.borrow().view(), false, false, 0);
+        // In a group, received messages say who they're from
+        if conversation.borrow().is_group() && message.borrow().was_received() {
+            if let Some(sender) = message.borrow().sender() {
+                let label = gtk::Label::new(Some(sender.to_str().as_str()));
+                label.set_halign(gtk::Align::Start);
+                let sender_style = label.get_style_context().unwrap();
+                sender_style.add_class("message__sender");
+                bubble.pack_start(&label, false, false, 0);
+            }
+        }
+        if let Some(ref quote) = quote {
+            bubble.pack_start(quote, false, false, 0);
+        }
+        bubble.pack_start(&text, false, false, 0);
+        bubble.pack_start(&edit_entry, false, false, 0);
+        bubble.pack_start(&edited, false, false, 0);
+        bubble.pack_start(&reactions, false, false, 0);
+        bubble.pack_start(&actions, false, false, 0);
+        bubble.pack_start(status.borrow().view(), false, false, 0);
+        view.add(&bubble);
+
+        view.add_events((gdk::EventMask::ENTER_NOTIFY_MASK |
+                         gdk::EventMask::LEAVE_NOTIFY_MASK).bits() as i32);
+        let a = actions.clone();
+        let m = message.clone();
+        view.connect_enter_notify_event(move |_, _| {
+            if !m.borrow().is_deleted() && m.borrow().hidden_count() == 0 {
+                a.show();
+            }
+            gtk::Inhibit(false)
+        });
+        view.connect_leave_notify_event(move |_, event| {
+            // Moving onto one of the buttons doesn't leave the message
+            if event.get_detail() != gdk::NotifyType::Inferior {
+                actions.hide();
+            }
+            gtk::Inhibit(false)
+        });
 
+        let id = message.borrow().id().to_str();
         let controller = Rc::new(RefCell::new(Message {
-            view: view
+            view: view,
+            id: id,
+            quote: quote,
+            text: text,
+            edited: edited,
+            reactions: reactions,
+            status: status,
+            subscriptions: vec![]
         }));
 
         if message.borrow().acknowledged() {
             controller.borrow().did_receieve_acknowledgement();
         }
+        controller.borrow().reactions_were_changed(message.borrow().reactions());
+        if message.borrow().is_edited() {
+            controller.borrow().edited.show();
+        }
+        if message.borrow().is_deleted() {
+            controller.borrow().was_deleted();
+        }
+        if message.borrow().was_sent() && message.borrow().is_read() {
+            controller.borrow().did_receive_read_receipt();
+        }
+        if message.borrow().has_failed() {
+            controller.borrow().did_fail();
+        }
 
-        message.borrow_mut().register_observer(controller.clone());
+        let subscription = models::subscribe(&message, controller.clone());
+        controller.borrow_mut().subscriptions.push(subscription);
 
         controller
     }
 
-    pub fn view(&self) -> &gtk::Box {
+    pub fn view(&self) -> &gtk::EventBox {
         &self.view
     }
 }
@@ -383,16 +1104,68 @@ impl MessageObserver for Message {
         let style = self.view().get_style_context().unwrap();
         style.add_class("message--acknowledged");
     }
+
+    fn reactions_were_changed(&self, reactions: Vec<(String, usize)>) {
+        for child in self.reactions.get_children() {
+            self.reactions.remove(&child);
+        }
+        for (emoji, count) in reactions {
+            let label = gtk::Label::new(Some(&format!("{} {}", emoji, count)));
+            let style = label.get_style_context().unwrap();
+            style.add_class("message__reaction");
+            label.show();
+            self.reactions.pack_start(&label, false, false, 0);
+        }
+    }
+
+    fn text_was_edited(&self, text: String) {
+        self.text.set_text(&text);
+        self.edited.show();
+    }
+
+    fn was_deleted(&self) {
+        let style = self.view().get_style_context().unwrap();
+        style.add_class("message--deleted");
+        self.text.set_text("This message was deleted");
+        self.edited.hide();
+        if let Some(ref quote) = self.quote {
+            quote.destroy();
+        }
+    }
+
+    fn did_receive_read_receipt(&self) {
+        let style = self.view().get_style_context().unwrap();
+        style.add_class("message--read");
+    }
+
+    fn hidden_count_was_changed(&self, count: usize) {
+        self.text.set_text(&models::hidden_text(count));
+    }
+
+    fn acknowledgements_were_changed(&self, _: usize, _: usize) { }
+
+    fn did_fail(&self) {
+        let style = self.view().get_style_context().unwrap();
+        style.add_class("message--failed");
+    }
 }
 
 pub struct Transcript {
-    view: gtk::ScrolledWindow,
-    container: gtk::Box
+    view: gtk::Box,
+    scrolled: gtk::ScrolledWindow,
+    container: gtk::Box,
+    key_changed: gtk::Box,
+    key_changed_warning: gtk::Label,
+    conversation: Rc<RefCell<models::Conversation>>,
+    this: Weak<RefCell<Transcript>>,
+    messages: Vec<Rc<RefCell<Message>>>,
+    subscriptions: Vec<Subscription>
 }
 
 impl Transcript {
     pub fn new(conversation: Rc<RefCell<models::Conversation>>) -> Rc<RefCell<Transcript>> {
-        let view = gtk::ScrolledWindow::new(None, None);
+        let view = gtk::Box::new(gtk::Orientation::Vertical, 0);
+        let scrolled = gtk::ScrolledWindow::new(None, None);
         let viewport = gtk::Viewport::new(None, None);
         let container = gtk::Box::new(gtk::Orientation::Vertical, 0);
 
@@ -401,31 +1174,146 @@ impl Transcript {
         style.add_class("transcript");
 
         viewport.add(&container);
-        view.add(&viewport);
+        scrolled.add(&viewport);
+
+        // Warns that someone else may be answering in the recipient's name
+        let key_changed = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+        key_changed.get_style_context().unwrap().add_class("transcript__key_changed");
+        let warning = gtk::Label::new(None);
+        warning.set_line_wrap(true);
+        warning.set_hexpand(true);
+        warning.set_halign(gtk::Align::Start);
+        let verify = gtk::Button::new_with_label("Verify");
+        let dismiss = gtk::Button::new_with_label("Dismiss");
+        key_changed.add(&warning);
+        key_changed.add(&verify);
+        key_changed.add(&dismiss);
+        key_changed.set_no_show_all(true);
+        warning.show();
+        verify.show();
+        dismiss.show();
+
+        let c = conversation.clone();
+        verify.connect_clicked(move |button| show_contact_verification(&c, button));
+        let c = conversation.clone();
+        dismiss.connect_clicked(move |_| c.update(|c| c.dismiss_key_change()));
+
+        view.pack_start(&key_changed, false, false, 0);
+        view.pack_start(&scrolled, true, true, 0);
 
         let controller = Rc::new(RefCell::new(Transcript {
             view: view,
-            container: container
+            scrolled: scrolled,
+            container: container,
+            key_changed: key_changed,
+            key_changed_warning: warning,
+            conversation: conversation.clone(),
+            this: Weak::new(),
+            messages: vec![],
+            subscriptions: vec![]
         }));
+        controller.borrow_mut().this = Rc::downgrade(&controller);
+        let verification = conversation.borrow().verification();
+        controller.borrow().show_verification(verification);
 
         for message in conversation.borrow().messages().iter().cloned() {
-            controller.borrow_mut().did_receive_message(message);
+            controller.borrow_mut().add_message(message);
         }
 
-        let observer_id = conversation.borrow_mut().register_observer(controller.clone());
-        controller.borrow().view().connect_destroy(move |_| {
-            conversation.borrow_mut().deregister_observer(&observer_id);
+        // Received messages count as read once they're on screen in the focused window. The
+        // window outlives the transcript, so the handler is disconnected along with its view.
+        let t = Rc::downgrade(&controller);
+        let focus_handler: Rc<RefCell<Option<(gtk::Window, glib::SignalHandlerId)>>> =
+            Rc::new(RefCell::new(None));
+        let handler = focus_handler.clone();
+        controller.borrow().view.connect_map(move |view| {
+            if let Some(t) = t.upgrade() {
+                t.borrow().mark_read_if_focused();
+            }
+            if handler.borrow().is_some() {
+                return;
+            }
+            let window = view.get_toplevel()
+                .and_then(|toplevel| toplevel.downcast::<gtk::Window>().ok());
+            if let Some(window) = window {
+                let t = t.clone();
+                let id = window.connect_focus_in_event(move |_, _| {
+                    // The window only becomes active once the default handler has run
+                    let t = t.clone();
+                    gtk::idle_add(move || {
+                        if let Some(t) = t.upgrade() {
+                            t.borrow().mark_read_if_focused();
+                        }
+                        gtk::Continue(false)
+                    });
+                    gtk::Inhibit(false)
+                });
+                *handler.borrow_mut() = Some((window, id));
+            }
+        });
+        controller.borrow().view.connect_destroy(move |_| {
+            if let Some((window, id)) = focus_handler.borrow_mut().take() {
+                signal::signal_handler_disconnect(&window, id);
+            }
         });
 
+        let subscription = models::subscribe(&conversation, controller.clone());
+        controller.borrow_mut().subscriptions.push(subscription);
+
         controller
     }
 
-    pub fn view(&self) -> &gtk::ScrolledWindow {
+    pub fn view(&self) -> &gtk::Box {
         &self.view
     }
 
+    /// Scrolls so that the message with the id `id` is at the top, if it's in the transcript.
+    pub fn scroll_to_message(&self, id: &str) {
+        if let Some(message) = self.messages.iter().find(|message| message.borrow().id == id) {
+            let y = message.borrow().view().get_allocation().y;
+            let adj = self.scrolled.get_vadjustment().unwrap();
+            adj.set_value((y as f64).min(adj.get_upper() - adj.get_page_size()));
+        }
+    }
+
+    /// Warns about the recipient's changed identity key until it's verified or dismissed.
+    fn show_verification(&self, verification: Verification) {
+        if verification != Verification::KeyChanged {
+            self.key_changed.hide();
+            return;
+        }
+        let recipient = self.conversation.borrow().recipient()
+            .map(|address| address.to_str())
+            .unwrap_or_default();
+        self.key_changed_warning.set_text(&format!(
+            "The safety number with {} has changed. Verify it before trusting new messages.", recipient));
+        self.key_changed.show();
+    }
+
+    fn mark_read_if_focused(&self) {
+        let focused = self.view.get_mapped() && self.view.get_toplevel()
+            .and_then(|toplevel| toplevel.downcast::<gtk::Window>().ok())
+            .map_or(false, |window| window.is_active());
+        if focused {
+            self.conversation.update(|c| c.mark_read());
+        }
+    }
+
+    fn add_message(&mut self, message: Rc<RefCell<models::Message>>) {
+        let transcript = self.this.clone();
+        let message_controller = Message::new(self.conversation.clone(), message, move |id| {
+            if let Some(transcript) = transcript.upgrade() {
+                transcript.borrow().scroll_to_message(&id);
+            }
+        });
+        self.container.pack_start(message_controller.borrow().view(), false, false, 0);
+        self.messages.push(message_controller);
+        self.view().show_all();
+        self.scroll_to_bottom();
+    }
+
     fn scroll_to_bottom(&self) {
-        let adj = self.view().get_vadjustment().unwrap();
+        let adj = self.scrolled.get_vadjustment().unwrap();
         gtk::idle_add(move || {
             let new_value = adj.get_upper() - adj.get_page_size();
             adj.set_value(new_value);
@@ -435,31 +1323,38 @@ impl Transcript {
 }
 
 impl ConversationObserver for Transcript {
+    fn identity_was_changed(&self, _: String) { }
     fn recipient_was_changed(&self, _: Option<comm::address::Address>) { }
     fn pending_message_was_changed(&self, _: String) { }
+    fn reply_was_changed(&self, _: Option<Quote>) { }
+    fn recipient_typing_was_changed(&self, _: bool) { }
+    fn encryption_was_changed(&self, _: EncryptionState) { }
+
+    fn verification_was_changed(&self, verification: Verification) {
+        self.show_verification(verification);
+    }
+
+    fn members_were_changed(&self, _: Vec<comm::address::Address>) { }
 
     fn did_receive_message(&mut self, message: Rc<RefCell<models::Message>>) {
-        let message_controller = Message::new(message);
-        self.container.pack_start(message_controller.borrow().view(), false, false, 0);
-        self.view().show_all();
-        self.scroll_to_bottom();
+        self.add_message(message);
+        self.mark_read_if_focused();
     }
 
     fn did_send_message(&mut self, message: Rc<RefCell<models::Message>>) {
-        let message_controller = Message::new(message);
-        self.container.pack_start(message_controller.borrow().view(), false, false, 0);
-        self.view().show_all();
-        self.scroll_to_bottom();
+        self.add_message(message);
     }
 }
 
 pub struct MessageEntry {
     view: gtk::Entry,
-    changed_signal: glib::SignalHandlerId
+    changed_signal: glib::SignalHandlerId,
+    subscriptions: Vec<Subscription>
 }
 
 impl MessageEntry {
-    pub fn new(conversation: Rc<RefCell<models::Conversation>>) -> Rc<RefCell<MessageEntry>> {
+    pub fn new(conversation: Rc<RefCell<models::Conversation>>,
+               keybindings: Rc<Keybindings>) -> Rc<RefCell<MessageEntry>> {
         let view = gtk::Entry::new();
 
         view.set_text(conversation.borrow().pending_message());
@@ -467,31 +1362,27 @@ impl MessageEntry {
         let c = conversation.clone();
         let changed_signal = view.connect_changed(move |entry| {
             let text = entry.get_text().unwrap();
-            c.borrow_mut().set_pending_message(text);
+            c.update(|c| c.set_pending_message(text));
         });
 
         let c = conversation.clone();
         view.connect_key_press_event(move |_, event| {
-            match event.get_keyval() {
-                65293 => {
-                    c.borrow_mut().send_message();
-                    gtk::Inhibit(true)
-                }
-                _ => {
-                    gtk::Inhibit(false)
-                }
+            if keybindings.matches(keybindings::Action::Send, event) {
+                c.update(|c| c.send_message());
+                gtk::Inhibit(true)
+            } else {
+                gtk::Inhibit(false)
             }
         });
 
         let controller = Rc::new(RefCell::new(MessageEntry {
             view: view,
-            changed_signal: changed_signal
+            changed_signal: changed_signal,
+            subscriptions: vec![]
         }));
 
-        let observer_id = conversation.borrow_mut().register_observer(controller.clone());
-        controller.borrow().view().connect_destroy(move |_| {
-            conversation.borrow_mut().deregister_observer(&observer_id);
-        });
+        let subscription = models::subscribe(&conversation, controller.clone());
+        controller.borrow_mut().subscriptions.push(subscription);
 
         controller
     }
@@ -502,6 +1393,7 @@ impl MessageEntry {
 }
 
 impl ConversationObserver for MessageEntry {
+    fn identity_was_changed(&self, _: String) { }
     fn recipient_was_changed(&self, _: Option<comm::address::Address>) { }
 
     fn pending_message_was_changed(&self, pending_message: String) {
@@ -510,33 +1402,146 @@ impl ConversationObserver for MessageEntry {
         signal::signal_handler_unblock(&self.view, &self.changed_signal);
     }
 
+    fn reply_was_changed(&self, _: Option<Quote>) { }
+    fn recipient_typing_was_changed(&self, _: bool) { }
+    fn encryption_was_changed(&self, _: EncryptionState) { }
+    fn verification_was_changed(&self, _: Verification) { }
+    fn members_were_changed(&self, _: Vec<comm::address::Address>) { }
+
     fn did_receive_message(&mut self, _: Rc<RefCell<models::Message>>) { }
     fn did_send_message(&mut self, _: Rc<RefCell<models::Message>>) { }
 }
 
 pub struct Conversation {
-    view: gtk::Box
+    view: gtk::Box,
+    conversation: Rc<RefCell<models::Conversation>>,
+    recipient: Rc<RefCell<ConversationRecipient>>,
+    #[allow(dead_code)]
+    transcript: Rc<RefCell<Transcript>>,
+    typing_indicator: gtk::Label,
+    reply_bar: gtk::Box,
+    reply_excerpt: gtk::Label,
+    request_bar: gtk::Box,
+    request_text: gtk::Label,
+    leave: gtk::Button,
+    message_entry: Rc<RefCell<MessageEntry>>,
+    subscriptions: Vec<Subscription>
 }
 
 impl Conversation {
-    pub fn new(conversation: Rc<RefCell<models::Conversation>>) -> Rc<RefCell<Conversation>> {
+    /// `conversations` is the list the conversation is in, which accepting, ignoring or blocking
+    /// a message request, or leaving a group, changes.
+    pub fn new(conversation: Rc<RefCell<models::Conversation>>,
+               conversations: Rc<RefCell<models::ConversationList>>,
+               identities: Vec<Rc<RefCell<models::Connection>>>,
+               keybindings: Rc<Keybindings>) -> Rc<RefCell<Conversation>> {
         let view = gtk::Box::new(gtk::Orientation::Vertical, 0);
-        let recipient_controller = ConversationRecipient::new(conversation.clone());
+        let recipient_controller = ConversationRecipient::new(conversation.clone(), identities);
         let transcript_controller = Transcript::new(conversation.clone());
-        let message_entry = MessageEntry::new(conversation.clone());
+        let message_entry = MessageEntry::new(conversation.clone(), keybindings);
+
+        // Only shown in groups
+        let leave = gtk::Button::new_with_label("Leave group");
+        leave.set_valign(gtk::Align::Center);
+        leave.set_no_show_all(true);
+        leave.set_visible(conversation.borrow().is_group());
+        let (c, cl) = (conversation.clone(), conversations.clone());
+        leave.connect_clicked(move |_| {
+            let index = index_of(&cl.borrow(), &c);
+            if let Some(index) = index {
+                cl.update(|cl| cl.leave_group(index));
+            }
+        });
+        let header = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+        header.pack_start(recipient_controller.borrow().view(), true, true, 0);
+        header.pack_start(&leave, false, false, 0);
+
+        // Only shown while the recipient is typing
+        let typing_indicator = gtk::Label::new(None);
+        typing_indicator.set_halign(gtk::Align::Start);
+        typing_indicator.set_no_show_all(true);
+        let typing_style = typing_indicator.get_style_context().unwrap();
+        typing_style.add_class("conversation__typing");
+
+        // Only shown while composing a reply
+        let reply_bar = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+        let reply_bar_style = reply_bar.get_style_context().unwrap();
+        reply_bar_style.add_class("conversation__reply_bar");
+        reply_bar.set_no_show_all(true);
+        let reply_excerpt = gtk::Label::new(None);
+        reply_excerpt.set_halign(gtk::Align::Start);
+        let cancel_reply = gtk::Button::new_from_icon_name("window-close", 2);
+        cancel_reply.set_relief(gtk::ReliefStyle::None);
+        cancel_reply.set_tooltip_text("Don't reply");
+        let c = conversation.clone();
+        cancel_reply.connect_clicked(move |_| {
+            c.update(|c| c.set_reply_to(None));
+        });
+        reply_excerpt.show();
+        cancel_reply.show();
+        reply_bar.pack_start(&reply_excerpt, true, true, 0);
+        reply_bar.pack_start(&cancel_reply, false, false, 0);
+
+        // Takes the place of the message entry until a message request is accepted
+        let request_bar = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+        request_bar.get_style_context().unwrap().add_class("conversation__request_bar");
+        request_bar.set_no_show_all(true);
+        let request_text = gtk::Label::new(Some(request_text(&conversation.borrow()).as_str()));
+        request_text.set_line_wrap(true);
+        request_text.set_halign(gtk::Align::Start);
+        request_bar.pack_start(&request_text, true, true, 0);
+        let request_actions: [(&str, fn(&mut models::ConversationList, usize)); 3] = [
+            ("Accept", models::ConversationList::accept_request),
+            ("Ignore", models::ConversationList::remove_conversation),
+            ("Block", models::ConversationList::block_conversation)
+        ];
+        for &(label, action) in request_actions.iter() {
+            let button = gtk::Button::new_with_label(label);
+            let (c, cl) = (conversation.clone(), conversations.clone());
+            button.connect_clicked(move |_| {
+                let index = index_of(&cl.borrow(), &c);
+                if let Some(index) = index {
+                    cl.update(|cl| action(cl, index));
+                }
+            });
+            request_bar.pack_start(&button, false, false, 0);
+        }
+        for child in request_bar.get_children() {
+            child.show();
+        }
 
-        view.pack_start(recipient_controller.borrow().view(), false, false, 0);
+        view.pack_start(&header, false, false, 0);
         view.pack_start(transcript_controller.borrow().view(), true, true, 0);
+        view.pack_start(&typing_indicator, false, false, 0);
+        view.pack_start(&reply_bar, false, false, 0);
+        view.pack_start(&request_bar, false, false, 0);
         view.pack_start(message_entry.borrow().view(), false, false, 0);
+        message_entry.borrow().view().set_no_show_all(true);
 
         let controller = Rc::new(RefCell::new(Conversation {
-            view: view
+            view: view,
+            conversation: conversation.clone(),
+            recipient: recipient_controller,
+            transcript: transcript_controller,
+            typing_indicator: typing_indicator,
+            reply_bar: reply_bar,
+            reply_excerpt: reply_excerpt,
+            request_bar: request_bar,
+            request_text: request_text,
+            leave: leave,
+            message_entry: message_entry,
+            subscriptions: vec![]
         }));
 
-        let observer_id = conversation.borrow_mut().register_observer(controller.clone());
-        controller.borrow().view().connect_destroy(move |_| {
-            conversation.borrow_mut().deregister_observer(&observer_id);
-        });
+        controller.borrow().show_request(conversation.borrow().is_request());
+        controller.borrow().reply_was_changed(conversation.borrow().reply_to().cloned());
+        if conversation.borrow().recipient_is_typing() {
+            controller.borrow().recipient_typing_was_changed(true);
+        }
+
+        let subscription = models::subscribe(&conversation, controller.clone());
+        controller.borrow_mut().subscriptions.push(subscription);
+        owned_by_view(&controller.borrow().view, &controller);
 
         controller
     }
@@ -544,61 +1549,203 @@ impl Conversation {
     pub fn view(&self) -> &gtk::Box {
         &self.view
     }
+
+    pub fn focus_recipient(&self) {
+        self.recipient.borrow().focus();
+    }
+
+    pub fn focus_composer(&self) {
+        self.message_entry.borrow().view().grab_focus();
+    }
+
+    /// Shows what to do about a message request in place of the message entry, or the entry once
+    /// it has been accepted.
+    fn show_request(&self, request: bool) {
+        let entry = self.message_entry.borrow().view().clone();
+        if request {
+            entry.hide();
+            self.request_bar.show();
+        } else {
+            self.request_bar.hide();
+            entry.show();
+        }
+    }
+}
+
+/// Keeps `controller` alive until `view` is destroyed, so that destroying the view is enough to
+/// free it.
+fn owned_by_view<W: IsA<gtk::Widget>, C: 'static>(view: &W, controller: &Rc<RefCell<C>>) {
+    let controller = RefCell::new(Some(controller.clone()));
+    view.connect_destroy(move |_| {
+        controller.borrow_mut().take();
+    });
+}
+
+/// Where the conversation `conversation` is in `conversations`.
+fn index_of(conversations: &models::ConversationList,
+            conversation: &Rc<RefCell<models::Conversation>>) -> Option<usize> {
+    (0..conversations.len()).find(|&index| Rc::ptr_eq(conversations.get(index).unwrap(), conversation))
+}
+
+/// Says who a message request is from.
+fn request_text(conversation: &models::Conversation) -> String {
+    if conversation.is_group() {
+        format!("You've been added to a group with {}. Accept to reply, or block them to stop their \
+                 messages.", members_text(conversation.members()))
+    } else {
+        let sender = conversation.recipient().map(|address| address.to_str()).unwrap_or_default();
+        format!("{} isn't in your conversations. Accept to reply, or block them to stop their messages.",
+                sender)
+    }
 }
 
 impl ConversationObserver for Conversation {
-    fn recipient_was_changed(&self, _: Option<comm::address::Address>) { }
+    fn identity_was_changed(&self, _: String) { }
+
+    fn recipient_was_changed(&self, _: Option<comm::address::Address>) {
+        self.leave.hide();
+    }
+
     fn pending_message_was_changed(&self, _: String) { }
+
+    fn reply_was_changed(&self, quote: Option<Quote>) {
+        match quote {
+            Some(quote) => {
+                self.reply_excerpt.set_text(&format!("Replying to: {}", quote.excerpt));
+                self.reply_bar.show();
+                self.focus_composer();
+            }
+            None => self.reply_bar.hide()
+        }
+    }
+
+    fn recipient_typing_was_changed(&self, typing: bool) {
+        if !typing {
+            self.typing_indicator.hide();
+            return;
+        }
+
+        let recipient = self.conversation.borrow().recipient()
+            .map(|address| address.to_str())
+            .unwrap_or_default();
+        self.typing_indicator.set_text(&format!("{} is typing…", recipient));
+        self.typing_indicator.show();
+
+        // Typing notices stop without a word when the recipient goes quiet
+        let conversation = Rc::downgrade(&self.conversation);
+        let typing_indicator = self.typing_indicator.clone();
+        gtk::timeout_add_seconds(1, move || {
+            match conversation.upgrade() {
+                Some(ref conversation) if conversation.borrow().recipient_is_typing() => {
+                    gtk::Continue(true)
+                }
+                _ => {
+                    typing_indicator.hide();
+                    gtk::Continue(false)
+                }
+            }
+        });
+    }
+
+    fn encryption_was_changed(&self, _: EncryptionState) { }
+    fn verification_was_changed(&self, _: Verification) { }
+
+    fn members_were_changed(&self, _: Vec<comm::address::Address>) {
+        self.request_text.set_text(&request_text(&self.conversation.borrow()));
+        self.leave.set_visible(self.conversation.borrow().is_group());
+    }
+
     fn did_receive_message(&mut self, _: Rc<RefCell<models::Message>>) { }
     fn did_send_message(&mut self, _: Rc<RefCell<models::Message>>) { }
 }
 
 pub struct ConversationListItemTitle {
-    view: gtk::Label
+    view: gtk::Box,
+    title: gtk::Label,
+    identity: gtk::Label,
+    subscriptions: Vec<Subscription>
 }
 
 impl ConversationListItemTitle {
     pub fn new(conversation: Rc<RefCell<models::Conversation>>) -> Rc<RefCell<ConversationListItemTitle>> {
-        let view = gtk::Label::new("");
+        let view = gtk::Box::new(gtk::Orientation::Vertical, 0);
+        let title = gtk::Label::new("");
+        title.set_halign(gtk::Align::Start);
+        let identity = gtk::Label::new(Some(conversation.borrow().identity().as_str()));
+        identity.set_halign(gtk::Align::Start);
+        let style = identity.get_style_context().unwrap();
+        style.add_class("conversation_list_item__identity");
 
         match conversation.borrow().recipient() {
-            None => view.set_text("New Conversation"),
-            Some(address) => view.set_text(&address.to_str())
+            None => title.set_text("New Conversation"),
+            Some(address) => title.set_text(&address.to_str())
         };
+        if conversation.borrow().is_group() {
+            title.set_text(&members_text(conversation.borrow().members()));
+        }
+
+        view.pack_start(&title, false, false, 0);
+        view.pack_start(&identity, false, false, 0);
 
         let controller = Rc::new(RefCell::new(ConversationListItemTitle {
-            view: view
+            view: view,
+            title: title,
+            identity: identity,
+            subscriptions: vec![]
         }));
 
-        conversation.borrow_mut().register_observer(controller.clone());
+        let subscription = models::subscribe(&conversation, controller.clone());
+        controller.borrow_mut().subscriptions.push(subscription);
 
         controller
     }
 
-    pub fn view(&self) -> &gtk::Label {
+    pub fn view(&self) -> &gtk::Box {
         &self.view
     }
 }
 impl ConversationObserver for ConversationListItemTitle {
+    fn identity_was_changed(&self, identity: String) {
+        self.identity.set_text(&identity);
+    }
+
     fn recipient_was_changed(&self, address: Option<comm::address::Address>) {
         match address {
-            Some(a) => self.view.set_text(&a.to_str()),
-            None => self.view.set_text("New Conversation")
+            Some(a) => self.title.set_text(&a.to_str()),
+            None => self.title.set_text("New Conversation")
         }
     }
 
     fn pending_message_was_changed(&self, _: String) { }
+    fn reply_was_changed(&self, _: Option<Quote>) { }
+    fn recipient_typing_was_changed(&self, _: bool) { }
+    fn encryption_was_changed(&self, _: EncryptionState) { }
+    fn verification_was_changed(&self, _: Verification) { }
+
+    fn members_were_changed(&self, members: Vec<comm::address::Address>) {
+        self.title.set_text(&members_text(&members));
+    }
+
     fn did_receive_message(&mut self, _: Rc<RefCell<models::Message>>) { }
     fn did_send_message(&mut self, _: Rc<RefCell<models::Message>>) { }
 }
 
 pub struct ConversationListItem {
-    view: gtk::ListBoxRow
+    view: gtk::ListBoxRow,
+    #[allow(dead_code)]
+    title: Rc<RefCell<ConversationListItemTitle>>,
+    subscriptions: Vec<Subscription>
 }
 
 impl ConversationObserver for ConversationListItem {
+    fn identity_was_changed(&self, _: String) { }
     fn recipient_was_changed(&self, _: Option<comm::address::Address>) { }
     fn pending_message_was_changed(&self, _: String) { }
+    fn reply_was_changed(&self, _: Option<Quote>) { }
+    fn recipient_typing_was_changed(&self, _: bool) { }
+    fn encryption_was_changed(&self, _: EncryptionState) { }
+    fn verification_was_changed(&self, _: Verification) { }
+    fn members_were_changed(&self, _: Vec<comm::address::Address>) { }
     fn did_receive_message(&mut self, _: Rc<RefCell<models::Message>>) { }
     fn did_send_message(&mut self, _: Rc<RefCell<models::Message>>) { }
 }
@@ -613,13 +1760,13 @@ impl ConversationListItem {
         view.add(title_controller.borrow().view());
 
         let controller = Rc::new(RefCell::new(ConversationListItem {
-            view: view
+            view: view,
+            title: title_controller,
+            subscriptions: vec![]
         }));
 
-        let observer_id = conversation.borrow_mut().register_observer(controller.clone());
-        controller.borrow().view().connect_destroy(move |_| {
-            conversation.borrow_mut().deregister_observer(&observer_id);
-        });
+        let subscription = models::subscribe(&conversation, controller.clone());
+        controller.borrow_mut().subscriptions.push(subscription);
 
         controller
     }
@@ -629,53 +1776,190 @@ impl ConversationListItem {
     }
 }
 
+/// The sidebar's list of conversations, below a section of message requests from people the
+/// user hasn't talked to yet.
 pub struct ConversationList {
-    view: gtk::ListBox
+    view: gtk::Box,
+    conversations: gtk::ListBox,
+    requests: gtk::ListBox,
+    requests_section: gtk::Box,
+    items: Vec<(Rc<RefCell<models::Conversation>>, Rc<RefCell<ConversationListItem>>)>,
+    row_selected_signals: (glib::SignalHandlerId, glib::SignalHandlerId),
+    subscriptions: Vec<Subscription>
 }
 
 impl ConversationList {
     pub fn new(conversations: Rc<RefCell<models::ConversationList>>) -> Rc<RefCell<ConversationList>> {
-        let view = gtk::ListBox::new();
+        let view = gtk::Box::new(gtk::Orientation::Vertical, 0);
 
+        // Only shown while there are requests
+        let requests_section = gtk::Box::new(gtk::Orientation::Vertical, 0);
+        requests_section.set_no_show_all(true);
+        let requests_heading = gtk::Label::new("Requests");
+        requests_heading.set_halign(gtk::Align::Start);
+        requests_heading.get_style_context().unwrap().add_class("conversation_list__heading");
+        let requests = gtk::ListBox::new();
+        requests_heading.show();
+        requests.show();
+        requests_section.pack_start(&requests_heading, false, false, 0);
+        requests_section.pack_start(&requests, false, false, 0);
+
+        let conversations_list = gtk::ListBox::new();
+        view.pack_start(&requests_section, false, false, 0);
+        view.pack_start(&conversations_list, true, true, 0);
+
+        let cl = conversations.clone();
+        let conversation_selected = conversations_list.connect_row_selected(move |_, list_item| {
+            if let Some(ref list_item) = *list_item {
+                select_in_section(&cl, false, list_item.get_index() as usize);
+            }
+        });
         let cl = conversations.clone();
-        view.connect_row_selected(move |_, list_item| {
-            let index = list_item.as_ref().unwrap().get_index() as usize;
-            cl.borrow_mut().select_conversation(index);
+        let request_selected = requests.connect_row_selected(move |_, list_item| {
+            if let Some(ref list_item) = *list_item {
+                select_in_section(&cl, true, list_item.get_index() as usize);
+            }
         });
 
         let controller = Rc::new(RefCell::new(ConversationList {
-            view: view
+            view: view,
+            conversations: conversations_list,
+            requests: requests,
+            requests_section: requests_section,
+            items: vec![],
+            row_selected_signals: (conversation_selected, request_selected),
+            subscriptions: vec![]
         }));
 
-        let observer_id = conversations.borrow_mut().register_observer(controller.clone());
-        controller.borrow().view().connect_destroy(move |_| {
-            conversations.borrow_mut().deregister_observer(&observer_id);
-        });
+        // Show conversations that were restored before the view was built
+
+        let len = conversations.borrow().len();
+        for index in (0..len).rev() {
+            let conversation = conversations.borrow().get(index).unwrap().clone();
+            controller.borrow_mut().conversation_was_added(conversation);
+        }
+
+        let subscription = models::subscribe(&conversations, controller.clone());
+        controller.borrow_mut().subscriptions.push(subscription);
 
         controller
     }
 
-    pub fn view(&self) -> &gtk::ListBox {
+    pub fn view(&self) -> &gtk::Box {
         &self.view
     }
 }
 
-impl ConversationListObserver for ConversationList {
-    fn conversation_was_added(&self, conversation: Rc<RefCell<models::Conversation>>) {
-        let list_item = ConversationListItem::new(conversation);
-        self.view.prepend(list_item.borrow().view());
+/// Selects the conversation shown `position` rows down the requests section, or the section of
+/// other conversations. Each section keeps the order of the list.
+fn select_in_section(conversations: &Rc<RefCell<models::ConversationList>>, requests: bool, position: usize) {
+    let index = {
+        let conversations = conversations.borrow();
+        (0..conversations.len())
+            .filter(|&index| conversations.get(index).unwrap().borrow().is_request() == requests)
+            .nth(position)
+    };
+    if let Some(index) = index {
+        conversations.update(|c| c.select_conversation(index));
+    }
+}
+
+impl ConversationList {
+    fn position(&self, conversation: &Rc<RefCell<models::Conversation>>) -> Option<usize> {
+        self.items.iter().position(|&(ref c, _)| Rc::ptr_eq(c, conversation))
+    }
+
+    /// Runs `f` without either list telling the model about the rows it selects.
+    fn without_selection_signals<F: FnOnce()>(&self, f: F) {
+        signal::signal_handler_block(&self.conversations, &self.row_selected_signals.0);
+        signal::signal_handler_block(&self.requests, &self.row_selected_signals.1);
+        f();
+        signal::signal_handler_unblock(&self.conversations, &self.row_selected_signals.0);
+        signal::signal_handler_unblock(&self.requests, &self.row_selected_signals.1);
+    }
+
+    /// Adds the row for `conversation` to the top of its section.
+    fn prepend(&self, conversation: &Rc<RefCell<models::Conversation>>,
+               list_item: &Rc<RefCell<ConversationListItem>>) {
+        if conversation.borrow().is_request() {
+            self.requests.prepend(list_item.borrow().view());
+        } else {
+            self.conversations.prepend(list_item.borrow().view());
+        }
         list_item.borrow().view().show_all();
+        self.show_requests_section();
+    }
+
+    fn show_requests_section(&self) {
+        if self.items.iter().any(|&(ref c, _)| c.borrow().is_request()) {
+            self.requests_section.show();
+        } else {
+            self.requests_section.hide();
+        }
+    }
+}
+
+impl ConversationListObserver for ConversationList {
+    fn conversation_was_added(&mut self, conversation: Rc<RefCell<models::Conversation>>) {
+        let list_item = ConversationListItem::new(conversation.clone());
+        self.items.insert(0, (conversation.clone(), list_item.clone()));
+        self.prepend(&conversation, &list_item);
+    }
+
+    fn conversation_was_removed(&mut self, conversation: Rc<RefCell<models::Conversation>>) {
+        if let Some(index) = self.position(&conversation) {
+            let (_, list_item) = self.items.remove(index);
+            self.without_selection_signals(|| list_item.borrow().view().destroy());
+            self.show_requests_section();
+        }
     }
 
-    fn conversation_was_selected(&self, _: Rc<RefCell<models::Conversation>>) { }
+    fn conversation_was_selected(&mut self, conversation: Rc<RefCell<models::Conversation>>) {
+        if let Some(index) = self.position(&conversation) {
+            let row = self.items[index].1.borrow().view().clone();
+            let (list, other) = if conversation.borrow().is_request() {
+                (&self.requests, &self.conversations)
+            } else {
+                (&self.conversations, &self.requests)
+            };
+            self.without_selection_signals(|| {
+                other.unselect_all();
+                list.select_row(Some(&row));
+            });
+        }
+    }
+
+    fn request_was_accepted(&mut self, conversation: Rc<RefCell<models::Conversation>>) {
+        if let Some(index) = self.position(&conversation) {
+            let (_, list_item) = self.items.remove(index);
+            let row = list_item.borrow().view().clone();
+            let selected = self.requests.get_selected_row().map_or(false, |selected| selected == row);
+            self.without_selection_signals(|| self.requests.remove(&row));
+            self.items.insert(0, (conversation.clone(), list_item.clone()));
+            self.prepend(&conversation, &list_item);
+            if selected {
+                self.without_selection_signals(|| self.conversations.select_row(Some(&row)));
+            }
+        }
+    }
 }
 
 pub struct Conversations {
-    view: gtk::Paned
+    view: gtk::Paned,
+    conversations: Rc<RefCell<models::ConversationList>>,
+    search: gtk::SearchEntry,
+    #[allow(dead_code)]
+    conversation_list: Rc<RefCell<ConversationList>>,
+    statuses: gtk::Box,
+    identities: Vec<(Rc<RefCell<models::Connection>>, Rc<RefCell<ConnectionStatus>>)>,
+    keybindings: Rc<Keybindings>,
+    selected: Option<Rc<RefCell<Conversation>>>,
+    subscriptions: Vec<Subscription>
 }
 
 impl Conversations {
-    pub fn new(connection: Rc<RefCell<models::Connection>>, conversations: Rc<RefCell<models::ConversationList>>) -> Rc<RefCell<Conversations>> {
+    pub fn new(conversations: Rc<RefCell<models::ConversationList>>,
+               keybindings: Rc<Keybindings>) -> Rc<RefCell<Conversations>> {
         // Build UI
 
         let view = gtk::Paned::new(gtk::Orientation::Horizontal);
@@ -693,37 +1977,33 @@ impl Conversations {
         search_add_pane.pack2(&new_conversation_button, false, false);
 
         let conversation_list_controller = ConversationList::new(conversations.clone());
-        let connection_status_controller = ConnectionStatus::new(connection.clone());
+        let statuses = gtk::Box::new(gtk::Orientation::Vertical, 0);
 
         sidebar.pack_start(&search_add_pane, false, false, 0);
         sidebar.pack_start(conversation_list_controller.borrow().view(), true, true, 0);
-        sidebar.pack_start(connection_status_controller.borrow().view(), false, false, 0);
+        sidebar.pack_start(&statuses, false, false, 0);
 
         // Connect view event signals
 
         let c = conversations.clone();
-        let conn = connection.clone();
         new_conversation_button.connect_clicked(move |_| {
-            let conversation = Rc::new(RefCell::new(models::Conversation::new(conn.clone())));
-            c.borrow_mut().add_conversation(conversation);
-            c.borrow_mut().select_conversation(0);
+            c.update(|c| c.start_new_conversation());
         });
 
         let controller = Rc::new(RefCell::new(Conversations {
-            view: view
+            view: view,
+            conversations: conversations.clone(),
+            search: search,
+            conversation_list: conversation_list_controller,
+            statuses: statuses,
+            identities: vec![],
+            keybindings: keybindings,
+            selected: None,
+            subscriptions: vec![]
         }));
 
-        let observer_id = conversations.borrow_mut().register_observer(controller.clone());
-        let c = conversations.clone();
-        controller.borrow().view().connect_destroy(move |_| {
-            c.borrow_mut().deregister_observer(&observer_id);
-        });
-
-        // Add initial new conversation
-
-        let conversation = Rc::new(RefCell::new(models::Conversation::new(connection.clone())));
-        conversations.borrow_mut().add_conversation(conversation);
-        conversations.borrow_mut().select_conversation(0);
+        let subscription = models::subscribe(&conversations, controller.clone());
+        controller.borrow_mut().subscriptions.push(subscription);
 
         controller
     }
@@ -731,17 +2011,817 @@ impl Conversations {
     pub fn view(&self) -> &gtk::Paned {
         &self.view
     }
+
+    pub fn add_identity(&mut self, connection: Rc<RefCell<models::Connection>>) {
+        let status = ConnectionStatus::new(connection.clone());
+        self.statuses.pack_start(status.borrow().view(), false, false, 0);
+        status.borrow().view().show_all();
+        self.identities.push((connection, status));
+    }
+
+    pub fn remove_identity(&mut self, connection: &Rc<RefCell<models::Connection>>) {
+        if let Some(index) = self.identities.iter().position(|&(ref c, _)| Rc::ptr_eq(c, connection)) {
+            let (_, status) = self.identities.remove(index);
+            status.borrow().view().destroy();
+        }
+    }
+
+    pub fn focus_search(&self) {
+        self.search.grab_focus();
+    }
+
+    pub fn focus_recipient(&self) {
+        if let Some(ref conversation) = self.selected {
+            conversation.borrow().focus_recipient();
+        }
+    }
+
+    pub fn focus_composer(&self) {
+        if let Some(ref conversation) = self.selected {
+            conversation.borrow().focus_composer();
+        }
+    }
 }
 
 impl ConversationListObserver for Conversations {
-    fn conversation_was_added(&self, _: Rc<RefCell<models::Conversation>>) { }
+    fn conversation_was_added(&mut self, _: Rc<RefCell<models::Conversation>>) { }
+    fn conversation_was_removed(&mut self, _: Rc<RefCell<models::Conversation>>) { }
 
-    fn conversation_was_selected(&self, conversation: Rc<RefCell<models::Conversation>>) {
-        let conversation_controller = Conversation::new(conversation);
+    fn conversation_was_selected(&mut self, conversation: Rc<RefCell<models::Conversation>>) {
+        let identities = self.identities.iter().map(|&(ref connection, _)| connection.clone()).collect();
+        let conversation_controller = Conversation::new(
+            conversation, self.conversations.clone(), identities, self.keybindings.clone());
         if let Some(widget) = self.view.get_child2() {
             widget.destroy();
         }
         self.view.add2(conversation_controller.borrow().view());
         self.view.show_all();
+        self.selected = Some(conversation_controller);
+    }
+
+    fn request_was_accepted(&mut self, conversation: Rc<RefCell<models::Conversation>>) {
+        if let Some(ref selected) = self.selected {
+            let selected = selected.borrow();
+            if Rc::ptr_eq(&selected.conversation, &conversation) {
+                selected.show_request(false);
+            }
+        }
+    }
+}
+
+pub struct Shortcuts {
+    view: gtk::Window
+}
+
+impl Shortcuts {
+    pub fn new(keybindings: Rc<Keybindings>) -> Rc<RefCell<Shortcuts>> {
+        let view = gtk::Window::new(gtk::WindowType::Toplevel);
+        view.set_title("Keyboard Shortcuts");
+        view.set_position(gtk::WindowPosition::Center);
+        view.set_resizable(false);
+
+        let grid = gtk::Grid::new();
+        grid.set_column_spacing(24);
+        grid.set_row_spacing(6);
+        grid.set_border_width(12);
+
+        for (row, action) in keybindings::ACTIONS.iter().enumerate() {
+            let description = gtk::Label::new(Some(action.description().as_str()));
+            description.set_halign(gtk::Align::Start);
+            let accel = gtk::Label::new(None);
+            accel.set_halign(gtk::Align::End);
+            let style = accel.get_style_context().unwrap();
+            style.add_class("shortcuts__accel");
+            match keybindings.label(*action) {
+                Some(label) => accel.set_text(&label),
+                None => accel.set_text("Disabled")
+            }
+            grid.attach(&description, 0, row as i32, 1, 1);
+            grid.attach(&accel, 1, row as i32, 1, 1);
+        }
+
+        view.connect_delete_event(|win, _| {
+            win.hide();
+            gtk::Inhibit(true)
+        });
+
+        view.add(&grid);
+
+        Rc::new(RefCell::new(Shortcuts {
+            view: view
+        }))
+    }
+
+    pub fn view(&self) -> &gtk::Window {
+        &self.view
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gdk;
+    use gtk;
+    use gtk::prelude::*;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    use comm;
+    use comm::address::Address;
+    use comm::client::{Event, Task};
+    use comm_client_core::encryption::{KeyPair, Sessions, SigningKey};
+    use keybindings::Keybindings;
+    use models::{self, Deletion, Edit, EventObserver, GroupMessage, Invite, Observable, Payload, Reaction,
+        ReadReceipt, Typing, Update};
+    use super::{Conversation, Conversations, Message};
+    use testing::{process_events, run_on_gtk_thread, started_connection, FakeTransport};
+
+    struct Ui {
+        conversations: Rc<RefCell<models::ConversationList>>,
+        controller: Rc<RefCell<Conversations>>,
+        identities: Vec<(Rc<RefCell<models::Connection>>, Rc<FakeTransport>)>
+    }
+
+    impl Ui {
+        /// The conversations window for running identities called `names`, with a new
+        /// conversation selected as the first of them. They don't send typing notices, encrypt or sign,
+        /// so the transport only sees what the tests send, as they sent it.
+        fn new(names: &[&str]) -> Ui {
+            let conversations = Rc::new(RefCell::new(models::ConversationList::new()));
+            let keybindings = Rc::new(Keybindings::new(&HashMap::new()));
+            let controller = Conversations::new(conversations.clone(), keybindings);
+
+            let identities: Vec<_> = names.iter().map(|name| started_connection(name)).collect();
+            for &(ref connection, _) in identities.iter() {
+                connection.borrow_mut().set_sends_typing_indicators(false);
+                connection.borrow_mut().set_encrypts_messages(false);
+                connection.borrow_mut().set_signs_messages(false);
+                conversations.update(|c| c.add_connection(connection.clone()));
+                controller.borrow_mut().add_identity(connection.clone());
+            }
+            conversations.update(|c| c.start_new_conversation());
+            process_events();
+
+            Ui {
+                conversations: conversations,
+                controller: controller,
+                identities: identities
+            }
+        }
+
+        fn selected(&self) -> Rc<RefCell<Conversation>> {
+            self.controller.borrow().selected.clone().expect("No conversation is shown")
+        }
+
+        fn selected_model(&self) -> Rc<RefCell<models::Conversation>> {
+            let conversations = self.conversations.borrow();
+            conversations.get(conversations.selected().unwrap()).unwrap().clone()
+        }
+
+        fn transcript(&self) -> Vec<Rc<RefCell<Message>>> {
+            self.selected().borrow().transcript.borrow().messages.clone()
+        }
+
+        fn sidebar_titles(&self) -> Vec<(String, String)> {
+            let controller = self.controller.borrow();
+            let list = controller.conversation_list.borrow();
+            list.items.iter().map(|&(_, ref item)| {
+                let item = item.borrow();
+                let title = item.title.borrow();
+                (title.title.get_text().unwrap(), title.identity.get_text().unwrap())
+            }).collect()
+        }
+
+        fn type_recipient(&self, address: Address) {
+            let entry = self.selected().borrow().recipient.borrow().entry.clone();
+            entry.set_text(&address.to_str());
+            process_events();
+        }
+
+        /// Types `text` into the message entry and sends it. Key presses can't be synthesised
+        /// reliably without a window manager, so sending goes straight to the model like the
+        /// entry's key handler does.
+        fn send(&self, text: &str) {
+            let entry = self.selected().borrow().message_entry.borrow().view().clone();
+            entry.set_text(text);
+            self.selected_model().update(|c| c.send_message());
+            process_events();
+        }
+
+        /// Clicks the `index`th row of the sidebar.
+        fn click_row(&self, index: usize) {
+            let row = {
+                let controller = self.controller.borrow();
+                let list = controller.conversation_list.borrow();
+                list.items[index].1.borrow().view().clone()
+            };
+            let list = row.get_parent().unwrap().downcast::<gtk::ListBox>().unwrap();
+            list.select_row(Some(&row));
+            process_events();
+        }
+
+        fn receive(&self, event: Event) {
+            let connection = self.identities[0].0.clone();
+            self.conversations.update(|c| c.handle_event(&connection, event));
+            process_events();
+        }
+    }
+
+    fn has_class<W: IsA<gtk::Widget>>(widget: &W, class: &str) -> bool {
+        widget.get_style_context().unwrap().has_class(class)
+    }
+
+    fn message_text(message: &Rc<RefCell<Message>>) -> String {
+        message.borrow().text.get_text().unwrap()
+    }
+
+    #[test]
+    fn sending_adds_the_message_and_locks_the_recipient() {
+        run_on_gtk_thread(|| {
+            let ui = Ui::new(&["alice"]);
+            let bob = Address::for_content("bob");
+
+            ui.type_recipient(bob);
+            assert!(ui.selected_model().borrow().recipient() == Some(bob));
+            assert_eq!(ui.sidebar_titles(), vec![(bob.to_str(), String::from("alice"))]);
+
+            ui.send("hello");
+
+            match ui.identities[0].1.tasks().as_slice() {
+                [Task::ScheduleMessageDelivery(address, text_message)] => {
+                    assert!(*address == bob);
+                    assert_eq!(text_message.text, "hello");
+                }
+                _ => panic!("Expected a single delivery")
+            }
+
+            let transcript = ui.transcript();
+            assert_eq!(transcript.len(), 1);
+            assert!(has_class(transcript[0].borrow().view(), "message--sent"));
+            assert_eq!(message_text(&transcript[0]), "hello");
+
+            let selected = ui.selected();
+            let selected = selected.borrow();
+            assert_eq!(selected.message_entry.borrow().view().get_text().unwrap(), "");
+            let recipient = selected.recipient.borrow();
+            assert!(recipient.entry.get_parent().is_none());
+            assert!(recipient.label.get_parent().is_some());
+            assert_eq!(recipient.label.get_text().unwrap(), bob.to_str());
+        });
+    }
+
+    #[test]
+    fn received_messages_start_a_conversation_in_the_sidebar() {
+        run_on_gtk_thread(|| {
+            let ui = Ui::new(&["alice"]);
+            let carol = Address::for_content("carol");
+
+            let text_message = comm::client::messages::TextMessage::new(carol, String::from("hi"));
+            ui.receive(Event::ReceivedTextMessage(text_message));
+
+            assert_eq!(ui.sidebar_titles(), vec![
+                (carol.to_str(), String::from("alice")),
+                (String::from("New Conversation"), String::from("alice"))
+            ]);
+
+            ui.click_row(0);
+
+            assert_eq!(ui.conversations.borrow().selected(), Some(0));
+            let transcript = ui.transcript();
+            assert_eq!(transcript.len(), 1);
+            assert!(has_class(transcript[0].borrow().view(), "message--received"));
+            assert_eq!(message_text(&transcript[0]), "hi");
+            assert!(ui.selected().borrow().recipient.borrow().entry.get_parent().is_none());
+        });
+    }
+
+    /// Clicks the request bar button labelled `label` on the selected conversation.
+    fn click_request_action(ui: &Ui, label: &str) {
+        let request_bar = ui.selected().borrow().request_bar.clone();
+        let button = request_bar.get_children().into_iter()
+            .filter_map(|child| child.downcast::<gtk::Button>().ok())
+            .find(|button| button.get_label().map_or(false, |l| l == label))
+            .expect("No such request action");
+        button.clicked();
+        process_events();
+    }
+
+    #[test]
+    fn message_requests_can_be_accepted_or_blocked() {
+        run_on_gtk_thread(|| {
+            let ui = Ui::new(&["alice"]);
+            let (carol, dave) = (Address::for_content("carol"), Address::for_content("dave"));
+            let receive = |from: Address| {
+                let text_message = comm::client::messages::TextMessage::new(from, String::from("hi"));
+                ui.receive(Event::ReceivedTextMessage(text_message));
+            };
+
+            receive(carol);
+            let requests_section = {
+                let controller = ui.controller.borrow();
+                let list = controller.conversation_list.borrow();
+                list.requests_section.clone()
+            };
+            assert!(requests_section.get_visible());
+            ui.click_row(0);
+            {
+                let selected = ui.selected();
+                let selected = selected.borrow();
+                assert!(selected.request_bar.get_visible());
+                assert!(!selected.message_entry.borrow().view().get_visible());
+            }
+
+            click_request_action(&ui, "Accept");
+            assert!(!ui.selected_model().borrow().is_request());
+            assert!(!requests_section.get_visible());
+            {
+                let selected = ui.selected();
+                let selected = selected.borrow();
+                assert!(!selected.request_bar.get_visible());
+                assert!(selected.message_entry.borrow().view().get_visible());
+            }
+
+            receive(dave);
+            ui.click_row(0);
+            click_request_action(&ui, "Block");
+            assert!(ui.identities[0].0.borrow().is_blocked(dave));
+            assert_eq!(ui.sidebar_titles(), vec![
+                (carol.to_str(), String::from("alice")),
+                (String::from("New Conversation"), String::from("alice"))
+            ]);
+            assert!(!requests_section.get_visible());
+        });
+    }
+
+    #[test]
+    fn messages_not_signed_by_their_sender_are_marked() {
+        run_on_gtk_thread(|| {
+            let ui = Ui::new(&["alice"]);
+            let (alice, bob) = (Address::for_content("alice"), Address::for_content("bob"));
+            let mut bob_sessions = Sessions::default();
+            bob_sessions.set_signing_key(SigningKey::for_secret("bob"));
+            let mut mallory = Sessions::default();
+            mallory.set_signing_key(SigningKey::for_secret("mallory"));
+
+            ui.type_recipient(bob);
+            for (sessions, text) in vec![(&bob_sessions, "hi"), (&mallory, "it's bob, honest")] {
+                let text_message = comm::client::messages::TextMessage::new(bob, String::from(text));
+                ui.receive(Event::ReceivedTextMessage(sessions.sign(alice, text_message)));
+            }
+            ui.receive(Event::ReceivedTextMessage(
+                comm::client::messages::TextMessage::new(bob, String::from("unsigned"))));
+
+            let transcript = ui.transcript();
+            assert_eq!(transcript.iter().map(message_text).collect::<Vec<_>>(),
+                       vec!["hi", "it's bob, honest", "unsigned"]);
+            let marked: Vec<bool> = transcript.iter()
+                .map(|message| has_class(message.borrow().view(), "message--unauthentic"))
+                .collect();
+            assert_eq!(marked, vec![false, true, true]);
+        });
+    }
+
+    #[test]
+    fn acknowledgements_mark_the_sent_message() {
+        run_on_gtk_thread(|| {
+            let ui = Ui::new(&["alice"]);
+            ui.type_recipient(Address::for_content("bob"));
+            ui.send("hello");
+
+            let message_id = match ui.identities[0].1.tasks()[0] {
+                Task::ScheduleMessageDelivery(_, ref text_message) => text_message.id,
+                _ => panic!("Expected a delivery")
+            };
+            let message = ui.transcript()[0].clone();
+            assert!(!has_class(message.borrow().view(), "message--acknowledged"));
+            assert_eq!(message.borrow().status.borrow().view().get_text().unwrap(), "");
+
+            let ack = comm::client::messages::MessageAcknowledgement { message_id: message_id };
+            ui.receive(Event::ReceivedMessageAcknowledgement(ack));
+
+            assert!(has_class(message.borrow().view(), "message--acknowledged"));
+            assert_eq!(message.borrow().status.borrow().view().get_text().unwrap(), "Acknowledged");
+        });
+    }
+
+    #[test]
+    fn reactions_are_shown_under_the_message() {
+        run_on_gtk_thread(|| {
+            let ui = Ui::new(&["alice"]);
+            let bob = Address::for_content("bob");
+            ui.type_recipient(bob);
+            ui.send("hello");
+
+            let message_id = match ui.identities[0].1.tasks()[0] {
+                Task::ScheduleMessageDelivery(_, ref text_message) => text_message.id,
+                _ => panic!("Expected a delivery")
+            };
+            let reaction = Payload::Reaction(Reaction {
+                message_id: message_id.to_str(),
+                emoji: String::from("🎉"),
+                removed: false
+            });
+            let text_message = comm::client::messages::TextMessage::new(bob, reaction.encode());
+            ui.receive(Event::ReceivedTextMessage(text_message));
+
+            let transcript = ui.transcript();
+            assert_eq!(transcript.len(), 1);
+            let reactions: Vec<String> = transcript[0].borrow().reactions.get_children().into_iter()
+                .map(|label| label.downcast::<gtk::Label>().unwrap().get_text().unwrap())
+                .collect();
+            assert_eq!(reactions, vec!["🎉 1"]);
+        });
+    }
+
+    #[test]
+    fn replies_quote_the_original_message() {
+        run_on_gtk_thread(|| {
+            let ui = Ui::new(&["alice"]);
+            ui.type_recipient(Address::for_content("bob"));
+            ui.send("hello");
+
+            let original = ui.selected_model().borrow().messages()[0].clone();
+            ui.selected_model().update(|c| c.set_reply_to(Some(&original)));
+            process_events();
+            {
+                let selected = ui.selected();
+                let selected = selected.borrow();
+                assert!(selected.reply_bar.get_visible());
+                assert_eq!(selected.reply_excerpt.get_text().unwrap(), "Replying to: hello");
+            }
+
+            ui.send("again");
+
+            assert!(!ui.selected().borrow().reply_bar.get_visible());
+            let transcript = ui.transcript();
+            assert_eq!(transcript.len(), 2);
+            assert!(transcript[0].borrow().quote.is_none());
+            let quote = transcript[1].borrow().quote.clone().expect("Expected a quote");
+            assert_eq!(quote.get_label().unwrap(), "hello");
+            quote.clicked();
+            process_events();
+        });
+    }
+
+    #[test]
+    fn floods_collapse_into_one_placeholder() {
+        run_on_gtk_thread(|| {
+            let ui = Ui::new(&["alice"]);
+            let mut limits = models::Limits::default();
+            limits.messages_per_sender = 1;
+            ui.identities[0].0.borrow_mut().set_limits(limits);
+            let carol = Address::for_content("carol");
+
+            for _ in 0..4 {
+                let text_message = comm::client::messages::TextMessage::new(carol, String::from("spam"));
+                ui.receive(Event::ReceivedTextMessage(text_message));
+            }
+            ui.click_row(0);
+
+            let transcript = ui.transcript();
+            assert_eq!(transcript.len(), 2);
+            assert_eq!(message_text(&transcript[0]), "spam");
+            assert!(has_class(transcript[1].borrow().view(), "message--hidden"));
+            assert_eq!(message_text(&transcript[1]), "3 messages hidden");
+        });
+    }
+
+    #[test]
+    fn group_messages_say_who_sent_them() {
+        run_on_gtk_thread(|| {
+            let ui = Ui::new(&["alice"]);
+            let (alice, bob, carol) = (Address::for_content("alice"), Address::for_content("bob"),
+                                       Address::for_content("carol"));
+            let receive = |from: Address, payload: Payload| {
+                let text_message = comm::client::messages::TextMessage::new(from, payload.encode());
+                ui.receive(Event::ReceivedTextMessage(text_message));
+            };
+
+            receive(bob, Payload::Invite(Invite {
+                group_id: String::from("friends"),
+                members: vec![bob.to_str(), carol.to_str(), alice.to_str()]
+            }));
+            receive(carol, Payload::Group(GroupMessage {
+                group_id: String::from("friends"),
+                message_id: Address::for_content("hello").to_str(),
+                text: String::from("hello")
+            }));
+            ui.click_row(0);
+            {
+                let selected = ui.selected();
+                let selected = selected.borrow();
+                assert!(selected.leave.get_visible());
+                let request_text = selected.request_text.get_text().unwrap();
+                assert!(request_text.starts_with("You've been added to a group"));
+            }
+
+            let transcript = ui.transcript();
+            assert_eq!(transcript.len(), 1);
+            let bubble = transcript[0].borrow().view().get_child().unwrap().downcast::<gtk::Box>().unwrap();
+            let sender = bubble.get_children().into_iter()
+                .find(|child| has_class(child, "message__sender"))
+                .and_then(|child| child.downcast::<gtk::Label>().ok())
+                .unwrap();
+            assert_eq!(sender.get_text().unwrap(), carol.to_str());
+        });
+    }
+
+    #[test]
+    fn edits_and_deletions_update_the_bubble() {
+        run_on_gtk_thread(|| {
+            let ui = Ui::new(&["alice"]);
+            let carol = Address::for_content("carol");
+            let receive = |payload: Payload| {
+                let text_message = comm::client::messages::TextMessage::new(carol, payload.encode());
+                ui.receive(Event::ReceivedTextMessage(text_message));
+            };
+
+            receive(Payload::Text(String::from("helo")));
+            ui.click_row(0);
+            let message = ui.transcript()[0].clone();
+            let id = message.borrow().id.clone();
+            assert!(!message.borrow().edited.get_visible());
+
+            receive(Payload::Edit(Edit { message_id: id.clone(), text: String::from("hello") }));
+            assert_eq!(message_text(&message), "hello");
+            assert!(message.borrow().edited.get_visible());
+
+            receive(Payload::Deletion(Deletion { message_id: id }));
+            assert_eq!(message_text(&message), "This message was deleted");
+            assert!(has_class(message.borrow().view(), "message--deleted"));
+            assert!(!message.borrow().edited.get_visible());
+        });
+    }
+
+    #[test]
+    fn typing_is_shown_until_the_message_arrives() {
+        run_on_gtk_thread(|| {
+            let ui = Ui::new(&["alice"]);
+            let carol = Address::for_content("carol");
+            let receive = |payload: Payload| {
+                let text_message = comm::client::messages::TextMessage::new(carol, payload.encode());
+                ui.receive(Event::ReceivedTextMessage(text_message));
+            };
+
+            receive(Payload::Text(String::from("hi")));
+            ui.click_row(0);
+            let typing_indicator = ui.selected().borrow().typing_indicator.clone();
+            assert!(!typing_indicator.get_visible());
+
+            receive(Payload::Typing(Typing { stopped: false }));
+            assert!(typing_indicator.get_visible());
+            assert_eq!(typing_indicator.get_text().unwrap(), format!("{} is typing…", carol.to_str()));
+
+            receive(Payload::Text(String::from("how are you?")));
+            assert!(!typing_indicator.get_visible());
+        });
+    }
+
+    #[test]
+    fn read_receipts_are_shown_apart_from_acknowledgements() {
+        run_on_gtk_thread(|| {
+            let ui = Ui::new(&["alice"]);
+            let bob = Address::for_content("bob");
+            ui.type_recipient(bob);
+            ui.send("hello");
+
+            let message_id = match ui.identities[0].1.tasks()[0] {
+                Task::ScheduleMessageDelivery(_, ref text_message) => text_message.id,
+                _ => panic!("Expected a delivery")
+            };
+            let message = ui.transcript()[0].clone();
+            let status = message.borrow().status.borrow().view().clone();
+
+            let receipt = Payload::ReadReceipt(ReadReceipt { message_ids: vec![message_id.to_str()] });
+            let text_message = comm::client::messages::TextMessage::new(bob, receipt.encode());
+            ui.receive(Event::ReceivedTextMessage(text_message));
+
+            assert_eq!(status.get_text().unwrap(), "Read");
+            assert!(has_class(message.borrow().view(), "message--read"));
+
+            // A late acknowledgement doesn't take it back
+            let ack = comm::client::messages::MessageAcknowledgement { message_id: message_id };
+            ui.receive(Event::ReceivedMessageAcknowledgement(ack));
+            assert_eq!(status.get_text().unwrap(), "Read");
+            assert!(has_class(message.borrow().view(), "message--acknowledged"));
+        });
+    }
+
+    /// Tells `window` it has gained or lost the focus, as the window manager would.
+    fn set_focused(window: &gtk::Window, focused: bool) {
+        let signal = if focused { "focus-in-event" } else { "focus-out-event" };
+        window.emit(signal, &[&gdk::Event::new(gdk::EventType::FocusChange)]).unwrap();
+        process_events();
+    }
+
+    fn sent_read_receipt(tasks: &[Task]) -> bool {
+        tasks.iter().any(|task| match *task {
+            Task::ScheduleMessageDelivery(_, ref text_message) => match Payload::decode(&text_message.text) {
+                Payload::ReadReceipt(_) => true,
+                _ => false
+            },
+            _ => false
+        })
+    }
+
+    #[test]
+    fn messages_are_read_only_while_shown_in_the_focused_window() {
+        run_on_gtk_thread(|| {
+            let ui = Ui::new(&["alice"]);
+            let bob = Address::for_content("bob");
+            ui.type_recipient(bob);
+            ui.send("hello");
+            ui.identities[0].1.tasks();
+
+            let window = gtk::Window::new(gtk::WindowType::Toplevel);
+            window.add(ui.controller.borrow().view());
+            window.show_all();
+            process_events();
+            let receive = |text: &str| {
+                let text_message = comm::client::messages::TextMessage::new(bob, String::from(text));
+                ui.receive(Event::ReceivedTextMessage(text_message));
+            };
+
+            set_focused(&window, false);
+            receive("are you there?");
+            assert!(!sent_read_receipt(&ui.identities[0].1.tasks()));
+
+            set_focused(&window, true);
+            assert!(sent_read_receipt(&ui.identities[0].1.tasks()));
+
+            receive("good");
+            assert!(sent_read_receipt(&ui.identities[0].1.tasks()));
+
+            window.hide();
+            process_events();
+            receive("bye");
+            assert!(!sent_read_receipt(&ui.identities[0].1.tasks()));
+
+            window.destroy();
+        });
+    }
+
+    #[test]
+    fn encryption_is_shown_beside_the_recipient() {
+        run_on_gtk_thread(|| {
+            let ui = Ui::new(&["alice"]);
+            let (alice, bob) = (Address::for_content("alice"), Address::for_content("bob"));
+            ui.identities[0].0.borrow_mut().set_encrypts_messages(true);
+            let tooltip = || {
+                let encryption = ui.selected().borrow().recipient.borrow().encryption.clone();
+                encryption.get_tooltip_text().unwrap()
+            };
+
+            ui.type_recipient(bob);
+            assert_eq!(tooltip(), "Messages aren't end-to-end encrypted yet");
+
+            ui.send("hi");
+            let exchange = match ui.identities[0].1.tasks().as_slice() {
+                [Task::ScheduleMessageDelivery(_, ref exchange)] => exchange.clone(),
+                _ => panic!("Expected a key exchange")
+            };
+            assert!(tooltip().starts_with("Setting up encryption"));
+
+            let mut bob_sessions = Sessions::default();
+            bob_sessions.set_identity(bob, KeyPair::for_secret("bob"));
+            let (_, answer) = bob_sessions.open(exchange);
+            ui.receive(Event::ReceivedTextMessage(answer[0].clone()));
+            assert_eq!(tooltip(), "Messages are end-to-end encrypted");
+
+            let sent = match ui.identities[0].1.tasks().as_slice() {
+                [Task::ScheduleMessageDelivery(_, ref sent)] => sent.clone(),
+                _ => panic!("Expected the waiting message")
+            };
+            let (opened, _) = bob_sessions.open(sent);
+            let opened = opened.unwrap();
+            assert_eq!((opened.sender, opened.text.as_str()), (alice, "hi"));
+            assert_eq!(ui.transcript().len(), 1);
+        });
+    }
+
+    #[test]
+    fn changed_safety_numbers_are_warned_about_in_the_transcript() {
+        run_on_gtk_thread(|| {
+            let ui = Ui::new(&["alice"]);
+            let (alice, bob) = (Address::for_content("alice"), Address::for_content("bob"));
+            ui.identities[0].0.borrow_mut().set_encrypts_messages(true);
+            let verify = ui.selected().borrow().recipient.borrow().verify.clone();
+            let banner = ui.selected().borrow().transcript.borrow().key_changed.clone();
+
+            ui.type_recipient(bob);
+            assert!(!verify.get_sensitive());
+
+            ui.send("hi");
+            let exchange = match ui.identities[0].1.tasks().as_slice() {
+                [Task::ScheduleMessageDelivery(_, ref exchange)] => exchange.clone(),
+                _ => panic!("Expected a key exchange")
+            };
+            let mut bob_sessions = Sessions::default();
+            bob_sessions.set_identity(bob, KeyPair::for_secret("bob"));
+            ui.receive(Event::ReceivedTextMessage(bob_sessions.open(exchange).1.remove(0)));
+            assert!(verify.get_sensitive());
+            assert_eq!(ui.selected_model().borrow().safety_number().unwrap().digits().len(), 60);
+
+            ui.selected_model().update(|c| c.set_verified(true));
+            process_events();
+            assert_eq!(verify.get_tooltip_text().unwrap(), "Safety number verified");
+            assert!(!banner.get_visible());
+
+            // Someone else starts a session in bob's name
+            let mut mallory = Sessions::default();
+            mallory.set_identity(bob, KeyPair::for_secret("mallory"));
+            let hi = comm::client::messages::TextMessage::new(bob, String::from("hi"));
+            ui.receive(Event::ReceivedTextMessage(mallory.seal(alice, hi).remove(0)));
+            assert!(banner.get_visible());
+            assert_eq!(verify.get_tooltip_text().unwrap(), "Safety number changed");
+
+            ui.selected_model().update(|c| c.dismiss_key_change());
+            process_events();
+            assert!(!banner.get_visible());
+            assert_eq!(verify.get_tooltip_text().unwrap(), "Verify safety number");
+        });
+    }
+
+    #[test]
+    fn identity_can_be_picked_until_the_conversation_starts() {
+        run_on_gtk_thread(|| {
+            let ui = Ui::new(&["alice", "bob"]);
+            let identity = ui.selected().borrow().recipient.borrow().identity.clone();
+            assert!(identity.get_parent().is_some());
+            assert_eq!(identity.get_active(), 0);
+
+            identity.set_active(1);
+            process_events();
+
+            assert_eq!(ui.selected_model().borrow().identity(), "bob");
+            assert_eq!(ui.sidebar_titles()[0].1, "bob");
+
+            ui.type_recipient(Address::for_content("carol"));
+            ui.send("hello");
+
+            assert!(ui.identities[0].1.tasks().is_empty());
+            assert_eq!(ui.identities[1].1.tasks().len(), 1);
+            assert!(identity.get_parent().is_none());
+        });
+    }
+
+    #[test]
+    fn selection_follows_the_model_and_the_sidebar() {
+        run_on_gtk_thread(|| {
+            let ui = Ui::new(&["alice"]);
+            ui.conversations.update(|c| c.start_new_conversation());
+            ui.conversations.update(|c| c.start_new_conversation());
+            process_events();
+            assert_eq!(ui.conversations.borrow().selected(), Some(0));
+
+            ui.conversations.update(|c| c.select_conversation(2));
+            process_events();
+
+            let list = ui.controller.borrow().conversation_list.borrow().view().clone();
+            assert_eq!(list.get_selected_row().unwrap().get_index(), 2);
+            let shown = ui.controller.borrow().view().get_child2().unwrap();
+            assert!(shown == ui.selected().borrow().view().clone().upcast::<gtk::Widget>());
+
+            ui.click_row(0);
+
+            assert_eq!(ui.conversations.borrow().selected(), Some(0));
+            let shown = ui.controller.borrow().view().get_child2().unwrap();
+            assert!(shown == ui.selected().borrow().view().clone().upcast::<gtk::Widget>());
+        });
+    }
+
+    #[test]
+    fn controllers_are_freed_with_their_view() {
+        run_on_gtk_thread(|| {
+            let (connection, _events) = models::Connection::new("test");
+            let conversation = Rc::new(RefCell::new(models::Conversation::new(connection.clone())));
+            let message = Rc::new(RefCell::new(models::Message::received(
+                Address::for_content("message"), String::from("hello"))));
+            conversation.update(|c| c.receive_message(message.clone()));
+
+            let keybindings = Rc::new(Keybindings::new(&HashMap::new()));
+            let conversations = Rc::new(RefCell::new(models::ConversationList::new()));
+            let (view, weak_conversation, weak_transcript, weak_message) = {
+                let controller = Conversation::new(
+                    conversation.clone(), conversations, vec![connection], keybindings);
+                let controller_ref = controller.borrow();
+                let weak_message = Rc::downgrade(&controller_ref.transcript.borrow().messages[0]);
+                let weak_transcript = Rc::downgrade(&controller_ref.transcript);
+                (controller_ref.view().clone(), Rc::downgrade(&controller), weak_transcript, weak_message)
+            };
+
+            // Nothing but the view holds the controller now
+            assert!(weak_conversation.upgrade().is_some());
+            assert!(conversation.borrow_mut().observers().len() > 0);
+            assert!(message.borrow_mut().observers().len() > 0);
+
+            view.destroy();
+
+            assert!(weak_conversation.upgrade().is_none());
+            assert!(weak_transcript.upgrade().is_none());
+            assert!(weak_message.upgrade().is_none());
+            assert_eq!(conversation.borrow_mut().observers().len(), 0);
+            assert_eq!(message.borrow_mut().observers().len(), 0);
+        });
     }
 }
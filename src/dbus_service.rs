@@ -0,0 +1,264 @@
+use dbus;
+use dbus::tree::{Factory, MethodErr};
+use std::cell::RefCell;
+use std::process;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::Duration;
+
+use comm::address::Address;
+use headless::Session;
+use models;
+use models::{ConversationListObserver, ConversationObserver, EncryptionState, MessageObserver, Quote,
+    Subscription, Update, Verification};
+
+const BUS_NAME: &str = "com.zacstewart.comm";
+const OBJECT_PATH: &str = "/com/zacstewart/comm";
+const INTERFACE: &str = "com.zacstewart.comm.Messenger";
+
+/// Serves the open profiles on the session bus.
+///
+/// Methods:
+///
+/// * `SendMessage(identity: s, recipient: s, text: s) -> (id: s)` sends as the named profile, or
+///   the first open one if `identity` is empty.
+///
+/// Signals:
+///
+/// * `MessageReceived(identity: s, sender: s, text: s)`
+/// * `MessageAcknowledged(identity: s, id: s)`
+/// * `MessageRead(identity: s, id: s)`, if the recipient sends read receipts
+pub fn run(profiles: Vec<String>) {
+    let mut session = Session::new();
+    if let Err(err) = session.open_all(&profiles) {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+
+    let bus = match dbus::Connection::get_private(dbus::BusType::Session) {
+        Ok(bus) => Rc::new(bus),
+        Err(err) => {
+            eprintln!("Couldn't connect to the session bus: {:?}", err);
+            process::exit(1);
+        }
+    };
+    if let Err(err) = bus.register_name(BUS_NAME, dbus::NameFlag::DoNotQueue as u32) {
+        eprintln!("Couldn't take the name {}: {:?}", BUS_NAME, err);
+        process::exit(1);
+    }
+
+    let factory = Factory::new_fn::<()>();
+    let conversations = session.conversations().clone();
+    let connections = session.connections().clone();
+    let tree = factory.tree(()).add(factory.object_path(OBJECT_PATH, ()).introspectable().add(
+        factory.interface(INTERFACE, ()).add_m(
+            factory.method("SendMessage", (), move |m| {
+                let (identity, recipient, text) = m.msg.read3::<&str, &str, &str>()?;
+                let connection = connections.iter()
+                    .find(|c| identity.is_empty() || c.borrow().name() == identity)
+                    .cloned()
+                    .ok_or_else(|| MethodErr::failed(&format!("{} isn't open", identity)))?;
+                let recipient = Address::from_str(recipient)
+                    .map_err(|_| MethodErr::invalid_arg(&recipient))?;
+
+                let id = send(&conversations, connection, recipient, text);
+                Ok(vec![m.msg.method_return().append1(id.to_str())])
+            }).inarg::<&str, _>("identity").inarg::<&str, _>("recipient").inarg::<&str, _>("text")
+              .outarg::<&str, _>("id")
+        ).add_s(factory.signal("MessageReceived", ())
+                .sarg::<&str, _>("identity").sarg::<&str, _>("sender").sarg::<&str, _>("text"))
+         .add_s(factory.signal("MessageAcknowledged", ())
+                .sarg::<&str, _>("identity").sarg::<&str, _>("id"))
+         .add_s(factory.signal("MessageRead", ())
+                .sarg::<&str, _>("identity").sarg::<&str, _>("id"))
+    ));
+
+    if let Err(err) = tree.set_registered(&bus, true) {
+        eprintln!("Couldn't register {}: {:?}", OBJECT_PATH, err);
+        process::exit(1);
+    }
+    bus.add_handler(tree);
+
+    let _emitter = Emitter::new(bus.clone(), session.conversations().clone());
+
+    for item in bus.iter(50) {
+        if let dbus::ConnectionItem::Nothing = item {
+            session.handle_events(Duration::from_millis(0));
+        }
+    }
+
+    session.close();
+}
+
+/// Sends `text` in the conversation with `recipient` held as `connection`, starting one if needed.
+fn send(conversations: &Rc<RefCell<models::ConversationList>>,
+        connection: Rc<RefCell<models::Connection>>, recipient: Address, text: &str) -> Address {
+    let existing = {
+        let conversations = conversations.borrow();
+        (0..conversations.len()).filter_map(|index| conversations.get(index).cloned()).find(|c| {
+            let c = c.borrow();
+            c.recipient() == Some(recipient) && Rc::ptr_eq(c.connection(), &connection)
+        })
+    };
+
+    let conversation = existing.unwrap_or_else(|| {
+        let conversation = Rc::new(RefCell::new(models::Conversation::new(connection)));
+        conversation.update(|c| c.set_recipient(Some(recipient)));
+        conversations.update(|c| c.add_conversation(conversation.clone()));
+        conversation
+    });
+
+    conversation.update(|c| {
+        c.set_pending_message(String::from(text));
+        c.send_message();
+        c.messages().last().unwrap().borrow().id()
+    })
+}
+
+fn emit(bus: &dbus::Connection, member: &str, args: &[&str]) {
+    let signal = match dbus::Message::new_signal(OBJECT_PATH, INTERFACE, member) {
+        Ok(signal) => signal,
+        Err(err) => {
+            warn!("Couldn't create {} signal: {}", member, err);
+            return;
+        }
+    };
+    let signal = args.iter().fold(signal, |signal, arg| signal.append1(*arg));
+    if bus.send(signal).is_err() {
+        warn!("Couldn't emit {}", member);
+    }
+}
+
+/// Turns model notifications into signals.
+struct Emitter {
+    bus: Rc<dbus::Connection>,
+    conversations: Vec<(Rc<RefCell<models::Conversation>>, Rc<RefCell<ConversationEmitter>>)>,
+    subscriptions: Vec<Subscription>
+}
+
+impl Emitter {
+    fn new(bus: Rc<dbus::Connection>,
+           conversations: Rc<RefCell<models::ConversationList>>) -> Rc<RefCell<Emitter>> {
+        let emitter = Rc::new(RefCell::new(Emitter {
+            bus: bus,
+            conversations: vec![],
+            subscriptions: vec![]
+        }));
+
+        let len = conversations.borrow().len();
+        for index in 0..len {
+            let conversation = conversations.borrow().get(index).unwrap().clone();
+            emitter.borrow_mut().conversation_was_added(conversation);
+        }
+
+        let subscription = models::subscribe(&conversations, emitter.clone());
+        emitter.borrow_mut().subscriptions.push(subscription);
+
+        emitter
+    }
+}
+
+impl ConversationListObserver for Emitter {
+    fn conversation_was_added(&mut self, conversation: Rc<RefCell<models::Conversation>>) {
+        let conversation_emitter = ConversationEmitter::new(self.bus.clone(), conversation.clone());
+        self.conversations.push((conversation, conversation_emitter));
+    }
+
+    fn conversation_was_removed(&mut self, conversation: Rc<RefCell<models::Conversation>>) {
+        self.conversations.retain(|&(ref c, _)| !Rc::ptr_eq(c, &conversation));
+    }
+
+    fn conversation_was_selected(&mut self, _: Rc<RefCell<models::Conversation>>) { }
+    fn request_was_accepted(&mut self, _: Rc<RefCell<models::Conversation>>) { }
+}
+
+struct ConversationEmitter {
+    bus: Rc<dbus::Connection>,
+    conversation: Rc<RefCell<models::Conversation>>,
+    messages: Vec<Rc<RefCell<MessageEmitter>>>,
+    subscriptions: Vec<Subscription>
+}
+
+impl ConversationEmitter {
+    fn new(bus: Rc<dbus::Connection>,
+           conversation: Rc<RefCell<models::Conversation>>) -> Rc<RefCell<ConversationEmitter>> {
+        let emitter = Rc::new(RefCell::new(ConversationEmitter {
+            bus: bus,
+            conversation: conversation.clone(),
+            messages: vec![],
+            subscriptions: vec![]
+        }));
+
+        let subscription = models::subscribe(&conversation, emitter.clone());
+        emitter.borrow_mut().subscriptions.push(subscription);
+
+        emitter
+    }
+}
+
+impl ConversationObserver for ConversationEmitter {
+    fn identity_was_changed(&self, _: String) { }
+    fn recipient_was_changed(&self, _: Option<Address>) { }
+    fn pending_message_was_changed(&self, _: String) { }
+    fn reply_was_changed(&self, _: Option<Quote>) { }
+    fn recipient_typing_was_changed(&self, _: bool) { }
+    fn encryption_was_changed(&self, _: EncryptionState) { }
+    fn verification_was_changed(&self, _: Verification) { }
+    fn members_were_changed(&self, _: Vec<Address>) { }
+
+    fn did_receive_message(&mut self, message: Rc<RefCell<models::Message>>) {
+        let conversation = self.conversation.borrow();
+        let sender = message.borrow().sender().or(conversation.recipient())
+            .map(|address| address.to_str())
+            .unwrap_or_default();
+        emit(&self.bus, "MessageReceived",
+             &[conversation.identity().as_str(), sender.as_str(), message.borrow().text()]);
+    }
+
+    fn did_send_message(&mut self, message: Rc<RefCell<models::Message>>) {
+        let identity = self.conversation.borrow().identity();
+        self.messages.push(MessageEmitter::new(self.bus.clone(), identity, message));
+    }
+}
+
+struct MessageEmitter {
+    bus: Rc<dbus::Connection>,
+    identity: String,
+    id: Address,
+    subscriptions: Vec<Subscription>
+}
+
+impl MessageEmitter {
+    fn new(bus: Rc<dbus::Connection>, identity: String,
+           message: Rc<RefCell<models::Message>>) -> Rc<RefCell<MessageEmitter>> {
+        let emitter = Rc::new(RefCell::new(MessageEmitter {
+            bus: bus,
+            identity: identity,
+            id: message.borrow().id(),
+            subscriptions: vec![]
+        }));
+
+        let subscription = models::subscribe(&message, emitter.clone());
+        emitter.borrow_mut().subscriptions.push(subscription);
+
+        emitter
+    }
+}
+
+impl MessageObserver for MessageEmitter {
+    fn did_receieve_acknowledgement(&self) {
+        emit(&self.bus, "MessageAcknowledged", &[self.identity.as_str(), self.id.to_str().as_str()]);
+    }
+
+    fn reactions_were_changed(&self, _: Vec<(String, usize)>) { }
+    fn text_was_edited(&self, _: String) { }
+    fn was_deleted(&self) { }
+
+    fn did_receive_read_receipt(&self) {
+        emit(&self.bus, "MessageRead", &[self.identity.as_str(), self.id.to_str().as_str()]);
+    }
+
+    fn hidden_count_was_changed(&self, _: usize) { }
+    fn acknowledgements_were_changed(&self, _: usize, _: usize) { }
+    fn did_fail(&self) { }
+}
@@ -0,0 +1,204 @@
+use glib;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+
+/// Snapshot of a dispatcher's queue.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// Values sent but not yet delivered to subscribers.
+    pub queue_depth: usize,
+    /// Deepest the queue has been.
+    pub max_queue_depth: usize,
+    /// Values delivered to subscribers so far.
+    pub dispatched: usize
+}
+
+struct Shared {
+    id: usize,
+    scheduled: AtomicBool,
+    queue_depth: AtomicUsize,
+    max_queue_depth: AtomicUsize,
+    dispatched: AtomicUsize
+}
+
+/// Sending half of a dispatcher. It can be moved to other threads and cloned freely.
+pub struct Sender<T> {
+    sender: mpsc::Sender<T>,
+    shared: Arc<Shared>
+}
+
+impl<T: Send + 'static> Sender<T> {
+    /// Queues `value` for delivery on the main loop. Fails, handing the value back, once the
+    /// dispatcher has been dropped.
+    pub fn send(&self, value: T) -> Result<(), T> {
+        // Counted before it's sent, so the main loop can't take it off the queue first
+        let depth = self.shared.queue_depth.fetch_add(1, Ordering::SeqCst) + 1;
+        if let Err(err) = self.sender.send(value) {
+            self.shared.queue_depth.fetch_sub(1, Ordering::SeqCst);
+            return Err(err.0);
+        }
+        self.shared.max_queue_depth.fetch_max(depth, Ordering::SeqCst);
+
+        // Only one wakeup is pending at a time. It stays until everything queued is delivered.
+        if !self.shared.scheduled.swap(true, Ordering::SeqCst) {
+            let id = self.shared.id;
+            glib::idle_add(move || glib::Continue(drain(id)));
+        }
+
+        Ok(())
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        Sender {
+            sender: self.sender.clone(),
+            shared: self.shared.clone()
+        }
+    }
+}
+
+struct Inner<T> {
+    receiver: mpsc::Receiver<T>,
+    shared: Arc<Shared>,
+    next_subscriber_id: Cell<usize>,
+    subscribers: RefCell<Vec<(usize, Rc<Fn(&T)>)>>
+}
+
+trait Drain {
+    /// Delivers up to `BATCH_SIZE` values. Returns whether the wakeup should run again for the
+    /// rest.
+    fn drain(&self) -> bool;
+}
+
+impl<T> Drain for Inner<T> {
+    fn drain(&self) -> bool {
+        self.shared.scheduled.store(false, Ordering::SeqCst);
+
+        for _ in 0..BATCH_SIZE {
+            let value = match self.receiver.try_recv() {
+                Ok(value) => value,
+                Err(_) => return false
+            };
+            self.shared.queue_depth.fetch_sub(1, Ordering::SeqCst);
+
+            // Subscribers may subscribe or unsubscribe while being notified
+            let subscribers: Vec<Rc<Fn(&T)>> = self.subscribers.borrow().iter()
+                .map(|&(_, ref subscriber)| subscriber.clone())
+                .collect();
+            for subscriber in subscribers {
+                subscriber(&value);
+            }
+
+            self.shared.dispatched.fetch_add(1, Ordering::SeqCst);
+        }
+
+        // The rest waits for the next iteration, unless a value sent meanwhile has already
+        // scheduled another wakeup for it
+        self.shared.queue_depth.load(Ordering::SeqCst) > 0
+            && !self.shared.scheduled.swap(true, Ordering::SeqCst)
+    }
+}
+
+/// Delivers values sent from any thread to subscribers on the GTK main loop, in the order they
+/// were sent. Each main loop iteration delivers at most `BATCH_SIZE` of them, so a flood of
+/// values can't keep the main loop from redrawing or handling input.
+///
+/// glib 0.4 has no main context channel, so wakeups go through `glib::idle_add`, which only takes
+/// `Send` closures. The closure carries the dispatcher's id and looks it up in a registry local
+/// to the main thread.
+pub struct Dispatcher<T> {
+    inner: Rc<Inner<T>>,
+    sender: Sender<T>
+}
+
+impl<T: Send + 'static> Dispatcher<T> {
+    pub fn new() -> Dispatcher<T> {
+        let (sender, receiver) = mpsc::channel();
+        let shared = Arc::new(Shared {
+            id: NEXT_ID.fetch_add(1, Ordering::SeqCst),
+            scheduled: AtomicBool::new(false),
+            queue_depth: AtomicUsize::new(0),
+            max_queue_depth: AtomicUsize::new(0),
+            dispatched: AtomicUsize::new(0)
+        });
+
+        let inner = Rc::new(Inner {
+            receiver: receiver,
+            shared: shared.clone(),
+            next_subscriber_id: Cell::new(0),
+            subscribers: RefCell::new(vec![])
+        });
+
+        let drain: Rc<Drain> = inner.clone();
+        DISPATCHERS.with(|dispatchers| {
+            dispatchers.borrow_mut().insert(shared.id, Rc::downgrade(&drain));
+        });
+
+        Dispatcher {
+            inner: inner,
+            sender: Sender {
+                sender: sender,
+                shared: shared
+            }
+        }
+    }
+
+    pub fn sender(&self) -> Sender<T> {
+        self.sender.clone()
+    }
+
+    /// Calls `subscriber` with every value dispatched from now on. Returns an id to unsubscribe
+    /// with.
+    pub fn subscribe<F: Fn(&T) + 'static>(&self, subscriber: F) -> usize {
+        let id = self.inner.next_subscriber_id.get();
+        self.inner.next_subscriber_id.set(id + 1);
+        self.inner.subscribers.borrow_mut().push((id, Rc::new(subscriber)));
+        id
+    }
+
+    pub fn unsubscribe(&self, id: usize) {
+        self.inner.subscribers.borrow_mut().retain(|&(subscriber_id, _)| subscriber_id != id);
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        let shared = &self.inner.shared;
+        Metrics {
+            queue_depth: shared.queue_depth.load(Ordering::SeqCst),
+            max_queue_depth: shared.max_queue_depth.load(Ordering::SeqCst),
+            dispatched: shared.dispatched.load(Ordering::SeqCst)
+        }
+    }
+}
+
+impl<T> Drop for Dispatcher<T> {
+    fn drop(&mut self) {
+        let id = self.inner.shared.id;
+        DISPATCHERS.with(|dispatchers| {
+            dispatchers.borrow_mut().remove(&id);
+        });
+    }
+}
+
+fn drain(id: usize) -> bool {
+    let dispatcher = DISPATCHERS.with(|dispatchers| {
+        dispatchers.borrow().get(&id).and_then(|dispatcher| dispatcher.upgrade())
+    });
+
+    match dispatcher {
+        Some(dispatcher) => dispatcher.drain(),
+        None => false
+    }
+}
+
+/// How many values are delivered per main loop iteration.
+const BATCH_SIZE: usize = 64;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+thread_local!(
+    static DISPATCHERS: RefCell<HashMap<usize, Weak<Drain>>> = RefCell::new(HashMap::new());
+);
@@ -0,0 +1,304 @@
+use gdk;
+use gio;
+use gio::prelude::*;
+use gtk;
+use gtk::prelude::*;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::{env, thread};
+use std::path;
+
+use comm;
+use controllers;
+use dispatcher::Dispatcher;
+use keybindings::{Action, Keybindings};
+use models;
+use models::Update;
+use {config_file, profile_dir};
+
+/// Opens the conversations window with the primary profile and any others in `profiles`.
+pub fn run(profiles: Vec<String>) {
+    let application = gtk::Application::new("com.zacstewart.comm",
+                                            gio::ApplicationFlags::empty())
+        .expect("Initialization failed...");
+
+    application.connect_startup(move |app| {
+        build_ui(app, profiles.clone());
+        app.activate();
+    });
+    application.connect_activate(|_| {
+        debug!("Application activated");
+    });
+
+    application.run(&[]);
+}
+
+/// A profile that is open in the window, with its own connection and configuration window.
+struct Identity {
+    connection: Rc<RefCell<models::Connection>>,
+    configuration_controller: Rc<RefCell<controllers::Configuration>>
+}
+
+struct App {
+    configuration: Rc<RefCell<models::Configuration>>,
+    config_file_path: path::PathBuf,
+    conversations: Rc<RefCell<models::ConversationList>>,
+    conversations_controller: Rc<RefCell<controllers::Conversations>>,
+    identities: RefCell<Vec<Identity>>,
+    event_handler: Rc<RefCell<models::EventHandler>>,
+    events: Dispatcher<(String, comm::client::Event)>
+}
+
+impl App {
+    /// Brings the profile online alongside any already open, or presents its configuration window
+    /// if it's already open.
+    fn open_identity(app: &Rc<App>, name: &str) {
+        if let Some(identity) = app.identities.borrow().iter().find(|i| i.connection.borrow().name() == name) {
+            identity.configuration_controller.borrow().view().present();
+            return;
+        }
+
+        debug!("Opening identity for profile {}", name);
+        app.configuration.borrow_mut().add_profile(name);
+
+        let (connection, events) = models::Connection::new(name);
+
+        let a = Rc::downgrade(app);
+        let from = String::from(name);
+        let on_profile_selected = move |to: String| {
+            // The configuration window goes away with the identity, so switch once its signal
+            // handler has returned.
+            let a = a.clone();
+            let from = from.clone();
+            gtk::idle_add(move || {
+                if let Some(app) = a.upgrade() {
+                    App::switch_identity(&app, &from, &to);
+                }
+                gtk::Continue(false)
+            });
+        };
+
+        let a = Rc::downgrade(app);
+        let on_profile_opened = move |name: String| {
+            if let Some(app) = a.upgrade() {
+                App::open_identity(&app, &name);
+            }
+        };
+
+        let configuration_controller = controllers::Configuration::new(
+            connection.clone(), app.conversations.clone(), app.configuration.clone(),
+            app.config_file_path.clone(), on_profile_selected, on_profile_opened);
+
+        let store = models::MessageStore::new(
+            profile_dir(&app.config_file_path, name).join("messages.yml"));
+
+        app.event_handler.borrow_mut().add_identity(connection.clone(), store);
+
+        app.conversations_controller.borrow_mut().add_identity(connection.clone());
+        if app.conversations.borrow().selected().is_none() {
+            app.conversations.update(|c| c.start_new_conversation());
+        }
+
+        configuration_controller.borrow().view().show_all();
+
+        let identity = String::from(name);
+        let sender = app.events.sender();
+        thread::spawn(move || {
+            for event in events.iter() {
+                // The dispatcher is gone once the application has shut down
+                if sender.send((identity.clone(), event)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        app.identities.borrow_mut().push(Identity {
+            connection: connection,
+            configuration_controller: configuration_controller
+        });
+    }
+
+    /// Shuts down the identity's connection and removes its conversations from the window.
+    fn close_identity(&self, name: &str) {
+        let position = self.identities.borrow().iter().position(|i| i.connection.borrow().name() == name);
+        if let Some(index) = position {
+            let identity = self.identities.borrow_mut().remove(index);
+
+            self.event_handler.borrow_mut().remove_identity(name);
+
+            identity.connection.update(|c| c.shutdown());
+            self.conversations_controller.borrow_mut().remove_identity(&identity.connection);
+            identity.configuration_controller.borrow().view().destroy();
+        }
+    }
+
+    fn close_all_identities(&self) {
+        let names: Vec<String> = self.identities.borrow().iter()
+            .map(|i| String::from(i.connection.borrow().name()))
+            .collect();
+        for name in names {
+            self.close_identity(&name);
+        }
+    }
+
+    fn switch_identity(app: &Rc<App>, from: &str, to: &str) {
+        let is_primary = app.identities.borrow().first()
+            .map(|i| i.connection.borrow().name() == from)
+            .unwrap_or(false);
+
+        app.close_identity(from);
+        if is_primary {
+            app.configuration.borrow_mut().select_profile(to);
+        }
+        App::open_identity(app, to);
+
+        if let Err(err) = app.configuration.borrow().save(app.config_file_path.clone()) {
+            warn!("Failed to save configuration: {}", err);
+        }
+    }
+}
+
+fn build_ui(application: &gtk::Application, profiles: Vec<String>) {
+    let main_window = gtk::ApplicationWindow::new(application);
+    main_window.set_title("Comm Messenger");
+    main_window.set_default_size(700, 400);
+    main_window.set_position(gtk::WindowPosition::Center);
+
+    let config_file_path = config_file();
+    let configuration = Rc::new(RefCell::new(models::Configuration::load_from_config_or_empty(config_file_path.clone())));
+    if let Some(name) = profiles.first() {
+        configuration.borrow_mut().select_profile(name);
+    }
+    let primary_profile = configuration.borrow().current_profile_name();
+
+    let keybindings = Rc::new(Keybindings::new(configuration.borrow().keybindings()));
+    let shortcuts_controller = controllers::Shortcuts::new(keybindings.clone());
+    shortcuts_controller.borrow().view().set_transient_for(&main_window);
+
+    let conversations = Rc::new(RefCell::new(models::ConversationList::new()));
+    let conversations_controller = controllers::Conversations::new(conversations.clone(), keybindings.clone());
+    let event_handler = Rc::new(RefCell::new(models::EventHandler::new(conversations.clone())));
+
+    main_window.add(conversations_controller.borrow().view());
+    main_window.show_all();
+
+    let events = Dispatcher::new();
+    let eh = event_handler.clone();
+    events.subscribe(move |&(ref identity, ref event): &(String, comm::client::Event)| {
+        eh.borrow().handle_event(identity, event.clone());
+    });
+    // What the user does, such as sending, is saved within a second
+    let eh = event_handler.clone();
+    gtk::timeout_add_seconds(1, move || {
+        models::deliver_stray_notifications();
+        eh.borrow().save_changes();
+        gtk::Continue(true)
+    });
+
+    let app = Rc::new(App {
+        configuration: configuration,
+        config_file_path: config_file_path,
+        conversations: conversations,
+        conversations_controller: conversations_controller,
+        identities: RefCell::new(vec![]),
+        event_handler: event_handler,
+        events: events
+    });
+
+    App::open_identity(&app, &primary_profile);
+    for name in profiles.iter().skip(1) {
+        App::open_identity(&app, name);
+    }
+
+    let a = app.clone();
+    main_window.connect_delete_event(move |win, _| {
+        a.close_all_identities();
+        debug!("Event queue at shutdown: {:?}", a.events.metrics());
+        win.destroy();
+        gtk::Inhibit(true)
+    });
+
+    let css_provider = gtk::CssProvider::new();
+    let screen = gdk::Screen::get_default().expect("Couldn't get default screen");
+    gtk::StyleContext::add_provider_for_screen(&screen,
+                                               &css_provider,
+                                               gtk::STYLE_PROVIDER_PRIORITY_APPLICATION);
+
+    let resources_dir = env::var("COMM_RESOURCES_DIR")
+        .unwrap_or(String::from("resources"));
+    let resources_dir = path::Path::new(resources_dir.as_str());
+
+    let stylesheet_path = resources_dir.join("style.css");
+
+    match css_provider.load_from_path(stylesheet_path.to_str().unwrap()) {
+        Ok(_) => debug!("Loaded stylesheet: {:?}", stylesheet_path),
+        Err(err) => warn!("Failed to load stylesheet: {}", err)
+    }
+
+    // Register actions and bind them to their accelerators
+
+    add_action(application, Action::ReloadStyle, move || {
+        match css_provider.load_from_path(stylesheet_path.to_str().unwrap()) {
+            Ok(_) => debug!("Reloaded stylesheet: {:?}", stylesheet_path),
+            Err(err) => warn!("Failed to load stylesheet: {}", err)
+        }
+    });
+
+    let a = app.clone();
+    add_action(application, Action::NextConversation, move || {
+        a.conversations.update(|c| c.select_relative(1));
+    });
+
+    let a = app.clone();
+    add_action(application, Action::PreviousConversation, move || {
+        a.conversations.update(|c| c.select_relative(-1));
+    });
+
+    for n in 1..10 {
+        let a = app.clone();
+        add_action(application, Action::SelectConversation(n), move || {
+            let len = a.conversations.borrow().len();
+            if n <= len {
+                a.conversations.update(|c| c.select_conversation(n - 1));
+            }
+        });
+    }
+
+    let a = app.clone();
+    add_action(application, Action::NewConversation, move || {
+        a.conversations.update(|c| c.start_new_conversation());
+        a.conversations_controller.borrow().focus_recipient();
+    });
+
+    let a = app.clone();
+    add_action(application, Action::CloseConversation, move || {
+        let selected = a.conversations.borrow().selected();
+        if let Some(index) = selected {
+            a.conversations.update(|c| c.remove_conversation(index));
+        }
+    });
+
+    let a = app.clone();
+    add_action(application, Action::FocusSearch, move || {
+        a.conversations_controller.borrow().focus_search();
+    });
+
+    let a = app.clone();
+    add_action(application, Action::FocusComposer, move || {
+        a.conversations_controller.borrow().focus_composer();
+    });
+
+    add_action(application, Action::ShowShortcuts, move || {
+        let view = shortcuts_controller.borrow().view().clone();
+        view.show_all();
+        view.present();
+    });
+
+    keybindings.install(application);
+}
+
+fn add_action<F: Fn() + 'static>(application: &gtk::Application, action: Action, activate: F) {
+    let simple_action = gio::SimpleAction::new(action.name(), None);
+    simple_action.connect_activate(move |_, _| activate());
+    application.add_action(&simple_action);
+}
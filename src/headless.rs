@@ -0,0 +1,114 @@
+use std::cell::RefCell;
+use std::path;
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use comm;
+use models;
+use models::Update;
+use {config_file, profile_dir};
+
+/// Profiles brought online without a window, for the frontends that run without GTK. Events are
+/// handled on the thread that owns the session whenever it calls `handle_events`.
+pub struct Session {
+    configuration: models::Configuration,
+    config_file_path: path::PathBuf,
+    conversations: Rc<RefCell<models::ConversationList>>,
+    event_handler: models::EventHandler,
+    connections: Vec<Rc<RefCell<models::Connection>>>,
+    events: mpsc::Receiver<(String, comm::client::Event)>,
+    event_sender: mpsc::Sender<(String, comm::client::Event)>
+}
+
+impl Session {
+    pub fn new() -> Session {
+        let config_file_path = config_file();
+        let configuration = models::Configuration::load_from_config_or_empty(config_file_path.clone());
+        let conversations = Rc::new(RefCell::new(models::ConversationList::new()));
+        let event_handler = models::EventHandler::new(conversations.clone());
+        let (event_sender, events) = mpsc::channel();
+
+        Session {
+            configuration: configuration,
+            config_file_path: config_file_path,
+            conversations: conversations,
+            event_handler: event_handler,
+            connections: vec![],
+            events: events,
+            event_sender: event_sender
+        }
+    }
+
+    pub fn conversations(&self) -> &Rc<RefCell<models::ConversationList>> {
+        &self.conversations
+    }
+
+    pub fn connections(&self) -> &Vec<Rc<RefCell<models::Connection>>> {
+        &self.connections
+    }
+
+    /// Opens each of `profiles`, or the current profile if there are none.
+    pub fn open_all(&mut self, profiles: &[String]) -> Result<(), String> {
+        if profiles.is_empty() {
+            let name = self.configuration.current_profile_name();
+            return self.open(&name);
+        }
+        for name in profiles.iter() {
+            self.open(name)?;
+        }
+        Ok(())
+    }
+
+    /// Loads the profile's conversations and connects it to the network.
+    pub fn open(&mut self, name: &str) -> Result<(), String> {
+        let profile = self.configuration.profile(name);
+        let (connection, events) = models::Connection::new(name);
+        connection.update(|c| c.start(&profile))
+            .map_err(|err| format!("Couldn't start profile {}: {}", name, err))?;
+
+        let store = models::MessageStore::new(
+            profile_dir(&self.config_file_path, name).join("messages.yml"));
+        self.event_handler.add_identity(connection.clone(), store);
+        if self.conversations.borrow().selected().is_none() {
+            self.conversations.update(|c| c.start_new_conversation());
+        }
+
+        let identity = String::from(name);
+        let sender = self.event_sender.clone();
+        thread::spawn(move || {
+            for event in events.iter() {
+                // The session is gone once the frontend has shut down
+                if sender.send((identity.clone(), event)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        self.connections.push(connection);
+        Ok(())
+    }
+
+    /// Handles every event that has arrived, waiting up to `timeout` for the first one, and saves
+    /// whatever has changed since the last call.
+    pub fn handle_events(&self, timeout: Duration) {
+        if let Ok((identity, event)) = self.events.recv_timeout(timeout) {
+            self.event_handler.handle_event(&identity, event);
+            while let Ok((identity, event)) = self.events.try_recv() {
+                self.event_handler.handle_event(&identity, event);
+            }
+        }
+        models::deliver_stray_notifications();
+        self.event_handler.save_changes();
+    }
+
+    /// Saves every profile's conversations and disconnects them.
+    pub fn close(&mut self) {
+        for connection in self.connections.drain(..) {
+            let name = String::from(connection.borrow().name());
+            self.event_handler.remove_identity(&name);
+            connection.update(|c| c.shutdown());
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use gdk;
+use gtk;
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Action {
+    NextConversation,
+    PreviousConversation,
+    /// Selects the nth conversation from the top of the sidebar, counting from 1.
+    SelectConversation(usize),
+    NewConversation,
+    CloseConversation,
+    FocusSearch,
+    FocusComposer,
+    Send,
+    ReloadStyle,
+    ShowShortcuts
+}
+
+pub const ACTIONS: &[Action] = &[
+    Action::NextConversation,
+    Action::PreviousConversation,
+    Action::SelectConversation(1),
+    Action::SelectConversation(2),
+    Action::SelectConversation(3),
+    Action::SelectConversation(4),
+    Action::SelectConversation(5),
+    Action::SelectConversation(6),
+    Action::SelectConversation(7),
+    Action::SelectConversation(8),
+    Action::SelectConversation(9),
+    Action::NewConversation,
+    Action::CloseConversation,
+    Action::FocusSearch,
+    Action::FocusComposer,
+    Action::Send,
+    Action::ReloadStyle,
+    Action::ShowShortcuts
+];
+
+const SELECT_CONVERSATION_NAMES: [&str; 9] = [
+    "select-conversation-1", "select-conversation-2", "select-conversation-3",
+    "select-conversation-4", "select-conversation-5", "select-conversation-6",
+    "select-conversation-7", "select-conversation-8", "select-conversation-9"
+];
+
+const SELECT_CONVERSATION_ACCELS: [&str; 9] = [
+    "<Alt>1", "<Alt>2", "<Alt>3", "<Alt>4", "<Alt>5", "<Alt>6", "<Alt>7", "<Alt>8", "<Alt>9"
+];
+
+impl Action {
+    pub fn from_name(name: &str) -> Option<Action> {
+        ACTIONS.iter().find(|action| action.name() == name).cloned()
+    }
+
+    /// Name of the action as registered on the application and as written in `comm.yml`.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Action::NextConversation => "next-conversation",
+            Action::PreviousConversation => "previous-conversation",
+            Action::SelectConversation(n) => SELECT_CONVERSATION_NAMES[n - 1],
+            Action::NewConversation => "new-conversation",
+            Action::CloseConversation => "close-conversation",
+            Action::FocusSearch => "focus-search",
+            Action::FocusComposer => "focus-composer",
+            Action::Send => "send",
+            Action::ReloadStyle => "reload-style",
+            Action::ShowShortcuts => "show-shortcuts"
+        }
+    }
+
+    pub fn detailed_name(&self) -> String {
+        format!("app.{}", self.name())
+    }
+
+    pub fn description(&self) -> String {
+        match *self {
+            Action::NextConversation => String::from("Next conversation"),
+            Action::PreviousConversation => String::from("Previous conversation"),
+            Action::SelectConversation(n) => format!("Go to conversation {}", n),
+            Action::NewConversation => String::from("New conversation"),
+            Action::CloseConversation => String::from("Close conversation"),
+            Action::FocusSearch => String::from("Search conversations"),
+            Action::FocusComposer => String::from("Write a message"),
+            Action::Send => String::from("Send message"),
+            Action::ReloadStyle => String::from("Reload stylesheet"),
+            Action::ShowShortcuts => String::from("Keyboard shortcuts")
+        }
+    }
+
+    /// Whether the accelerator is installed application-wide. Sending is handled by the message
+    /// entry itself so that pressing Return in other entries doesn't send the pending message.
+    pub fn is_global(&self) -> bool {
+        *self != Action::Send
+    }
+
+    fn default_accels(&self) -> Vec<&'static str> {
+        match *self {
+            Action::NextConversation => vec!["<Primary>Tab", "<Primary>Page_Down"],
+            Action::PreviousConversation => vec!["<Primary><Shift>ISO_Left_Tab", "<Primary>Page_Up"],
+            Action::SelectConversation(n) => vec![SELECT_CONVERSATION_ACCELS[n - 1]],
+            Action::NewConversation => vec!["<Primary>n"],
+            Action::CloseConversation => vec!["<Primary>w"],
+            Action::FocusSearch => vec!["<Primary>f"],
+            Action::FocusComposer => vec!["<Primary>l"],
+            Action::Send => vec!["Return", "KP_Enter"],
+            Action::ReloadStyle => vec!["F5"],
+            Action::ShowShortcuts => vec!["<Primary>question", "F1"]
+        }
+    }
+}
+
+pub struct Keybindings {
+    accels: HashMap<Action, Vec<String>>
+}
+
+impl Keybindings {
+    /// Builds the keybindings from the defaults, replacing any that are overridden by name. An
+    /// empty override unbinds the action.
+    pub fn new(overrides: &HashMap<String, String>) -> Keybindings {
+        let mut accels: HashMap<Action, Vec<String>> = ACTIONS.iter().map(|action| {
+            (*action, action.default_accels().iter().map(|a| a.to_string()).collect())
+        }).collect();
+
+        for (name, accel) in overrides.iter() {
+            match Action::from_name(name) {
+                Some(action) => {
+                    if accel.is_empty() {
+                        accels.insert(action, vec![]);
+                    } else if Self::parse(accel).is_some() {
+                        accels.insert(action, vec![accel.clone()]);
+                    } else {
+                        warn!("Invalid accelerator for {}: {:?}", name, accel);
+                    }
+                }
+                None => warn!("Unknown action in keybindings: {}", name)
+            }
+        }
+
+        Keybindings {
+            accels: accels
+        }
+    }
+
+    pub fn accels(&self, action: Action) -> &[String] {
+        self.accels.get(&action).map(|accels| accels.as_slice()).unwrap_or(&[])
+    }
+
+    /// Human readable label for the first accelerator bound to `action`.
+    pub fn label(&self, action: Action) -> Option<String> {
+        self.accels(action).first()
+            .and_then(|accel| Self::parse(accel))
+            .and_then(|(key, mods)| gtk::accelerator_get_label(key, mods))
+    }
+
+    pub fn install(&self, application: &gtk::Application) {
+        for action in ACTIONS.iter().filter(|action| action.is_global()) {
+            let accels: Vec<&str> = self.accels(*action).iter().map(|a| a.as_str()).collect();
+            application.set_accels_for_action(&action.detailed_name(), &accels);
+        }
+    }
+
+    pub fn matches(&self, action: Action, event: &gdk::EventKey) -> bool {
+        let keyval = gdk::keyval_to_lower(event.get_keyval());
+        let state = event.get_state() & gtk::accelerator_get_default_mod_mask();
+        self.accels(action).iter().filter_map(|accel| Self::parse(accel)).any(|(key, mods)| {
+            gdk::keyval_to_lower(key) == keyval && mods == state
+        })
+    }
+
+    fn parse(accel: &str) -> Option<(u32, gdk::ModifierType)> {
+        match gtk::accelerator_parse(accel) {
+            (0, _) => None,
+            parsed => Some(parsed)
+        }
+    }
+}
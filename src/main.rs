@@ -1,104 +1,103 @@
 #[macro_use] extern crate log;
-#[macro_use] extern crate serde_derive;
 extern crate comm;
+extern crate comm_client_core;
 extern crate env_logger;
-extern crate gdk;
-extern crate gio;
-extern crate glib;
-extern crate gtk;
-extern crate serde_yaml;
-
-use gtk::prelude::*;
-use gio::prelude::*;
-use std::cell::RefCell;
-use std::rc::Rc;
-use std::sync::mpsc;
-use std::{env, thread};
+#[cfg(feature = "dbus")] extern crate dbus;
+#[cfg(feature = "gui")] extern crate gdk;
+#[cfg(feature = "gui")] extern crate gio;
+#[cfg(feature = "gui")] extern crate glib;
+#[cfg(feature = "gui")] extern crate gtk;
+#[cfg(all(test, feature = "gui"))] #[macro_use] extern crate lazy_static;
+
+use std::{env, process};
 use std::path;
 
-mod models;
+#[cfg(feature = "cli")]
+mod cli;
+#[cfg(feature = "gui")]
 mod controllers;
+#[cfg(feature = "dbus")]
+mod dbus_service;
+#[cfg(feature = "gui")]
+mod dispatcher;
+#[cfg(feature = "gui")]
+mod gui;
+#[cfg(any(feature = "cli", feature = "dbus"))]
+mod headless;
+#[cfg(feature = "gui")]
+mod keybindings;
+#[cfg(all(test, feature = "gui"))]
+mod testing;
+
+use comm_client_core::models;
+
+#[cfg(not(any(feature = "gui", feature = "cli", feature = "dbus")))]
+compile_error!("Enable at least one frontend: the gui, cli or dbus feature");
 
 fn main() {
     env_logger::init().unwrap();
 
-    let application = gtk::Application::new("com.zacstewart.comm",
-                                            gio::ApplicationFlags::empty())
-        .expect("Initialization failed...");
-
-    application.connect_startup(move |app| {
-        build_ui(app);
-        app.activate();
-    });
-    application.connect_activate(|_| {
-        debug!("Application activated");
-    });
-
-    application.run(&[]);
-}
-
-fn build_ui(application: &gtk::Application) {
-    let main_window = gtk::ApplicationWindow::new(application);
-    main_window.set_title("Comm Messenger");
-    main_window.set_default_size(700, 400);
-    main_window.set_position(gtk::WindowPosition::Center);
-    main_window.connect_delete_event(|win, _| {
-        win.destroy();
-        gtk::Inhibit(true)
-    });
-
-    let config_file_path = config_file();
-    let configuration = Rc::new(RefCell::new(models::Configuration::load_from_config_or_empty(config_file_path.clone())));
-    let (connection, events) = models::Connection::new();
-    let conversations = Rc::new(RefCell::new(models::ConversationList::new(connection.clone())));
-
-    let configuration_controller = controllers::Configuration::new(connection.clone(), configuration.clone(), config_file_path);
-    let conversations_controller = controllers::Conversations::new(connection.clone(), conversations.clone());
-
-    let event_handler = models::EventHandler::new(conversations);
-
-    main_window.add(conversations_controller.borrow().view());
-    main_window.show_all();
-    configuration_controller.borrow().view().show_all();
-
-    let (tx, rx) = mpsc::channel();
-    GLOBAL.with(move |global| {
-        *global.borrow_mut() = Some((event_handler, rx));
-    });
-
-    thread::spawn(move || {
-        for event in events.iter() {
-            tx.send(event).unwrap();
-            glib::idle_add(handle_event);
+    let options = Options::from_args();
+    let frontend = options.frontend.clone()
+        .unwrap_or_else(|| String::from(frontends()[0]));
+
+    match frontend.as_str() {
+        #[cfg(feature = "gui")]
+        "gui" => gui::run(options.profiles),
+        #[cfg(feature = "cli")]
+        "cli" => cli::run(options.profiles),
+        #[cfg(feature = "dbus")]
+        "dbus" => dbus_service::run(options.profiles),
+        other => {
+            eprintln!("The {} frontend isn't available in this build. Try one of: {}",
+                      other, frontends().join(", "));
+            process::exit(2);
         }
-    });
-
-    let css_provider = gtk::CssProvider::new();
-    let screen = gdk::Screen::get_default().expect("Couldn't get default screen");
-    gtk::StyleContext::add_provider_for_screen(&screen,
-                                               &css_provider,
-                                               gtk::STYLE_PROVIDER_PRIORITY_APPLICATION);
-
-    let resources_dir = env::var("COMM_RESOURCES_DIR")
-        .unwrap_or(String::from("resources"));
-    let resources_dir = path::Path::new(resources_dir.as_str());
-
-    let stylesheet_path = resources_dir.join("style.css");
+    }
+}
 
-    match css_provider.load_from_path(stylesheet_path.to_str().unwrap()) {
-        Ok(_) => debug!("Loaded stylesheet: {:?}", stylesheet_path),
-        Err(err) => warn!("Failed to load stylesheet: {}", err)
+/// Frontends compiled into this build, the default first.
+fn frontends() -> Vec<&'static str> {
+    let mut frontends = vec![];
+    if cfg!(feature = "gui") {
+        frontends.push("gui");
+    }
+    if cfg!(feature = "cli") {
+        frontends.push("cli");
+    }
+    if cfg!(feature = "dbus") {
+        frontends.push("dbus");
     }
+    frontends
+}
+
+struct Options {
+    profiles: Vec<String>,
+    frontend: Option<String>
+}
 
-    main_window.connect_key_press_event(move |_, event| {
-        if event.get_keyval() == 65474 { // F5
-            match css_provider.load_from_path(stylesheet_path.to_str().unwrap()) {
-                Ok(_) => debug!("Reloaded stylesheet: {:?}", stylesheet_path),
-                Err(err) => warn!("Failed to load stylesheet: {}", err)
+impl Options {
+    /// Reads the profiles to open from `--profile NAME` or `--profile=NAME`, which may be
+    /// repeated, and the frontend to run from `--frontend NAME` or `--frontend=NAME`.
+    fn from_args() -> Options {
+        let mut options = Options {
+            profiles: vec![],
+            frontend: None
+        };
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            if arg == "--profile" {
+                options.profiles.extend(args.next());
+            } else if arg.starts_with("--profile=") {
+                options.profiles.push(String::from(&arg["--profile=".len()..]));
+            } else if arg == "--frontend" {
+                options.frontend = args.next();
+            } else if arg.starts_with("--frontend=") {
+                options.frontend = Some(String::from(&arg["--frontend=".len()..]));
             }
         }
-        gtk::Inhibit(false)
-    });
+        options
+    }
 }
 
 fn config_file() -> path::PathBuf {
@@ -112,17 +111,11 @@ fn config_file() -> path::PathBuf {
     }
 }
 
-fn handle_event() -> glib::Continue {
-    GLOBAL.with(|global| {
-        if let Some((ref event_handler, ref events)) = *global.borrow() {
-            if let Ok(event) = events.try_recv() {
-                event_handler.handle_event(event);
-            }
-        }
-    });
-    glib::Continue(false)
+/// Directory holding the data that belongs to a single profile, next to the config file.
+fn profile_dir(config_file_path: &path::Path, profile_name: &str) -> path::PathBuf {
+    config_file_path.parent()
+        .unwrap_or(path::Path::new("."))
+        .join("profiles")
+        .join(profile_name)
 }
 
-thread_local!(
-    pub static GLOBAL: RefCell<Option<(models::EventHandler, comm::client::Events)>> = RefCell::new(None);
-);
@@ -0,0 +1,62 @@
+use gtk;
+use std::any::Any;
+use std::env;
+use std::panic;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+
+pub use comm_client_core::testing::{started_connection, FakeTransport};
+
+type Job = (Box<FnMut() + Send>, mpsc::Sender<Result<(), Box<Any + Send>>>);
+
+lazy_static! {
+    /// GTK may only be used from the thread that initialised it, but every test runs on a thread
+    /// of its own, so UI tests are all sent to this one. `None` when there's no display.
+    static ref GTK_THREAD: Mutex<Option<mpsc::Sender<Job>>> = {
+        let (sender, jobs) = mpsc::channel::<Job>();
+        let (initialised, is_initialised) = mpsc::channel();
+        thread::spawn(move || {
+            let available = gtk::init().is_ok();
+            initialised.send(available).unwrap();
+            if !available {
+                return;
+            }
+
+            for (mut test, result) in jobs.iter() {
+                let outcome = panic::catch_unwind(panic::AssertUnwindSafe(|| test()));
+                process_events();
+                let _ = result.send(outcome);
+            }
+        });
+
+        Mutex::new(if is_initialised.recv().unwrap_or(false) { Some(sender) } else { None })
+    };
+}
+
+/// Runs `test` on the GTK thread and passes on any panic. Without a display the test fails, unless
+/// `SKIP_UI_TESTS` is set; run them under `xvfb-run` or the Broadway backend (see the README).
+pub fn run_on_gtk_thread<F: FnOnce() + Send + 'static>(test: F) {
+    let gtk_thread = match *GTK_THREAD.lock().unwrap() {
+        Some(ref gtk_thread) => gtk_thread.clone(),
+        None if env::var_os("SKIP_UI_TESTS").is_some() => {
+            warn!("Skipping UI test: GTK couldn't be initialised");
+            return;
+        }
+        None => panic!("GTK couldn't be initialised. Run the UI tests under xvfb-run or Broadway, \
+                        or set SKIP_UI_TESTS to skip them")
+    };
+
+    let mut test = Some(test);
+    let (result, outcome) = mpsc::channel();
+    gtk_thread.send((Box::new(move || (test.take().unwrap())()), result)).unwrap();
+    if let Err(panic) = outcome.recv().unwrap() {
+        panic::resume_unwind(panic);
+    }
+}
+
+/// Handles pending GTK events, such as the `show_all` queued by a controller.
+pub fn process_events() {
+    while gtk::events_pending() {
+        gtk::main_iteration();
+    }
+}